@@ -21,8 +21,8 @@ fn criterion_benchmark(c: &mut Criterion) {
     let mut runner: BarnesHutRunner = BarnesHutRunner::from_theta(1.0f64);
     runner.generate_circle(&mut bodies,450.0,500.0,30.0);
     runner.generate_circle(&mut bodies,550.0,500.0,30.0);
-    runner.resize(&mut qt,&mut bodies);
-    runner.create_tree(&mut qt,&mut bodies);
+    runner.resize(&mut qt,&bodies);
+    runner.create_tree(&mut qt,&bodies);
     c.bench_function("iterate 100", |b| {
         b.iter(||
             runner.iterate(&mut qt,&mut bodies)
@@ -10,7 +10,7 @@ use winit::window::WindowBuilder;
 use winit_input_helper::WinitInputHelper;
 use barnes_hut::bh_runner::BarnesHutRunner;
 use barnes_hut::body::Body;
-use barnes_hut::quadtree::{Quadtree, Rectangle};
+use barnes_hut::quadtree::{Quadtree, Rectangle, Subtree};
 use barnes_hut::canvas::Canvas;
 const DEBUG: bool = false;
 
@@ -56,8 +56,8 @@ fn criterion_benchmark(c: &mut Criterion) {
     // runner.generate_circle(&mut bodies, 450.0,450.0,50.0);
     // bodies.push(Body::with_mass_and_pos(100000.0,Vector2::new(WIDTH_F,HEIGHT_F)));
     runner.generate_square(&mut bodies, 100, 450.0, 450.0);
-    runner.resize(&mut qt,&mut bodies);
-    runner.create_tree(&mut qt,&mut bodies);
+    runner.resize(&mut qt,&bodies);
+    runner.create_tree(&mut qt,&bodies);
     // runner.toggle_pause();
     println!("{:?}",bodies.len());
     c.bench_function("draw 100", |b| {
@@ -65,8 +65,8 @@ fn criterion_benchmark(c: &mut Criterion) {
             runner.iterate(&mut qt, &mut bodies);
 
             match (draw_boxes) {
-                true => { recursively_draw_tree(&mut canvas, &qt); },
-                false => { recursively_draw_tree_no_box(&mut canvas, &qt); }
+                true => { recursively_draw_tree(&mut canvas, qt.view(), &bodies); },
+                false => { recursively_draw_tree_no_box(&mut canvas, qt.view(), &bodies); }
             }
             canvas.copy_huemap_to_buffer(pixels.frame_mut());
             canvas.clear();
@@ -76,28 +76,28 @@ fn criterion_benchmark(c: &mut Criterion) {
     } );
 }
 
-fn recursively_draw_tree_no_box(canvas: &mut Canvas, qt: &Quadtree){
-    for tree in &qt.subtrees{
-        recursively_draw_tree_no_box(canvas,&tree);
+fn recursively_draw_tree_no_box(canvas: &mut Canvas, qt: Subtree, bodies: &[Body]){
+    for tree in qt.subtrees(){
+        recursively_draw_tree_no_box(canvas,tree,bodies);
     }
-    draw_bodies(canvas,&qt.bodies);
+    draw_bodies(canvas,&qt.bodies,bodies);
 }
-fn recursively_draw_tree(canvas: &mut Canvas, qt: &Quadtree){
+fn recursively_draw_tree(canvas: &mut Canvas, qt: Subtree, bodies: &[Body]){
     canvas.draw_square_safe(qt.boundaries.tl.x.round() as i32, qt.boundaries.tl.y.round() as i32, qt.boundaries.width() as i32, qt.boundaries.height() as i32,&(0.0,0.0,1.0));
-    for tree in &qt.subtrees{
-        recursively_draw_tree(canvas,&tree);
+    for tree in qt.subtrees(){
+        recursively_draw_tree(canvas,tree,bodies);
     }
 
-    draw_bodies(canvas,&qt.bodies);
+    draw_bodies(canvas,&qt.bodies,bodies);
 
 }
 
-fn draw_bodies(canvas: &mut Canvas, bodies: &Vec<Body>){
-    match(bodies.is_empty()){
+fn draw_bodies(canvas: &mut Canvas, indices: &[usize], bodies: &[Body]){
+    match(indices.is_empty()){
         true => {}
         false => {
-            for body in bodies{
-                update_pixel_heat(canvas, body); //not a fan of this casting
+            for index in indices{
+                update_pixel_heat(canvas, &bodies[*index]); //not a fan of this casting
             }
         }
     }
@@ -0,0 +1,143 @@
+use std::time::{Duration, Instant};
+use cgmath::{InnerSpace, Vector2};
+use crate::bh_runner::BarnesHutRunner;
+use crate::body::Body;
+use crate::gravity::PhysicsParams;
+use crate::quadtree::Quadtree;
+use crate::simulation::pairwise_forces;
+
+/// Summary of per-body relative force errors
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub struct ErrorStats {
+    pub median: f64,
+    pub percentile_99: f64,
+    pub max: f64
+}
+
+/// How a tree run compared with direct summation for one body set
+#[derive(Debug,Clone)]
+pub struct AccuracyReport {
+    /// |F_tree - F_exact| / |F_exact| for every body, in body order
+    pub errors: Vec<f64>,
+    pub stats: ErrorStats,
+    /// Nodes the walk opened over all bodies, see `BarnesHutRunner::opened_nodes`
+    pub opened_nodes: u64,
+    pub tree_nodes: usize,
+    /// Wall time for building the tree and walking it once per body
+    pub elapsed: Duration
+}
+
+/// The O(n^2) forces `Simulation::update` uses, in body order
+pub fn exact_forces(bodies: &[Body], params: &PhysicsParams) -> Vec<Vector2<f64>> {
+    let mut exact: Vec<Body> = bodies.to_vec();
+    pairwise_forces(&mut exact, params);
+    exact.iter().map(|body| body.force).collect()
+}
+
+/// Relative error of every approximate force. A body with no exact force counts as exact if its
+/// approximation is zero too, and as an error of 1 otherwise.
+pub fn relative_errors(approximate: &[Vector2<f64>], exact: &[Vector2<f64>]) -> Vec<f64> {
+    approximate.iter().zip(exact.iter()).map(|(approximate, exact)| {
+        let difference = (approximate - exact).magnitude();
+        let magnitude = exact.magnitude();
+        if magnitude > 0.0 {
+            difference / magnitude
+        } else if difference > 0.0 {
+            1.0
+        } else {
+            0.0
+        }
+    }).collect()
+}
+
+impl ErrorStats {
+    /// Nearest rank percentiles, all zero for an empty slice
+    pub fn from_errors(errors: &[f64]) -> Self {
+        let mut sorted: Vec<f64> = errors.to_vec();
+        sorted.sort_by(f64::total_cmp);
+        let rank = |fraction: f64| -> f64 {
+            if sorted.is_empty() {
+                return 0.0;
+            }
+            let index = ((fraction * sorted.len() as f64).ceil() as usize).clamp(1, sorted.len()) - 1;
+            sorted[index]
+        };
+        Self {
+            median: rank(0.5),
+            percentile_99: rank(0.99),
+            max: sorted.last().copied().unwrap_or(0.0)
+        }
+    }
+}
+
+/// Builds `quadtree` around `bodies`, walks it with `runner` and compares every force with `exact`
+/// (from `exact_forces`, so a sweep only pays for direct summation once). `bodies` is left untouched.
+pub fn compare_with(runner: &BarnesHutRunner, quadtree: &mut Quadtree, bodies: &[Body], exact: &[Vector2<f64>]) -> AccuracyReport {
+    let mut tree_bodies: Vec<Body> = bodies.to_vec();
+    let opened = runner.opened_nodes();
+    let start = Instant::now();
+    runner.evaluate_forces(quadtree, &mut tree_bodies);
+    let elapsed = start.elapsed();
+    let approximate: Vec<Vector2<f64>> = tree_bodies.iter().map(|body| body.force).collect();
+    let errors = relative_errors(&approximate, exact);
+    AccuracyReport {
+        stats: ErrorStats::from_errors(&errors),
+        errors,
+        opened_nodes: runner.opened_nodes() - opened,
+        tree_nodes: quadtree.nodes.len(),
+        elapsed
+    }
+}
+
+/// `compare_with` for a single run
+pub fn compare(runner: &BarnesHutRunner, quadtree: &mut Quadtree, bodies: &[Body]) -> AccuracyReport {
+    let exact = exact_forces(bodies, &runner.params);
+    compare_with(runner, quadtree, bodies, &exact)
+}
+
+impl std::fmt::Display for ErrorStats{
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result{
+        write!(f,"median: {:.3e} p99: {:.3e} max: {:.3e}",self.median,self.percentile_99,self.max)
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use cgmath::Vector2;
+    use crate::accuracy::{compare, relative_errors, ErrorStats};
+    use crate::bh_runner::BarnesHutRunner;
+    use crate::body::Body;
+    use crate::quadtree::{Quadtree, Rectangle};
+
+    #[test]
+    fn percentiles(){
+        let errors: Vec<f64> = (1..=200).rev().map(|i| i as f64).collect();
+        let stats = ErrorStats::from_errors(&errors);
+        assert_eq!(stats.median, 100.0);
+        assert_eq!(stats.percentile_99, 198.0);
+        assert_eq!(stats.max, 200.0);
+        assert_eq!(ErrorStats::from_errors(&[]).max, 0.0);
+        assert_eq!(ErrorStats::from_errors(&[3.0]).median, 3.0);
+    }
+
+    #[test]
+    fn zero_exact_force(){
+        let errors = relative_errors(&[Vector2::new(0.0, 0.0), Vector2::new(1.0, 0.0), Vector2::new(3.0, 4.0)],
+                                     &[Vector2::new(0.0, 0.0), Vector2::new(0.0, 0.0), Vector2::new(0.0, 5.0)]);
+        assert_eq!(errors, vec![0.0, 1.0, 10.0f64.sqrt() / 5.0]);
+    }
+
+    #[test]
+    fn error_grows_with_theta(){
+        let mut rng = fastrand::Rng::with_seed(300);
+        let bodies: Vec<Body> = (0..300).map(|_| Body::with_mass_and_pos(10.0, Vector2::new(rng.f64(), rng.f64()) * 500.0)).collect();
+        let mut qt: Quadtree = Quadtree::new(Rectangle::new(Vector2::new(0.0,0.0),Vector2::new(1.0,1.0)),1);
+        let exact_tree = compare(&BarnesHutRunner::from_theta(0.0), &mut qt, &bodies);
+        assert!(exact_tree.stats.max < 1e-9);
+        assert_eq!(exact_tree.errors.len(), 300);
+        let loose = compare(&BarnesHutRunner::from_theta(1.0), &mut qt, &bodies);
+        assert!(loose.stats.median > exact_tree.stats.max);
+        assert!(loose.stats.median <= loose.stats.percentile_99 && loose.stats.percentile_99 <= loose.stats.max);
+        assert!(loose.opened_nodes < exact_tree.opened_nodes);
+    }
+}
@@ -1,14 +1,138 @@
 use std::f32::consts::PI;
-use std::ops::{Deref, DerefMut};
-use crate::quadtree::{Quadtree, Rectangle};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use crate::quadtree::{Node, Quadtree, Subtree, ROOT};
+use crate::octree::{Cuboid, Octree};
 use crate::gravity;
-use cgmath::{MetricSpace, Vector2};
-use crate::body::Body;
+use crate::gravity::PhysicsParams;
+use crate::boundary::{minimum_image, Boundary, EwaldTable};
+use crate::collision::{Collisions, MergeEvent};
+use crate::external::{add_external_forces, ExternalField};
+use crate::force_law::{ForceLaw, Gravity, Multipole};
+use cgmath::{InnerSpace, MetricSpace, Vector2, Vector3, Zero};
+use crate::body::{Body, Body3};
+use crate::integrator::{for_each_body, ExplicitEuler, Integrator};
+use crate::solver::Solver;
+use crate::sph::Sph;
+use crate::timestep::{BlockStats, BlockTimesteps};
 use rand::prelude::*;
 use rand_distr::{Distribution, Normal, StandardNormal};
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+/// How far the multipole expansion of an accepted node goes
+#[derive(Debug,Copy,Clone,PartialEq,Default)]
+pub enum MultipoleOrder {
+    /// Total mass at the center of mass
+    #[default]
+    Monopole,
+    /// Adds the second moments of each node, more accurate at the same `theta` for a little more work
+    Quadrupole
+}
+
+/// When the walk has to open a node instead of using its multipole expansion.
+/// A node holding the body itself is opened under every criterion.
+#[derive(Debug,Copy,Clone,PartialEq,Default)]
+pub enum OpeningCriterion {
+    /// Open when `width / distance > theta`, distance measured to the center of mass
+    #[default]
+    Classic,
+    /// Salmon & Warren: open when `bmax / distance > theta`, where bmax is the distance from the center
+    /// of mass to the farthest corner of the node. Stays safe when the center of mass sits near an edge,
+    /// but with the mass near the middle it opens less than `Classic` at the same `theta`.
+    Bmax,
+    /// GADGET style: open when `G M width^2 / distance^4 > alpha |a|`, using the acceleration of the
+    /// previous step stored on the body. Bodies without one yet fall back to `Classic`.
+    RelativeAcceleration { alpha: f64 }
+}
+
+/// What a criterion gets to see of a node, measured from the body
+struct Candidate {
+    width: f64,
+    bmax: f64,
+    distance: f64,
+    mass: f64,
+    /// The node holds the body, or a periodic copy of it can't be used whole
+    must_open: bool
+}
+
+/// The arena of a `Quadtree` or `Octree`, all the shared walk needs to get around it
+trait Tree {
+    /// Arena indices of the children of node `index`, `None` for a leaf
+    fn children(&self, index: usize) -> Option<&[usize]>;
+
+    /// The bodies of leaf `index`, as indices into the slice the tree was built from
+    fn leaf_bodies(&self, index: usize) -> &[usize];
+}
+
+/// What a walk does at the nodes it reaches, see `BarnesHutRunner::walk`
+trait Visitor {
+    /// How node `index` looks from the body, `None` when nothing in it pulls
+    fn candidate(&self, index: usize) -> Option<Candidate>;
+
+    /// Every leaf the walk reaches
+    fn leaf(&mut self, bodies: &[usize]);
+
+    /// Node `index` is far enough away to be used whole
+    fn accept(&mut self, index: usize);
+}
+
+/// The 2D force on one body under `law`
+struct ForceWalk<'a> {
+    runner: &'a BarnesHutRunner,
+    quadtree: &'a Quadtree,
+    multipoles: &'a [Option<Multipole>],
+    bodies: &'a [Body],
+    body: &'a Body,
+    coupling: f64,
+    force: Vector2<f64>
+}
+
+/// The 2D gravitational potential at one body
+struct PotentialWalk<'a> {
+    runner: &'a BarnesHutRunner,
+    quadtree: &'a Quadtree,
+    bodies: &'a [Body],
+    body: &'a Body,
+    potential: f64
+}
+
+/// The 3D gravitational force and potential at one body, only the one that is asked for is summed
+struct OctreeWalk<'a> {
+    runner: &'a BarnesHutRunner,
+    octree: &'a Octree,
+    bodies: &'a [Body3],
+    body: &'a Body3,
+    potential: bool,
+    force: Vector3<f64>,
+    sum: f64
+}
+
 pub struct BarnesHutRunner {
     pub theta: f64,
-    pub paused: bool
+    pub order: MultipoleOrder,
+    pub criterion: OpeningCriterion,
+    pub paused: bool,
+    pub integrator: Box<dyn Integrator>,
+    pub params: PhysicsParams,
+    /// The interaction the 2D walk sums, `Gravity` by default. Potentials and the 3D walk are always gravity.
+    pub law: Box<dyn ForceLaw>,
+    pub time: f64,
+    /// Overlapping bodies are merged or bounced after every `update` when set
+    pub collisions: Option<Collisions>,
+    /// Background fields every body feels on top of the tree forces during `update`
+    pub external: Vec<Box<dyn ExternalField>>,
+    /// Pressure and viscosity between gas bodies, added to the tree forces when set
+    pub sph: Option<Sph>,
+    /// Individual power-of-two timesteps below `params.dt` when set, used instead of `integrator`.
+    /// Gas needs a common step, so they are not used while `sph` is set.
+    pub timesteps: Option<BlockTimesteps>,
+    block_stats: Option<BlockStats>,
+    merges: Vec<MergeEvent>,
+    opened: AtomicU64,
+    pool: Option<ThreadPool>,
+    boundary: Boundary,
+    /// Tabulated for a `Boundary::Periodic` domain the first time an `inverse_square` law needs it
+    ewald: OnceLock<EwaldTable>
 }
 
 
@@ -16,14 +140,38 @@ impl BarnesHutRunner{
     pub fn new() -> BarnesHutRunner{
         Self {
             theta: 0.5,
-            paused: false
+            order: MultipoleOrder::Monopole,
+            criterion: OpeningCriterion::Classic,
+            paused: false,
+            integrator: Box::new(ExplicitEuler),
+            params: PhysicsParams::default(),
+            law: Box::new(Gravity),
+            time: 0.0,
+            collisions: None,
+            external: Vec::new(),
+            sph: None,
+            timesteps: None,
+            block_stats: None,
+            merges: Vec::new(),
+            opened: AtomicU64::new(0),
+            pool: None,
+            boundary: Boundary::Open,
+            ewald: OnceLock::new()
         }
     }
 
     pub fn from_theta(theta: f64) -> BarnesHutRunner {
         Self {
             theta,
-            paused: false
+            ..Self::new()
+        }
+    }
+
+    pub fn with_integrator(theta: f64, integrator: Box<dyn Integrator>) -> BarnesHutRunner {
+        Self {
+            theta,
+            integrator,
+            ..Self::new()
         }
     }
 
@@ -73,105 +221,297 @@ impl BarnesHutRunner{
         }
     }
 
+    /// `body_count` bodies of equal mass drawn from a Plummer sphere of `mass` and `scale` a, laid flat around
+    /// `center` and set on counter-clockwise circular orbits in its potential. Radii are cut off at 10 a.
+    pub fn generate_plummer(&mut self, bodies: &mut Vec<Body>, center: Vector2<f64>, body_count: usize, mass: f64, scale: f64) {
+        let mut rng = thread_rng();
+        //share of the mass inside 10 a
+        let cutoff = (100.0f64 / 101.0).powf(1.5);
+        for _ in 0..body_count {
+            let enclosed: f64 = rng.gen_range(0.0..cutoff);
+            let r = scale / (enclosed.powf(-2.0 / 3.0) - 1.0).sqrt();
+            let angle: f64 = rng.gen_range(0.0..std::f64::consts::TAU);
+            let direction = Vector2::new(angle.cos(), angle.sin());
+            let speed = (self.params.g * mass * r * r / (r * r + scale * scale).powf(1.5)).sqrt();
+            let mut body = Body::with_mass_and_pos(mass / body_count as f64, center + (direction * r));
+            body.velocity = Vector2::new(-direction.y, direction.x) * speed;
+            bodies.push(body);
+        }
+    }
+
+    /// The force on `body` from everything in the tree, `bodies` is the slice the tree was built from.
+    /// Works out the node multipoles of `law` for this one body, `tree_forces` shares them between bodies.
+    pub fn barnes_hut_force(&self, quadtree: &Quadtree, bodies: &[Body], body: &Body) -> Vector2<f64> {
+        self.walk_force(quadtree, &self.law.node_multipoles(quadtree, bodies), bodies, body)
+    }
+
+    fn walk_force(&self, quadtree: &Quadtree, multipoles: &[Option<Multipole>], bodies: &[Body], body: &Body) -> Vector2<f64> {
+        let mut walk = ForceWalk { runner: self, quadtree, multipoles, bodies, body, coupling: self.law.coupling(body), force: Vector2::zero() };
+        let opened = self.walk(quadtree, ROOT, body.acceleration.magnitude(), &mut walk);
+        self.opened.fetch_add(opened, Ordering::Relaxed);
+        walk.force
+    }
 
-    pub fn barnes_hut_force(&mut self, quadtree: &mut Quadtree, body: &mut Body){
-        if quadtree.subtrees.is_empty() {
-            // just sum forces from bodies in subtree
-            for other_body in quadtree.bodies.iter_mut(){
-                //ignore self
-                if body.pos == other_body.pos {
-                    continue;
+    /// The walk every force and potential sum of the 2D and 3D trees shares: the bodies of a leaf are taken one
+    /// by one, other nodes are opened or used whole as `opens` decides. A node holding the body itself is always
+    /// opened, otherwise the body would attract itself. Returns how many nodes were opened.
+    fn walk(&self, tree: &impl Tree, index: usize, acceleration: f64, visitor: &mut impl Visitor) -> u64 {
+        match tree.children(index) {
+            None => {
+                visitor.leaf(tree.leaf_bodies(index));
+                0
+            }
+            Some(children) => match visitor.candidate(index) {
+                Some(candidate) if self.opens(&candidate, acceleration) => {
+                    1 + children.iter().map(|child| self.walk(tree, *child, acceleration, visitor)).sum::<u64>()
                 }
+                Some(_) => {
+                    visitor.accept(index);
+                    0
+                }
+                None => 0
             }
-            // quadtree.bodies.par_iter_mut().for_each(|other_body|{
-            //     if body.pos != other_body.pos {
-            //         gravity::calculate_force_single(body,other_body);
-            //     }
-            // })
-
-        }
-
-        //at this point the node is not external, because it has subtrees
-        //so we see if it has a center of mass
-        match quadtree.center_of_mass {
-            //center of mass exists,
-            //calculate distance from body to region of space
-            //if far enough we can use its center of mass (COM)
-            //if not, we drill down and all of its subtrees
-            Some(com) => {
-                let s = quadtree.boundaries.width();
-                let d = body.pos.distance(com);
-                let theta_body = s/d;
-                if theta_body > self.theta {
-                    //go further into the tree
-                    //how can we do this if the runner owns the tree?
-                    for other_subtree in quadtree.subtrees.iter_mut(){
-                        self.barnes_hut_force(other_subtree.deref_mut(),body);
-                    }
-                    return;
+        }
+    }
+
+    fn candidate(&self, node: &Node, com: Vector2<f64>, mass: f64, body: &Body) -> Candidate {
+        let center = self.nearest_copy(body, com);
+        Candidate {
+            width: node.boundaries.width(),
+            bmax: node.boundaries.max_extent_from(com),
+            distance: body.pos.distance(center),
+            mass,
+            must_open: node.boundaries.contains(body.pos) || self.straddles_copies(node, center - com, body)
+        }
+    }
+
+    /// Where the walk sees a mass at `center`: its copy nearest to `body` in a periodic domain, otherwise `center`
+    fn nearest_copy(&self, body: &Body, center: Vector2<f64>) -> Vector2<f64> {
+        match self.boundary.period() {
+            Some(period) => body.pos - minimum_image(body.pos - center, period.x, period.y),
+            None => center
+        }
+    }
+
+    /// A node can only be used whole if its copy moved by `shift` lies within half a domain of the body,
+    /// otherwise the nearest copies of its bodies aren't all in that one copy
+    fn straddles_copies(&self, node: &Node, shift: Vector2<f64>, body: &Body) -> bool {
+        match self.boundary.period() {
+            Some(period) => {
+                let (tl, br) = (node.boundaries.tl + shift - body.pos, node.boundaries.br + shift - body.pos);
+                tl.x < -period.x / 2.0 || br.x > period.x / 2.0 || tl.y < -period.y / 2.0 || br.y > period.y / 2.0
+            }
+            None => false
+        }
+    }
+
+    /// The Ewald correction for the rest of the lattice of a source at the minimum image `d` when periodic,
+    /// `strengths` is the coupling of the body times the strength of the source.
+    /// Only an `inverse_square` law in a `Boundary::Periodic` domain has one, other laws see the nearest copy alone.
+    fn lattice_force(&self, d: Vector2<f64>, strengths: f64) -> Vector2<f64> {
+        match (self.boundary, self.law.inverse_square(&self.params)) {
+            (Boundary::Periodic(domain), Some(c)) => {
+                let table = self.ewald.get_or_init(|| EwaldTable::new(domain.width(), domain.height()));
+                table.correction(d) * (-c * strengths)
+            }
+            _ => Vector2::zero()
+        }
+    }
+
+    /// Whether the walk has to open `node` for a body whose last acceleration had magnitude `acceleration`.
+    /// Shared by the 2D and 3D walks.
+    fn opens(&self, node: &Candidate, acceleration: f64) -> bool {
+        if node.must_open {
+            return true;
+        }
+        match self.criterion {
+            OpeningCriterion::Classic => node.width / node.distance > self.theta,
+            OpeningCriterion::Bmax => node.bmax / node.distance > self.theta,
+            OpeningCriterion::RelativeAcceleration { alpha } => {
+                if acceleration > 0.0 {
+                    let distance2 = node.distance * node.distance;
+                    self.params.g * node.mass * node.width * node.width > alpha * acceleration * distance2 * distance2
                 } else {
-                    //use the COM
-                    gravity::calculate_force_mass_center(body,com,quadtree.total_mass);
-                    return;
+                    node.width / node.distance > self.theta
                 }
             }
-            None => {
-                return;
-            }
         }
+    }
 
+    /// How many nodes the force walks opened since the start of the last `update` or `iterate3`,
+    /// a measure of how much work the opening criterion asks for
+    pub fn opened_nodes(&self) -> u64 {
+        self.opened.load(Ordering::Relaxed)
     }
 
-    pub fn create_tree(&mut self, quadtree: &mut Quadtree, bodies: &Vec<Body>){
-        //I need to check if creating a new quadtree this way safely disposes of the old one
-        //Does this mean the reference only exists for the lifetime of this function?
-        // quadtree = &Quadtree::new(quadtree.boundaries, quadtree.limit); //Works because of the Clone trait (￣▽￣)"
+    /// Gravitational potential per unit mass at `body`, walking the tree the same way as `barnes_hut_force`
+    pub fn barnes_hut_potential(&self, quadtree: &Quadtree, bodies: &[Body], body: &Body) -> f64 {
+        let mut walk = PotentialWalk { runner: self, quadtree, bodies, body, potential: 0.0 };
+        self.walk(quadtree, ROOT, body.acceleration.magnitude(), &mut walk);
+        walk.potential
+    }
 
+    /// Rebuilds the tree from scratch around `bodies`, the root takes `quadtree.boundaries`
+    pub fn create_tree(&self, quadtree: &mut Quadtree, bodies: &[Body]){
+        quadtree.build(bodies);
+    }
 
+    /// Spreads the force walk and the integration over `threads` threads, 1 (the default) runs everything
+    /// on the calling thread. Each body's force is computed the same way on any thread, so the results don't
+    /// depend on the thread count.
+    pub fn set_threads(&mut self, threads: usize){
+        self.pool = match threads {
+            0 | 1 => None,
+            _ => Some(ThreadPoolBuilder::new().num_threads(threads).build().expect("could not start the force threads"))
+        };
+    }
 
-        for body in bodies{
-            quadtree.insert(body.clone()); //I think clone here makes a new body
-        }
+    /// Sets what happens at the edge of the domain, `Boundary::Open` by default.
+    /// In a periodic domain the walk sees the nearest copy of every node, and an `inverse_square` law in a
+    /// `Boundary::Periodic` one gets the Ewald correction for the rest of the lattice.
+    /// Only the 2D bodies are affected.
+    pub fn set_boundary(&mut self, boundary: Boundary){
+        self.ewald = OnceLock::new();
+        self.boundary = boundary;
+    }
 
-        quadtree.update_mass();
+    pub fn boundary(&self) -> Boundary {
+        self.boundary
     }
 
-    pub fn update(&mut self, quadtree: &mut Quadtree, bodies: &mut Vec<Body>){
-        for body in bodies.iter_mut(){
-            self.barnes_hut_force(quadtree,body);
+    pub fn threads(&self) -> usize {
+        match &self.pool {
+            Some(pool) => pool.current_num_threads(),
+            None => 1
         }
+    }
 
-        for body in bodies.iter_mut(){
-            gravity::apply_force(body);
+    /// Runs `f` in the thread pool of `set_threads`, or on the calling thread when there is none
+    pub fn install<R: Send>(&self, f: impl FnOnce() -> R + Send) -> R {
+        match &self.pool {
+            Some(pool) => pool.install(f),
+            None => f()
         }
     }
 
-    pub fn resize(&mut self, quadtree: &mut Quadtree, bodies: &mut Vec<Body>){
-        //can we just edit in place?
-        let mut smallest: f64 = quadtree.boundaries.tl.x;
-        let mut largest: f64 = quadtree.boundaries.br.x;
-        for body in bodies{
-            if body.pos.x > largest{
-                largest = body.pos.x;
-            }
+    pub fn update(&mut self, quadtree: &mut Quadtree, bodies: &mut Vec<Body>){
+        self.opened.store(0, Ordering::Relaxed);
+        self.block_stats = self.install(|| self.step(quadtree, bodies));
+        self.boundary.apply(bodies);
+        self.time += self.params.dt;
 
-            if body.pos.x < smallest{
-                smallest = body.pos.x;
+        self.merges.clear();
+        if let Some(collisions) = self.collisions {
+            self.resize(quadtree, bodies);
+            self.create_tree(quadtree, bodies);
+            self.merges = collisions.resolve(quadtree, bodies, self.time);
+            //the tree has to match the bodies that are left
+            if !self.merges.is_empty() {
+                self.create_tree(quadtree, bodies);
             }
+        }
+    }
 
-            if body.pos.y > largest{
-                largest = body.pos.y;
-            }
+    /// The merges of the last `update`, see `collisions`
+    pub fn merges(&self) -> &[MergeEvent] {
+        &self.merges
+    }
 
-            if body.pos.y < smallest{
-                smallest = body.pos.y;
-            }
+    /// How the bodies were binned in the last `update`, when it used `timesteps`
+    pub fn block_stats(&self) -> Option<&BlockStats> {
+        self.block_stats.as_ref()
+    }
+
+    fn step(&self, quadtree: &mut Quadtree, bodies: &mut [Body]) -> Option<BlockStats> {
+        self.tree_forces(quadtree, bodies);
+        self.sph_forces(quadtree, bodies);
+        add_external_forces(&self.external, bodies, self.time, self.params.g);
+        if let (Some(timesteps), None) = (&self.timesteps, &self.sph) {
+            let start = self.time;
+            return Some(timesteps.step(bodies, &self.params, &mut |bodies: &mut [Body], active: &[usize], offset: f64| {
+                //everyone has drifted, so the tree is rebuilt even when few bodies need a force
+                self.rebuild(quadtree, bodies);
+                self.subset_forces(quadtree, bodies, active);
+                for index in active{
+                    let body = &mut bodies[*index];
+                    body.force += self.external.acceleration(body.pos, start + offset, self.params.g) * body.test_mass();
+                }
+            }));
+        }
+        //multi-stage integrators move the bodies mid step, so the tree has to be rebuilt
+        //before every extra force evaluation. The external fields are taken at the time of the stage.
+        let start = self.time;
+        self.integrator.step(bodies, &self.params, &mut |bodies: &mut [Body], offset: f64| {
+            self.evaluate_forces(quadtree, bodies);
+            self.sph_forces(quadtree, bodies);
+            add_external_forces(&self.external, bodies, start + offset, self.params.g);
+        });
+
+        //the integrator moved the internal energy along with the velocity, it can't end up negative
+        if self.sph.is_some() {
+            for_each_body(bodies, |body: &mut Body| {
+                if body.is_gas() {
+                    body.gas.internal_energy = body.gas.internal_energy.max(0.0);
+                }
+            });
+        }
+        None
+    }
+
+    /// Adds the `sph` forces to the gas bodies, the tree has to be built from `bodies`
+    fn sph_forces(&self, quadtree: &Quadtree, bodies: &mut [Body]){
+        if let Some(sph) = &self.sph {
+            sph.add_forces(quadtree, bodies, &self.boundary);
+        }
+    }
+
+    /// Overwrites the force on every body using the tree as it was last built.
+    /// Runs in parallel when called from inside the runner's thread pool.
+    pub fn tree_forces(&self, quadtree: &Quadtree, bodies: &mut [Body]){
+        let multipoles = self.law.node_multipoles(quadtree, bodies);
+        let forces: Vec<Vector2<f64>> = if rayon::current_thread_index().is_some() {
+            bodies.par_iter().map(|body| self.walk_force(quadtree, &multipoles, bodies, body)).collect()
+        } else {
+            bodies.iter().map(|body| self.walk_force(quadtree, &multipoles, bodies, body)).collect()
+        };
+        for (body, force) in bodies.iter_mut().zip(forces){
+            body.force = force;
+        }
+    }
+
+    /// Overwrites the force on the bodies at the indices in `active` using the tree as it was last built,
+    /// the others keep theirs. Runs in parallel when called from inside the runner's thread pool.
+    pub fn subset_forces(&self, quadtree: &Quadtree, bodies: &mut [Body], active: &[usize]){
+        let multipoles = self.law.node_multipoles(quadtree, bodies);
+        let forces: Vec<Vector2<f64>> = if rayon::current_thread_index().is_some() {
+            active.par_iter().map(|index| self.walk_force(quadtree, &multipoles, bodies, &bodies[*index])).collect()
+        } else {
+            active.iter().map(|index| self.walk_force(quadtree, &multipoles, bodies, &bodies[*index])).collect()
+        };
+        for (index, force) in active.iter().zip(forces){
+            bodies[*index].force = force;
         }
+    }
 
-        quadtree.boundaries.tl.x = smallest;
-        quadtree.boundaries.tl.y = smallest;
-        quadtree.boundaries.br.x = largest;
-        quadtree.boundaries.br.y = largest;
+    /// Rebuilds the tree around the current positions and overwrites the force on every body
+    pub fn evaluate_forces(&self, quadtree: &mut Quadtree, bodies: &mut [Body]){
+        self.rebuild(quadtree,bodies);
+        self.tree_forces(quadtree,bodies);
+    }
+
+    /// Rebuilds the tree around the current positions, wrapped back into a periodic domain
+    fn rebuild(&self, quadtree: &mut Quadtree, bodies: &mut [Body]){
+        self.boundary.wrap(bodies);
+        self.resize(quadtree,bodies);
+        self.create_tree(quadtree,bodies);
+    }
+
+    /// Grows the root to hold every body, or pins it to the domain of a closed `boundary`
+    pub fn resize(&self, quadtree: &mut Quadtree, bodies: &[Body]){
+        match self.boundary.domain() {
+            Some(domain) => quadtree.boundaries = domain,
+            None => quadtree.resize_to_fit(bodies)
+        }
     }
 
 
@@ -185,7 +525,6 @@ impl BarnesHutRunner{
     }
 
     pub fn force_iterate(&mut self, quadtree: &mut Quadtree, bodies: &mut Vec<Body>){
-        quadtree.clear();
         self.resize(quadtree,bodies);
         self.create_tree(quadtree, bodies);
         self.update(quadtree,bodies);
@@ -195,46 +534,713 @@ impl BarnesHutRunner{
         self.paused = !self.paused;
     }
 
-    pub fn print_bodies(&self, quadtree: &Quadtree){
-        for body in &quadtree.bodies{
-            println!("{}",body);
+    pub fn print_bodies(&self, quadtree: &Quadtree, bodies: &[Body]){
+        self.print_subtree(quadtree.view(), bodies);
+    }
+
+    fn print_subtree(&self, quadtree: Subtree, bodies: &[Body]){
+        for index in &quadtree.bodies{
+            println!("{}",bodies[*index]);
         }
-        for subtree in &quadtree.subtrees{
-            self.print_bodies(&subtree);
+        for subtree in quadtree.subtrees(){
+            self.print_subtree(subtree, bodies);
         }
     }
 
+    /// `generate_bivariate_random_dist` in a box of `size`, z is spread over the depth by the same normal distribution
+    pub fn generate_bivariate_random_dist3(&mut self, bodies: &mut Vec<Body3>, size: Vector3<f64>, body_count: i32, body_mass: f64, spread: f64) {
+        let mut flat: Vec<Body> = Vec::new();
+        self.generate_bivariate_random_dist(&mut flat, size.x, size.y, body_count, body_mass, spread);
+        let mut rng = thread_rng();
+        let z_dist = Normal::new(size.z / 2.0, spread * size.z / 6.0).unwrap();
+        bodies.extend(flat.iter().map(|body| Body3::from_body(body, z_dist.sample(&mut rng).clamp(0.0, size.z))));
+    }
+
+    /// The force on `body` from everything in the octree, walked like `barnes_hut_force`
+    pub fn barnes_hut_force3(&self, octree: &Octree, bodies: &[Body3], body: &Body3) -> Vector3<f64> {
+        let mut walk = OctreeWalk { runner: self, octree, bodies, body, potential: false, force: Vector3::zero(), sum: 0.0 };
+        let opened = self.walk(octree, ROOT, body.acceleration.magnitude(), &mut walk);
+        self.opened.fetch_add(opened, Ordering::Relaxed);
+        walk.force
+    }
+
+    /// Gravitational potential per unit mass at `body`, walking the octree the same way as `barnes_hut_force3`
+    pub fn barnes_hut_potential3(&self, octree: &Octree, bodies: &[Body3], body: &Body3) -> f64 {
+        let mut walk = OctreeWalk { runner: self, octree, bodies, body, potential: true, force: Vector3::zero(), sum: 0.0 };
+        self.walk(octree, ROOT, body.acceleration.magnitude(), &mut walk);
+        walk.sum
+    }
+
+    /// Grows `octree.boundaries` into a cube holding every body
+    pub fn resize3(&self, octree: &mut Octree, bodies: &[Body3]){
+        let mut smallest: f64 = octree.boundaries.min.x;
+        let mut largest: f64 = octree.boundaries.max.x;
+        for body in bodies{
+            for coordinate in [body.pos.x, body.pos.y, body.pos.z]{
+                smallest = smallest.min(coordinate);
+                largest = largest.max(coordinate);
+            }
+        }
+        octree.boundaries = Cuboid::new(Vector3::new(smallest, smallest, smallest), Vector3::new(largest, largest, largest));
+    }
+
+    pub fn create_octree(&self, octree: &mut Octree, bodies: &[Body3]){
+        octree.build(bodies);
+    }
+
+    /// Overwrites the force on every body using the octree as it was last built
+    pub fn tree_forces3(&self, octree: &Octree, bodies: &mut [Body3]){
+        let forces: Vec<Vector3<f64>> = if rayon::current_thread_index().is_some() {
+            bodies.par_iter().map(|body| self.barnes_hut_force3(octree, bodies, body)).collect()
+        } else {
+            bodies.iter().map(|body| self.barnes_hut_force3(octree, bodies, body)).collect()
+        };
+        for (body, force) in bodies.iter_mut().zip(forces){
+            body.force = force;
+        }
+    }
+
+    pub fn evaluate_forces3(&self, octree: &mut Octree, bodies: &mut [Body3]){
+        self.resize3(octree, bodies);
+        self.create_octree(octree, bodies);
+        self.tree_forces3(octree, bodies);
+    }
+
+    /// Rebuilds the octree and advances the 3D bodies by one step of `integrator`.
+    /// The 3D walk is always gravity, `law`, `external`, `sph`, `timesteps` and the boundary only apply to `Body`.
+    pub fn iterate3(&mut self, octree: &mut Octree, bodies: &mut [Body3]){
+        if self.paused {
+            return;
+        }
+        self.opened.store(0, Ordering::Relaxed);
+        self.install(|| self.step3(octree, bodies));
+        self.time += self.params.dt;
+    }
+
+    fn step3(&self, octree: &mut Octree, bodies: &mut [Body3]){
+        self.evaluate_forces3(octree, bodies);
+        self.integrator.step3(bodies, &self.params, &mut |bodies: &mut [Body3], _| self.evaluate_forces3(octree, bodies));
+    }
+
+}
+
+impl Tree for Quadtree {
+    fn children(&self, index: usize) -> Option<&[usize]> {
+        self.nodes[index].children.as_ref().map(|children| children.as_slice())
+    }
+
+    fn leaf_bodies(&self, index: usize) -> &[usize] {
+        &self.nodes[index].bodies
+    }
+}
+
+impl Tree for Octree {
+    fn children(&self, index: usize) -> Option<&[usize]> {
+        self.nodes[index].children.as_ref().map(|children| children.as_slice())
+    }
+
+    fn leaf_bodies(&self, index: usize) -> &[usize] {
+        &self.nodes[index].bodies
+    }
+}
+
+impl Visitor for ForceWalk<'_> {
+    fn candidate(&self, index: usize) -> Option<Candidate> {
+        self.multipoles[index].as_ref().map(|multipole| self.runner.candidate(&self.quadtree.nodes[index], multipole.center, multipole.magnitude, self.body))
+    }
+
+    fn leaf(&mut self, bodies: &[usize]){
+        let (runner, body) = (self.runner, self.body);
+        for other_body in bodies.iter().map(|index| &self.bodies[*index]){
+            let strength = runner.law.strength(other_body);
+            //ignore self, and tracers which pull on nothing
+            if other_body.id == body.id || strength == 0.0 {
+                continue;
+            }
+            let d = body.pos - runner.nearest_copy(body,other_body.pos);
+            self.force += (runner.law.kernel(d,&runner.params) * (self.coupling * strength)) + runner.lattice_force(d,self.coupling * strength);
+        }
+    }
+
+    fn accept(&mut self, index: usize){
+        let runner = self.runner;
+        if let Some(multipole) = &self.multipoles[index] {
+            let d = self.body.pos - runner.nearest_copy(self.body,multipole.center);
+            let mut field = runner.law.multipole_field(d,multipole,&runner.params);
+            if runner.order == MultipoleOrder::Quadrupole {
+                field += runner.law.quadrupole_field(d,multipole,&runner.params);
+            }
+            self.force += (field * self.coupling) + runner.lattice_force(d,self.coupling * multipole.strength);
+        }
+    }
+}
+
+impl Visitor for PotentialWalk<'_> {
+    fn candidate(&self, index: usize) -> Option<Candidate> {
+        let node = &self.quadtree.nodes[index];
+        node.center_of_mass.map(|com| self.runner.candidate(node, com, node.total_mass, self.body))
+    }
+
+    fn leaf(&mut self, bodies: &[usize]){
+        let (runner, body) = (self.runner, self.body);
+        for other_body in bodies.iter().map(|index| &self.bodies[*index]){
+            //ignore self, and tracers which pull on nothing
+            if other_body.id == body.id || !other_body.is_source() {
+                continue;
+            }
+            self.potential += gravity::potential_mass_center(body,runner.nearest_copy(body,other_body.pos),other_body.mass,&runner.params);
+        }
+    }
+
+    fn accept(&mut self, index: usize){
+        let (runner, body, node) = (self.runner, self.body, &self.quadtree.nodes[index]);
+        if let Some(com) = node.center_of_mass {
+            let center = runner.nearest_copy(body,com);
+            self.potential += gravity::potential_mass_center(body,center,node.total_mass,&runner.params);
+            if runner.order == MultipoleOrder::Quadrupole {
+                self.potential += gravity::potential_quadrupole(body,center,node.quadrupole,&runner.params);
+            }
+        }
+    }
+}
+
+impl Visitor for OctreeWalk<'_> {
+    fn candidate(&self, index: usize) -> Option<Candidate> {
+        let node = &self.octree.nodes[index];
+        node.center_of_mass.map(|com| Candidate {
+            width: node.boundaries.width(),
+            bmax: node.boundaries.max_extent_from(com),
+            distance: self.body.pos.distance(com),
+            mass: node.total_mass,
+            must_open: node.boundaries.contains(self.body.pos)
+        })
+    }
+
+    fn leaf(&mut self, bodies: &[usize]){
+        for index in bodies{
+            let other_body = &self.bodies[*index];
+            if other_body.id != self.body.id {
+                self.add(other_body.pos, other_body.mass);
+            }
+        }
+    }
+
+    fn accept(&mut self, index: usize){
+        let node = &self.octree.nodes[index];
+        if let Some(com) = node.center_of_mass {
+            self.add(com, node.total_mass);
+        }
+    }
+}
+
+impl OctreeWalk<'_> {
+    fn add(&mut self, center: Vector3<f64>, mass: f64){
+        let params = &self.runner.params;
+        if self.potential {
+            self.sum += gravity::potential_mass_center3(self.body, center, mass, params);
+        } else {
+            self.force += gravity::force_mass_center3(self.body, center, mass, params);
+        }
+    }
+}
+
+impl Solver for BarnesHutRunner {
+    fn params(&self) -> &PhysicsParams {
+        &self.params
+    }
+
+    fn params_mut(&mut self) -> &mut PhysicsParams {
+        &mut self.params
+    }
+
+    fn time(&self) -> f64 {
+        self.time
+    }
+
+    fn evaluate_forces(&self, quadtree: &mut Quadtree, bodies: &mut [Body]){
+        BarnesHutRunner::evaluate_forces(self, quadtree, bodies);
+    }
+
+    fn iterate(&mut self, quadtree: &mut Quadtree, bodies: &mut Vec<Body>){
+        BarnesHutRunner::iterate(self, quadtree, bodies);
+    }
 }
 
 #[cfg(test)]
 mod tests{
-    use cgmath::Vector2;
-    use crate::bh_runner::BarnesHutRunner;
+    use cgmath::{InnerSpace, Vector2};
+    use crate::accuracy::{relative_errors, ErrorStats};
+    use crate::bh_runner::{BarnesHutRunner, Candidate, MultipoleOrder, OpeningCriterion};
     use crate::body::Body;
+    use crate::boundary::Boundary;
+    use cgmath::Vector3;
+    use crate::body::Body3;
+    use crate::gravity;
+    use crate::fmm::FmmSolver;
+    use crate::force_law::{Coulomb, ForceLaw, Gravity};
+    use crate::gravity::PhysicsParams;
+    use crate::integrator::{ForestRuth, Integrator, Leapfrog, Yoshida6};
+    use crate::octree::{Cuboid, Octree};
     use crate::quadtree::{Quadtree, Rectangle};
+    use crate::simulation::{pairwise_forces, pairwise_forces_with, Simulation};
+    use crate::softening::Softening;
+    use crate::solver::Solver;
+    use crate::vortex::BiotSavart;
+
+    fn assert_forces_match(runner: &BarnesHutRunner, limit: usize, bodies: &[Body]){
+        let mut qt: Quadtree = Quadtree::new(Rectangle::new(Vector2::new(0.0,0.0),Vector2::new(1.0,1.0)),limit);
+        let mut tree_bodies: Vec<Body> = bodies.to_vec();
+        let mut naive_bodies: Vec<Body> = bodies.to_vec();
+        runner.evaluate_forces(&mut qt, &mut tree_bodies);
+        pairwise_forces(&mut naive_bodies, &runner.params);
+        for (tree, naive) in tree_bodies.iter().zip(naive_bodies.iter()){
+            assert!((tree.force - naive.force).magnitude() <= 1e-9 * naive.force.magnitude().max(1e-9), "{:?} != {:?}", tree.force, naive.force);
+        }
+    }
+
+    #[test]
+    fn leaf_direct_sum_matches_naive(){
+        let runner: BarnesHutRunner = BarnesHutRunner::from_theta(0.0);
+        let bodies: Vec<Body> = (0..40).map(|_| Body::random(0.0, 100.0)).collect();
+        for limit in [1, 4, 16, 100]{
+            assert_forces_match(&runner, limit, &bodies);
+        }
+    }
+
+    #[test]
+    fn piled_up_bodies_match_naive(){
+        let runner: BarnesHutRunner = BarnesHutRunner::from_theta(0.0);
+        let mut bodies: Vec<Body> = (0..10).map(|_| Body::random(50.0, 0.3)).collect();
+        //two bodies on top of each other still attract everything else
+        bodies.push(Body::with_mass_and_pos(5.0, Vector2::new(20.0, 20.0)));
+        bodies.push(Body::with_mass_and_pos(5.0, Vector2::new(20.0, 20.0)));
+        bodies.extend((0..10).map(|_| Body::random(0.0, 100.0)));
+        assert_forces_match(&runner, 1, &bodies);
+    }
+
+    #[test]
+    fn tracers_feel_but_do_not_pull(){
+        let massive: Vec<Body> = (0..60).map(|_| Body::random(0.0, 1000.0)).collect();
+        let mut bodies: Vec<Body> = massive.clone();
+        bodies.extend((0..20).map(|_| Body::tracer(Body::random(0.0, 1000.0).pos)));
+        //a pile of tracers makes a leaf without a center of mass
+        bodies.push(Body::tracer(Vector2::new(500.0, 500.0)));
+        bodies.push(Body::tracer(Vector2::new(500.0, 500.0)));
+        let params = BarnesHutRunner::new().params;
+        let mut naive: Vec<Body> = bodies.clone();
+        let mut alone: Vec<Body> = massive.clone();
+        pairwise_forces(&mut naive, &params);
+        pairwise_forces(&mut alone, &params);
+        for (with, without) in naive.iter().zip(alone.iter()){
+            assert!((with.force - without.force).magnitude() <= 1e-12 * without.force.magnitude());
+        }
+        //a tracer feels what a unit mass in its place would
+        for tracer in naive.iter().filter(|body| body.is_tracer()){
+            let probe = Body::with_mass_and_pos(1.0, tracer.pos);
+            let expected: Vector2<f64> = massive.iter().map(|other| gravity::force_mass_center(&probe, other.pos, other.mass, &params)).sum();
+            assert!((tracer.force - expected).magnitude() <= 1e-9 * expected.magnitude(), "{:?} != {:?}", tracer.force, expected);
+        }
+        let exact: Vec<Vector2<f64>> = naive.iter().map(|body| body.force).collect();
+        let solvers: Vec<Box<dyn Solver>> = vec![Box::new(BarnesHutRunner::from_theta(0.3)), Box::new(FmmSolver::new())];
+        for solver in solvers{
+            let mut qt: Quadtree = Quadtree::new(Rectangle::new(Vector2::new(0.0,0.0),Vector2::new(1.0,1.0)),1);
+            let mut tree_bodies: Vec<Body> = bodies.clone();
+            solver.evaluate_forces(&mut qt, &mut tree_bodies);
+            let tree: Vec<Vector2<f64>> = tree_bodies.iter().map(|body| body.force).collect();
+            let stats = ErrorStats::from_errors(&relative_errors(&tree, &exact));
+            //a tracer left out of the walk would be off by 1
+            assert!(stats.median < 5e-3 && stats.max < 0.5, "{}", stats);
+        }
+    }
+
+    #[test]
+    fn tracers_feel_but_do_not_pull_in_3d(){
+        let mut runner: BarnesHutRunner = BarnesHutRunner::with_integrator(0.5, Box::new(Leapfrog));
+        runner.params = PhysicsParams::new(1.0, 0.01, Softening::None { min_distance: 0.0 });
+        let mut bodies: Vec<Body3> = vec![
+            Body3::with_mass_and_pos(1.0, Vector3::new(0.0, 0.0, 0.0)),
+            Body3::from_body(&Body::tracer(Vector2::new(2.0, 0.0)), 0.0),
+            Body3::from_body(&Body::tracer(Vector2::new(0.0, 3.0)), 4.0)
+        ];
+        let mut octree: Octree = Octree::new(Cuboid::new(Vector3::new(0.0,0.0,0.0),Vector3::new(1.0,1.0,1.0)),1);
+        runner.evaluate_forces3(&mut octree, &mut bodies);
+        assert_eq!(bodies[0].force, Vector3::new(0.0, 0.0, 0.0));
+        //a tracer feels what a unit mass in its place would
+        assert!((bodies[1].force - Vector3::new(-0.25, 0.0, 0.0)).magnitude() < 1e-12, "{:?}", bodies[1].force);
+        runner.iterate3(&mut octree, &mut bodies);
+        assert_eq!(bodies[0].pos, Vector3::new(0.0, 0.0, 0.0));
+        assert!((bodies[1].velocity - Vector3::new(-0.0025, 0.0, 0.0)).magnitude() < 1e-6, "{:?}", bodies[1].velocity);
+        assert!(bodies[2].velocity.y < 0.0 && bodies[2].velocity.z < 0.0);
+    }
+
+    /// Coulomb without the dipole terms, what a plain monopole walk would do
+    #[derive(Debug)]
+    struct ChargeMonopoles;
+
+    impl ForceLaw for ChargeMonopoles {
+        fn strength(&self, body: &Body) -> f64 {
+            body.charge
+        }
+
+        fn coupling(&self, body: &Body) -> f64 {
+            body.charge
+        }
+
+        fn kernel(&self, d: Vector2<f64>, params: &PhysicsParams) -> Vector2<f64> {
+            Coulomb::new(1.0).kernel(d, params)
+        }
+    }
+
+    #[test]
+    fn coulomb_dipoles_handle_neutral_clusters(){
+        //tight neutral clusters of opposite charges, their monopoles cancel
+        let mut bodies: Vec<Body> = Vec::new();
+        for _ in 0..40{
+            let center = Body::random(0.0, 1000.0).pos;
+            for (offset, charge) in [(Vector2::new(3.0, 1.0), 1.0), (Vector2::new(-2.0, -3.0), -1.0), (Vector2::new(1.0, -2.0), 0.5), (Vector2::new(-1.0, 2.5), -0.5)]{
+                bodies.push(Body::with_mass_and_pos(1.0, center + offset).with_charge(charge));
+            }
+        }
+        let mut naive: Vec<Body> = bodies.clone();
+        let params = BarnesHutRunner::new().params;
+        pairwise_forces_with(&Coulomb::new(1.0), &mut naive, &params);
+        let exact: Vec<Vector2<f64>> = naive.iter().map(|body| body.force).collect();
+
+        let mut medians: Vec<f64> = Vec::new();
+        for law in [Box::new(ChargeMonopoles) as Box<dyn ForceLaw>, Box::new(Coulomb::new(1.0))]{
+            let mut runner: BarnesHutRunner = BarnesHutRunner::from_theta(0.5);
+            runner.law = law;
+            let mut qt: Quadtree = Quadtree::new(Rectangle::new(Vector2::new(0.0,0.0),Vector2::new(1.0,1.0)),1);
+            let mut tree_bodies: Vec<Body> = bodies.clone();
+            runner.evaluate_forces(&mut qt, &mut tree_bodies);
+            let tree: Vec<Vector2<f64>> = tree_bodies.iter().map(|body| body.force).collect();
+            medians.push(ErrorStats::from_errors(&relative_errors(&tree, &exact)).median);
+        }
+        assert!(medians[1] < 1e-2, "{:?}", medians);
+        assert!(medians[1] < 0.2 * medians[0], "{:?}", medians);
+
+        //like charges push apart
+        let mut simulation = Simulation::new();
+        simulation.law = Box::new(Coulomb::new(1.0));
+        simulation.bodies = vec![Body::with_mass_and_pos(1.0, Vector2::new(0.0, 0.0)).with_charge(2.0), Body::with_mass_and_pos(1.0, Vector2::new(1.0, 0.0)).with_charge(2.0)];
+        simulation.update();
+        assert!(simulation.bodies[0].velocity.x < 0.0 && simulation.bodies[1].velocity.x > 0.0);
+    }
+
+    #[test]
+    fn quadrupole_improves_accuracy(){
+        let mut rng = fastrand::Rng::with_seed(400);
+        let mut bodies: Vec<Body> = (0..400).map(|_| Body::with_mass_and_pos(10.0, Vector2::new(rng.f64(), rng.f64()) * 1000.0)).collect();
+        let mut naive: Vec<Body> = bodies.clone();
+        let mut qt: Quadtree = Quadtree::new(Rectangle::new(Vector2::new(0.0,0.0),Vector2::new(1.0,1.0)),1);
+        let mut runner: BarnesHutRunner = BarnesHutRunner::from_theta(1.0);
+        pairwise_forces(&mut naive, &runner.params);
+        runner.resize(&mut qt, &bodies);
+        runner.create_tree(&mut qt, &bodies);
+        let mut errors: Vec<f64> = Vec::new();
+        for order in [MultipoleOrder::Monopole, MultipoleOrder::Quadrupole]{
+            runner.order = order;
+            runner.tree_forces(&qt, &mut bodies);
+            let tree: Vec<Vector2<f64>> = bodies.iter().map(|body| body.force).collect();
+            let exact: Vec<Vector2<f64>> = naive.iter().map(|body| body.force).collect();
+            errors.push(ErrorStats::from_errors(&relative_errors(&tree, &exact)).median);
+        }
+        assert!(errors[1] < 0.5 * errors[0], "{:?}", errors);
+    }
+
+    #[test]
+    fn bmax_opens_node_with_com_at_edge(){
+        let mut runner: BarnesHutRunner = BarnesHutRunner::from_theta(1.0);
+        //a node of width 64 whose mass sits at the edge facing the body, 70 away
+        let node = Candidate { width: 64.0, bmax: 64.0f64.hypot(64.0), distance: 70.0, mass: 1.0, must_open: false };
+        assert!(!runner.opens(&node, 0.0));
+        runner.criterion = OpeningCriterion::Bmax;
+        assert!(runner.opens(&node, 0.0));
+        let inside = Candidate { must_open: true, ..node };
+        runner.criterion = OpeningCriterion::RelativeAcceleration { alpha: 1e9 };
+        assert!(runner.opens(&inside, 1.0));
+        assert!(!runner.opens(&node, 1.0));
+    }
+
+    #[test]
+    fn criteria_report_opened_nodes(){
+        let mut rng = fastrand::Rng::with_seed(300);
+        let initial: Vec<Body> = (0..300).map(|_| Body::with_mass_and_pos(10.0, Vector2::new(rng.f64(), rng.f64()) * 1000.0)).collect();
+        let mut naive: Vec<Body> = initial.clone();
+        let params = BarnesHutRunner::new().params;
+        pairwise_forces(&mut naive, &params);
+        let criteria = [
+            OpeningCriterion::Classic,
+            OpeningCriterion::Bmax,
+            OpeningCriterion::RelativeAcceleration { alpha: 0.001 }
+        ];
+        let mut opened: Vec<u64> = Vec::new();
+        for criterion in criteria{
+            let mut runner: BarnesHutRunner = BarnesHutRunner::with_integrator(0.7, Box::new(Leapfrog));
+            runner.criterion = criterion;
+            let mut qt: Quadtree = Quadtree::new(Rectangle::new(Vector2::new(0.0,0.0),Vector2::new(1.0,1.0)),1);
+            let mut bodies: Vec<Body> = initial.clone();
+            //the relative criterion needs an acceleration from the step before
+            for (body, exact) in bodies.iter_mut().zip(naive.iter()){
+                body.acceleration = exact.force / exact.mass;
+            }
+            runner.evaluate_forces(&mut qt, &mut bodies);
+            let error = bodies.iter().zip(naive.iter()).map(|(tree, naive)| (tree.force - naive.force).magnitude() / naive.force.magnitude()).sum::<f64>() / bodies.len() as f64;
+            assert!(error < 0.1, "{:?} error {}", criterion, error);
+            runner.update(&mut qt, &mut bodies);
+            let first_step = runner.opened_nodes();
+            assert!(first_step > 0);
+            //the count starts over every step
+            runner.update(&mut qt, &mut bodies);
+            assert!(runner.opened_nodes() < 2 * first_step);
+            opened.push(first_step);
+        }
+        assert!(opened[0] != opened[1] && opened[0] != opened[2]);
+    }
+
+    #[test]
+    fn ewald_table_only_for_inverse_square_laws(){
+        let domain = Rectangle::new(Vector2::new(0.0,0.0),Vector2::new(100.0,100.0));
+        let mut runner: BarnesHutRunner = BarnesHutRunner::from_theta(0.5);
+        runner.law = Box::new(BiotSavart::new(0.0));
+        runner.set_boundary(Boundary::Periodic(domain));
+        let mut bodies: Vec<Body> = vec![Body::with_pos(Vector2::new(10.0, 50.0)).with_circulation(1.0), Body::with_pos(Vector2::new(90.0, 50.0))];
+        runner.evaluate_forces(&mut Quadtree::new(domain,1), &mut bodies);
+        assert!(runner.ewald.get().is_none());
+        runner.law = Box::new(Gravity);
+        runner.evaluate_forces(&mut Quadtree::new(domain,1), &mut bodies);
+        assert!(runner.ewald.get().is_some());
+        runner.set_boundary(Boundary::PeriodicX(domain));
+        runner.evaluate_forces(&mut Quadtree::new(domain,1), &mut bodies);
+        assert!(runner.ewald.get().is_none());
+    }
+
+    #[test]
+    fn lone_body_feels_no_force(){
+        let runner: BarnesHutRunner = BarnesHutRunner::from_theta(1.0);
+        let mut qt: Quadtree = Quadtree::new(Rectangle::new(Vector2::new(0.0,0.0),Vector2::new(1.0,1.0)),4);
+        let mut bodies: Vec<Body> = vec![Body::with_mass_and_pos(10.0, Vector2::new(3.0, 4.0))];
+        runner.evaluate_forces(&mut qt, &mut bodies);
+        assert_eq!(bodies[0].force, Vector2::new(0.0, 0.0));
+    }
+
+    #[test]
+    fn update_matches_simulation(){
+        let mut runner: BarnesHutRunner = BarnesHutRunner::with_integrator(0.0, Box::new(Leapfrog));
+        let mut simulation: Simulation = Simulation::with_integrator(Box::new(Leapfrog));
+        runner.params.dt = 0.01;
+        simulation.params.dt = 0.01;
+        simulation.bodies = (0..20).map(|_| Body::random(0.0, 50.0)).collect();
+        let mut bodies: Vec<Body> = simulation.bodies.clone();
+        let mut qt: Quadtree = Quadtree::new(Rectangle::new(Vector2::new(0.0,0.0),Vector2::new(1.0,1.0)),2);
+        for _ in 0..50{
+            runner.force_iterate(&mut qt, &mut bodies);
+            simulation.update();
+        }
+        for (tree, naive) in bodies.iter().zip(simulation.bodies.iter()){
+            assert!((tree.pos - naive.pos).magnitude() < 1e-9);
+            assert!((tree.velocity - naive.velocity).magnitude() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn body_on_the_max_corner_does_not_pull_itself(){
+        //the tree is resized so the outermost body sits on the bottom right edge of the root
+        let mut bodies: Vec<Body> = vec![
+            Body::with_mass_and_pos(10.0, Vector2::new(0.0, 0.0)),
+            Body::with_mass_and_pos(10.0, Vector2::new(1.0, 0.0)),
+            Body::with_mass_and_pos(10.0, Vector2::new(0.0, 1.0)),
+            Body::with_mass_and_pos(5.0, Vector2::new(100.0, 100.0)),
+            Body::with_mass_and_pos(5.0, Vector2::new(99.0, 100.0))
+        ];
+        let mut naive: Vec<Body> = bodies.clone();
+        pairwise_forces(&mut naive, &PhysicsParams::default());
+        let runner: BarnesHutRunner = BarnesHutRunner::from_theta(1.0);
+        let mut qt: Quadtree = Quadtree::new(Rectangle::new(Vector2::new(0.0,0.0),Vector2::new(1.0,1.0)),1);
+        runner.evaluate_forces(&mut qt, &mut bodies);
+        for (tree, naive) in bodies.iter().zip(naive.iter()).skip(3){
+            assert!((tree.force - naive.force).magnitude() < 1e-2 * naive.force.magnitude(), "{:?} != {:?}", tree.force, naive.force);
+        }
+    }
+
+    #[test]
+    fn higher_order_steps_are_reversible(){
+        //forward, turn around and back again, through the tree and through the direct sum
+        let integrators: [fn() -> Box<dyn Integrator>; 2] = [|| Box::new(ForestRuth), || Box::new(Yoshida6)];
+        for integrator in integrators{
+            let initial: Vec<Body> = (0..30).map(|_| Body::random(0.0, 100.0)).collect();
+            let mut runner: BarnesHutRunner = BarnesHutRunner::with_integrator(0.7, integrator());
+            runner.params.dt = 0.05;
+            let mut simulation: Simulation = Simulation::with_integrator(integrator());
+            simulation.params.dt = 0.05;
+            simulation.bodies = initial.clone();
+            let mut bodies: Vec<Body> = initial.clone();
+            let mut qt: Quadtree = Quadtree::new(Rectangle::new(Vector2::new(0.0,0.0),Vector2::new(1.0,1.0)),1);
+            for pass in 0..2{
+                if pass == 1 {
+                    assert!(bodies.iter().zip(initial.iter()).any(|(body, start)| (body.pos - start.pos).magnitude() > 1e-2));
+                    for body in bodies.iter_mut().chain(simulation.bodies.iter_mut()){
+                        body.velocity = -body.velocity;
+                    }
+                }
+                for _ in 0..20{
+                    runner.force_iterate(&mut qt, &mut bodies);
+                    simulation.update();
+                }
+            }
+            for (start, (tree, naive)) in initial.iter().zip(bodies.iter().zip(simulation.bodies.iter())){
+                assert!((tree.pos - start.pos).magnitude() < 1e-9, "{:?} != {:?}", tree.pos, start.pos);
+                assert!((naive.pos - start.pos).magnitude() < 1e-9, "{:?} != {:?}", naive.pos, start.pos);
+            }
+        }
+    }
+
+    #[test]
+    fn threads_match_serial(){
+        let initial: Vec<Body> = (0..500).map(|_| Body::random(0.0, 200.0)).collect();
+        let mut results: Vec<Vec<Body>> = Vec::new();
+        for threads in [1, 4]{
+            let mut runner: BarnesHutRunner = BarnesHutRunner::with_integrator(0.7, Box::new(Leapfrog));
+            runner.set_threads(threads);
+            assert_eq!(runner.threads(), threads);
+            let mut qt: Quadtree = Quadtree::new(Rectangle::new(Vector2::new(0.0,0.0),Vector2::new(1.0,1.0)),1);
+            let mut bodies: Vec<Body> = initial.clone();
+            for _ in 0..10{
+                runner.iterate(&mut qt, &mut bodies);
+            }
+            results.push(bodies);
+        }
+        for (serial, parallel) in results[0].iter().zip(results[1].iter()){
+            assert_eq!(serial.pos, parallel.pos);
+            assert_eq!(serial.velocity, parallel.velocity);
+        }
+    }
+
+    #[test]
+    fn octree_forces_match_naive(){
+        let runner: BarnesHutRunner = BarnesHutRunner::from_theta(0.0);
+        let mut octree: Octree = Octree::new(Cuboid::new(Vector3::new(0.0,0.0,0.0),Vector3::new(1.0,1.0,1.0)),2);
+        //a fixed cloud, a random one now and then puts a body where its pulls nearly cancel
+        let mut rng = fastrand::Rng::with_seed(60);
+        let mut bodies: Vec<Body3> = (0..60).map(|_| Body3::with_mass_and_pos(10.0, Vector3::new(rng.f64(), rng.f64(), rng.f64()) * 100.0)).collect();
+        runner.evaluate_forces3(&mut octree, &mut bodies);
+        for body in &bodies{
+            let mut naive: Vector3<f64> = Vector3::new(0.0,0.0,0.0);
+            for other in bodies.iter().filter(|other| other.id != body.id){
+                naive += gravity::force_mass_center3(body, other.pos, other.mass, &runner.params);
+            }
+            assert!((body.force - naive).magnitude() <= 1e-9 * naive.magnitude());
+        }
+
+        //at theta 0.5 the walk still has to be close
+        let runner: BarnesHutRunner = BarnesHutRunner::from_theta(0.5);
+        for body in &bodies{
+            let force = runner.barnes_hut_force3(&octree, &bodies, body);
+            assert!((force - body.force).magnitude() <= 0.05 * body.force.magnitude(), "{:?} != {:?}", force, body.force);
+        }
+    }
+
+    #[test]
+    fn flat_octree_matches_quadtree(){
+        let runner: BarnesHutRunner = BarnesHutRunner::from_theta(0.0);
+        let mut bodies: Vec<Body> = (0..40).map(|_| Body::random(0.0, 100.0)).collect();
+        let mut lifted: Vec<Body3> = bodies.iter().map(|body| Body3::from_body(body, 0.0)).collect();
+        let mut qt: Quadtree = Quadtree::new(Rectangle::new(Vector2::new(0.0,0.0),Vector2::new(1.0,1.0)),1);
+        let mut octree: Octree = Octree::new(Cuboid::new(Vector3::new(0.0,0.0,0.0),Vector3::new(1.0,1.0,1.0)),1);
+        runner.evaluate_forces(&mut qt, &mut bodies);
+        runner.evaluate_forces3(&mut octree, &mut lifted);
+        for (body, body3) in bodies.iter().zip(lifted.iter()){
+            assert!((body.force.extend(0.0) - body3.force).magnitude() <= 1e-9 * body.force.magnitude());
+        }
+    }
+
+    #[test]
+    fn octree_binary_keeps_orbit(){
+        let mut runner: BarnesHutRunner = BarnesHutRunner::with_integrator(0.5, Box::new(Leapfrog));
+        runner.params.dt = 0.001;
+        runner.params.softening = crate::softening::Softening::None { min_distance: 0.0 };
+        let speed = 0.5f64.sqrt();
+        let mut bodies: Vec<Body3> = vec![
+            Body3::with_mass_and_pos(1.0, Vector3::new(0.5, 0.0, 0.0)),
+            Body3::with_mass_and_pos(1.0, Vector3::new(-0.5, 0.0, 0.0))
+        ];
+        //orbiting in the xz plane
+        bodies[0].velocity = Vector3::new(0.0, 0.0, speed);
+        bodies[1].velocity = Vector3::new(0.0, 0.0, -speed);
+        let mut octree: Octree = Octree::new(Cuboid::new(Vector3::new(-1.0,-1.0,-1.0),Vector3::new(1.0,1.0,1.0)),1);
+        for _ in 0..1000{
+            runner.iterate3(&mut octree, &mut bodies);
+        }
+        assert!((runner.time - 1.0).abs() < 1e-9);
+        assert!(((bodies[0].pos - bodies[1].pos).magnitude() - 1.0).abs() < 1e-4);
+        assert!(bodies[0].pos.y.abs() < 1e-12);
+    }
+
+    #[test]
+    fn octree_runs_through_the_integrator(){
+        //the same circular binary, which turns at sqrt(2) radians per unit time
+        let error = |integrator: Box<dyn Integrator>| {
+            let mut runner: BarnesHutRunner = BarnesHutRunner::with_integrator(0.5, integrator);
+            runner.params.dt = 0.01;
+            runner.params.softening = Softening::None { min_distance: 0.0 };
+            let speed = 0.5f64.sqrt();
+            let mut bodies: Vec<Body3> = vec![
+                Body3::with_mass_and_pos(1.0, Vector3::new(0.5, 0.0, 0.0)),
+                Body3::with_mass_and_pos(1.0, Vector3::new(-0.5, 0.0, 0.0))
+            ];
+            bodies[0].velocity = Vector3::new(0.0, 0.0, speed);
+            bodies[1].velocity = Vector3::new(0.0, 0.0, -speed);
+            let mut octree: Octree = Octree::new(Cuboid::new(Vector3::new(-1.0,-1.0,-1.0),Vector3::new(1.0,1.0,1.0)),1);
+            for _ in 0..100{
+                runner.iterate3(&mut octree, &mut bodies);
+            }
+            let angle = 2.0f64.sqrt() * runner.time;
+            (bodies[0].pos - Vector3::new(0.5 * angle.cos(), 0.0, 0.5 * angle.sin())).magnitude()
+        };
+        let leapfrog = error(Box::new(Leapfrog));
+        let yoshida = error(Box::new(Yoshida6));
+        assert!(yoshida < 1e-9, "{}", yoshida);
+        assert!(yoshida < 1e-3 * leapfrog, "{} {}", yoshida, leapfrog);
+    }
 
     #[test]
     fn test_resize(){
         let rec: Rectangle = Rectangle::new(Vector2::new(0.0f64,0.0f64),Vector2::new(100.0f64,100.0f64));
         let mut qt: Quadtree = Quadtree::new(rec,1);
         let mut bodies: Vec<Body> = Vec::new();
-        let mut runner: BarnesHutRunner = BarnesHutRunner::from_theta(0.5f64);
+        let runner: BarnesHutRunner = BarnesHutRunner::from_theta(0.5f64);
         // runner.insert(Body::with_pos(Vector2::new(1.0,1.0)));
         bodies.push(Body::with_pos(Vector2::new(110.0,1.0)));
         bodies.push(Body::with_pos(Vector2::new(1.0,150.0)));
         bodies.push(Body::with_pos(Vector2::new(-10.0,120.0)));
         bodies.push(Body::with_pos(Vector2::new(-10.0,-20.0)));
-        runner.resize(&mut qt, &mut bodies);
-        runner.create_tree(&mut qt, &mut bodies);
+        runner.resize(&mut qt, &bodies);
+        runner.create_tree(&mut qt, &bodies);
         assert_eq!(qt.boundaries.tl.x,-20.0);
         assert_eq!(qt.boundaries.tl.y,-20.0);
         assert_eq!(qt.boundaries.br.x,150.0);
         assert_eq!(qt.boundaries.br.y,150.0);
-        for st in qt.subtrees{
+        for st in qt.subtrees(){
             println!("{:?}",st.boundaries);
         }
     }
 
+    #[test]
+    fn subset_forces_match_the_full_walk(){
+        let mut bodies: Vec<Body> = (0..300).map(|_| Body::random(0.0, 1000.0)).collect();
+        let runner = BarnesHutRunner::from_theta(0.7);
+        let mut qt: Quadtree = Quadtree::new(Rectangle::new(Vector2::new(0.0,0.0),Vector2::new(1.0,1.0)),1);
+        runner.evaluate_forces(&mut qt, &mut bodies);
+        let full: Vec<Vector2<f64>> = bodies.iter().map(|body| body.force).collect();
+        for body in bodies.iter_mut(){
+            body.force = Vector2::new(0.0, 0.0);
+        }
+        runner.subset_forces(&qt, &mut bodies, &[3, 150, 299]);
+        for (index, body) in bodies.iter().enumerate(){
+            let expected = if [3, 150, 299].contains(&index) { full[index] } else { Vector2::new(0.0, 0.0) };
+            assert_eq!(body.force, expected);
+        }
+    }
 }
 
 /*
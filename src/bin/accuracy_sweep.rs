@@ -0,0 +1,30 @@
+use barnes_hut::accuracy::{compare_with, exact_forces};
+use barnes_hut::bh_runner::BarnesHutRunner;
+use barnes_hut::body::Body;
+use barnes_hut::quadtree::{Quadtree, Rectangle};
+use cgmath::Vector2;
+
+/// Prints force error against cost for a range of `theta` and leaf limits.
+/// Usage: accuracy_sweep [body count]
+fn main() {
+    let body_count: i32 = std::env::args().nth(1).and_then(|count| count.parse().ok()).unwrap_or(2000);
+    let width = 1000.0;
+    let height = 1000.0;
+    let mut bodies: Vec<Body> = Vec::new();
+    let mut runner: BarnesHutRunner = BarnesHutRunner::new();
+    runner.generate_bivariate_random_dist(&mut bodies, width, height, body_count, 10.0, 0.75);
+    let exact = exact_forces(&bodies, &runner.params);
+
+    println!("{} bodies", bodies.len());
+    println!("{:>6} {:>6} {:>11} {:>11} {:>11} {:>13} {:>8} {:>10}", "theta", "limit", "median", "p99", "max", "opened nodes", "nodes", "time (ms)");
+    for limit in [1, 4, 16]{
+        for theta in [0.1, 0.3, 0.5, 0.7, 1.0, 1.5]{
+            runner.theta = theta;
+            let mut qt: Quadtree = Quadtree::new(Rectangle::new(Vector2::new(0.0,0.0),Vector2::new(width,height)),limit);
+            let report = compare_with(&runner, &mut qt, &bodies, &exact);
+            println!("{:>6} {:>6} {:>11.3e} {:>11.3e} {:>11.3e} {:>13} {:>8} {:>10.2}",
+                     theta, limit, report.stats.median, report.stats.percentile_99, report.stats.max,
+                     report.opened_nodes, report.tree_nodes, report.elapsed.as_secs_f64() * 1000.0);
+        }
+    }
+}
@@ -10,8 +10,8 @@ fn main() {
     let mut runner: BarnesHutRunner = BarnesHutRunner::from_theta(0.5f64);
     runner.generate_circle(&mut bodies,450.0,500.0,30.0);
     runner.generate_circle(&mut bodies,550.0,500.0,30.0);
-    runner.resize(&mut qt,&mut bodies);
-    runner.create_tree(&mut qt,&mut bodies);
+    runner.resize(&mut qt,&bodies);
+    runner.create_tree(&mut qt,&bodies);
     for _ in 1..100{
         runner.iterate(&mut qt,&mut bodies);
     }
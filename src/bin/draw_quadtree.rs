@@ -10,7 +10,7 @@ use winit::window::WindowBuilder;
 use winit_input_helper::WinitInputHelper;
 use barnes_hut::bh_runner::BarnesHutRunner;
 use barnes_hut::body::Body;
-use barnes_hut::quadtree::{Quadtree, Rectangle};
+use barnes_hut::quadtree::{Quadtree, Rectangle, Subtree};
 use barnes_hut::canvas::Canvas;
 const DEBUG: bool = false;
 
@@ -63,9 +63,9 @@ fn main() -> Result<(), Error> {
     // bodies.last_mut().unwrap().velocity.x = 1.0;
     // bodies.push(Body::with_mass_and_pos(100000.0,Vector2::new(WIDTH_F/2.0,HEIGHT_F/2.0)));
     // runner.generate_square(&mut bodies, 100, 450.0, 450.0);
-    runner.resize(&mut qt,&mut bodies);
+    runner.resize(&mut qt,&bodies);
     println!("{:?}",qt.boundaries);
-    runner.create_tree(&mut qt,&mut bodies);
+    runner.create_tree(&mut qt,&bodies);
     runner.paused = true;
     println!("{:?}",bodies.len());
     event_loop.run(move |event, _, control_flow| {
@@ -73,11 +73,11 @@ fn main() -> Result<(), Error> {
         if let Event::RedrawRequested(_) = event {
 
             runner.iterate(&mut qt, &mut bodies);
-            if(DEBUG) {runner.print_bodies(&qt);}
+            if(DEBUG) {runner.print_bodies(&qt, &bodies);}
 
             match(draw_boxes){
-                true => {recursively_draw_tree(&mut canvas, &qt);},
-                false => {recursively_draw_tree_no_box(&mut canvas, &qt);}
+                true => {recursively_draw_tree(&mut canvas, qt.view(), &bodies);},
+                false => {recursively_draw_tree_no_box(&mut canvas, qt.view(), &bodies);}
             }
             canvas.copy_huemap_to_buffer(pixels.frame_mut());
             canvas.clear();
@@ -122,16 +122,16 @@ fn main() -> Result<(), Error> {
 
 }
 
-fn recursively_draw_tree_no_box(canvas: &mut Canvas, qt: &Quadtree){
-    for tree in &qt.subtrees{
-        recursively_draw_tree_no_box(canvas,&tree);
+fn recursively_draw_tree_no_box(canvas: &mut Canvas, qt: Subtree, bodies: &[Body]){
+    for tree in qt.subtrees(){
+        recursively_draw_tree_no_box(canvas,tree,bodies);
     }
-    draw_bodies(canvas,&qt.bodies);
+    draw_bodies(canvas,&qt.bodies,bodies);
 }
-fn recursively_draw_tree(canvas: &mut Canvas, qt: &Quadtree){
+fn recursively_draw_tree(canvas: &mut Canvas, qt: Subtree, bodies: &[Body]){
     canvas.draw_square_safe(qt.boundaries.tl.x.round() as i32, qt.boundaries.tl.y.round() as i32, qt.boundaries.width() as i32, qt.boundaries.height() as i32,&(0.0,0.0,1.0));
-    for tree in &qt.subtrees{
-        recursively_draw_tree(canvas,&tree);
+    for tree in qt.subtrees(){
+        recursively_draw_tree(canvas,tree,bodies);
     }
     // match qt.center_of_mass {
     //     Some(center_of_mass) => {
@@ -146,30 +146,19 @@ fn recursively_draw_tree(canvas: &mut Canvas, qt: &Quadtree){
     //     None => {}
     // }
 
-    draw_bodies(canvas,&qt.bodies);
+    draw_bodies(canvas,&qt.bodies,bodies);
 
 }
 
-fn draw_bodies(canvas: &mut Canvas, bodies: &Vec<Body>){
-    match(bodies.is_empty()){
+fn draw_bodies(canvas: &mut Canvas, indices: &[usize], bodies: &[Body]){
+    match(indices.is_empty()){
         true => {}
         false => {
-            for body in bodies{
-                update_pixel_heat(canvas, body); //not a fan of this casting
+            for index in indices{
+                canvas.draw_body(&bodies[*index]);
             }
         }
     }
 }
 
-fn update_pixel_heat(canvas: &mut Canvas, body: &Body){
-    let x_pos: i32 = body.pos.x.round() as i32;
-    let y_pos: i32 = body.pos.y.round() as i32;
-
-    if(!canvas.pos_valid(x_pos,y_pos)){
-        return;
-    }
-
-    canvas.increment_huemap(body.pos.x as i32, body.pos.y as i32, (240.0,1.0,1.0),-1.0);
-}
-
 
@@ -21,18 +21,20 @@ fn main() -> Result<(), image::ImageError> {
     let mut runner: BarnesHutRunner = BarnesHutRunner::from_theta(1.0f64);
     let mut canvas: Canvas = Canvas::new(width ,height, (0,0,0,0));
 
-    // I set dt in gravity.rs to 0.001 instead of 0.01
+    // dt of 0.001 instead of 0.01
     // this makes the sim much slower but more accurate
+    runner.params.dt = 0.001;
+    runner.set_threads(std::thread::available_parallelism().map(|threads| threads.get()).unwrap_or(1));
 
     runner.generate_bivariate_random_dist(&mut bodies, width as f64, height as f64, 100000, 10.0, 0.75);
-    runner.resize(&mut qt,&mut bodies);
-    runner.create_tree(&mut qt,&mut bodies);
+    runner.resize(&mut qt,&bodies);
+    runner.create_tree(&mut qt,&bodies);
     runner.paused = false;
     for i in 0..10000{
         canvas.clear();
         runner.iterate(&mut qt, &mut bodies);
         print!("{:?}\n",i);
-        draw_bodies(&mut canvas, &bodies);
+        canvas.draw_bodies(&bodies);
 
         // Copy simulation
         for (pixel,hue) in img.pixels_mut().zip(canvas.huemap.iter()) {
@@ -51,26 +53,3 @@ fn main() -> Result<(), image::ImageError> {
     Ok(())
 
 }
-
-fn draw_bodies(canvas: &mut Canvas, bodies: &Vec<Body>){
-    match(bodies.is_empty()){
-        true => {}
-        false => {
-            for body in bodies{
-                update_pixel_heat(canvas, body); //not a fan of this casting
-            }
-        }
-    }
-}
-
-fn update_pixel_heat(canvas: &mut Canvas, body: &Body){
-    let x_pos: i32 = body.pos.x.round() as i32;
-    let y_pos: i32 = body.pos.y.round() as i32;
-
-    if(!canvas.pos_valid(x_pos,y_pos)){
-        return;
-    }
-
-    canvas.increment_huemap(body.pos.x as i32, body.pos.y as i32, (240.0,1.0,1.0),-1.0);
-}
-
@@ -0,0 +1,51 @@
+use image::{ImageBuffer, Rgb};
+use barnes_hut::bh_runner::BarnesHutRunner;
+use barnes_hut::body::Body3;
+use barnes_hut::integrator::Leapfrog;
+use barnes_hut::octree::{Cuboid, Octree};
+use cgmath::Vector3;
+use hsv::hsv_to_rgb;
+use barnes_hut::canvas::{Canvas, Projection};
+
+fn main() -> Result<(), image::ImageError> {
+    let width = 2000;
+    let height = 2000;
+    let depth = 2000.0;
+    let mut img = ImageBuffer::from_pixel(width, height, Rgb([255u8, 255u8, 255u8]));
+
+    let cuboid: Cuboid = Cuboid::new(Vector3::new(0.0f64,0.0f64,0.0f64),Vector3::new(width as f64,height as f64,depth));
+    let mut octree: Octree = Octree::new(cuboid,1);
+    let mut bodies: Vec<Body3> = Vec::new();
+    let mut runner: BarnesHutRunner = BarnesHutRunner::from_theta(1.0f64);
+    let mut canvas: Canvas = Canvas::new(width ,height, (0,0,0,0));
+
+    runner.integrator = Box::new(Leapfrog);
+    runner.params.dt = 0.001;
+    runner.set_threads(std::thread::available_parallelism().map(|threads| threads.get()).unwrap_or(1));
+
+    runner.generate_bivariate_random_dist3(&mut bodies, cuboid.max, 100000, 10.0, 0.75);
+    runner.paused = false;
+
+    //the camera slowly circles the middle of the box, tilted to show the depth
+    let mut projection: Projection = Projection::new(cuboid.midpoint(), 0.0, 0.6, 0.5);
+    for i in 0..10000{
+        canvas.clear();
+        runner.iterate3(&mut octree, &mut bodies);
+        println!("{:?}",i);
+        projection.yaw = i as f64 * 0.002;
+        for body in &bodies{
+            let (x_pos, y_pos) = projection.project(body.pos, width, height);
+            canvas.increment_huemap(x_pos, y_pos, (240.0,1.0,1.0),-1.0);
+        }
+
+        for (pixel,hue) in img.pixels_mut().zip(canvas.huemap.iter()) {
+            let (h,s,v) = *hue;
+            let rgb = hsv_to_rgb(h,s,v);
+            *pixel = Rgb([rgb.0 as u8,rgb.1 as u8,rgb.2 as u8]);
+        }
+
+        img.save(format!("frames/output{i}.png"))?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,53 @@
+use image::{ImageBuffer, Rgb};
+use barnes_hut::body::Body;
+use barnes_hut::canvas::Canvas;
+use barnes_hut::quadtree::{Quadtree, Rectangle};
+use barnes_hut::vortex::{kelvin_helmholtz, vortex_merger, VortexSolver};
+use cgmath::Vector2;
+use hsv::hsv_to_rgb;
+
+/// Renders a point-vortex flow to frames/vortex{i}.png.
+/// Usage: vortex_renderer [kh | merger]
+fn main() -> Result<(), image::ImageError> {
+    let width = 1000;
+    let height = 1000;
+    let mut img = ImageBuffer::from_pixel(width, height, Rgb([255u8, 255u8, 255u8]));
+
+    let domain: Rectangle = Rectangle::new(Vector2::new(0.0f64,0.0f64),Vector2::new(width as f64 ,height as f64));
+    let mut qt: Quadtree = Quadtree::new(domain,1);
+    let mut canvas: Canvas = Canvas::new(width ,height, (0,0,0,0));
+    let scenario = std::env::args().nth(1).unwrap_or(String::from("kh"));
+
+    let mut bodies: Vec<Body>;
+    let mut solver: VortexSolver;
+    match scenario.as_str() {
+        "merger" => {
+            bodies = vortex_merger(domain.midpoint(), 200.0, 75.0, 5000, 50000.0);
+            solver = VortexSolver::new(0.5, 2.0);
+        }
+        _ => {
+            //a sheet of two wavelengths across a periodic box, rolling up into two vortices
+            bodies = kelvin_helmholtz(&domain, 4000, 50.0, 10.0, 2);
+            solver = VortexSolver::periodic(0.5, 25.0, domain);
+        }
+    }
+    solver.runner.params.dt = 0.05;
+    solver.runner.set_threads(std::thread::available_parallelism().map(|threads| threads.get()).unwrap_or(1));
+
+    for i in 0..1000{
+        canvas.clear();
+        solver.iterate(&mut qt, &mut bodies);
+        println!("{:?}",i);
+        canvas.draw_vortices(&bodies);
+
+        for (pixel,hue) in img.pixels_mut().zip(canvas.huemap.iter()) {
+            let (h,s,v) = *hue;
+            let rgb = hsv_to_rgb(h,s,v);
+            *pixel = Rgb([rgb.0 as u8,rgb.1 as u8,rgb.2 as u8]);
+        }
+
+        img.save(format!("frames/vortex{i}.png"))?;
+    }
+
+    Ok(())
+}
@@ -1,22 +1,96 @@
-use cgmath::Vector2;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::f64::consts::PI;
+use cgmath::{Vector2, Vector3};
 
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Whether a body pulls on the others
+#[derive(Debug,Copy,Clone,PartialEq,Eq,Default)]
+pub enum BodyKind {
+    #[default]
+    Massive,
+    /// A test particle: it feels the field but adds nothing to it, whatever its `mass`.
+    /// Its `force` is the force per unit mass, so it is also its acceleration.
+    Tracer,
+    /// A massive SPH particle that also feels pressure, see `sph::Sph`
+    Gas
+}
+
+/// The hydrodynamic state of a `BodyKind::Gas` body, see `sph`
+#[derive(Debug,Copy,Clone,PartialEq,Default)]
+pub struct Gas {
+    /// Specific internal energy
+    pub internal_energy: f64,
+    /// Rate of change of `internal_energy` at the last force evaluation
+    pub energy_rate: f64,
+    pub density: f64,
+    pub pressure: f64,
+    /// Half the radius of the kernel, adapted to the neighbour count
+    pub smoothing_length: f64
+}
+
+/// Every constructor hands out a new id, copies of a body keep the id of the original
 #[derive(Debug,Copy,Clone)]
 pub struct Body {
+    pub id: u64,
+    pub tag: Option<u32>,
+    pub kind: BodyKind,
     pub pos: Vector2<f64>,
     pub acceleration: Vector2<f64>,
     pub velocity: Vector2<f64>,
     pub mass: f64,
+    /// Source of the Coulomb force, see `force_law::Coulomb`
+    pub charge: f64,
+    /// Strength of the body as a point vortex, see `vortex::BiotSavart`
+    pub circulation: f64,
+    pub gas: Gas,
     pub force: Vector2<f64>
 }
 
+/// A body in three dimensions, see `octree` and the `*3` functions of `BarnesHutRunner`.
+/// Ids come from the same counter as `Body`.
+#[derive(Debug,Copy,Clone)]
+pub struct Body3 {
+    pub id: u64,
+    pub tag: Option<u32>,
+    pub pos: Vector3<f64>,
+    pub acceleration: Vector3<f64>,
+    pub velocity: Vector3<f64>,
+    pub mass: f64,
+    pub force: Vector3<f64>
+}
+
+/// Returns an id no other body has been given yet
+pub fn next_id() -> u64 {
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Makes sure ids up to and including `id` are never handed out again, used after loading bodies from a file.
+/// Returns false and reserves nothing when `id` is `u64::MAX`, there would be no id left to hand out.
+pub fn reserve_ids(id: u64) -> bool {
+    match id.checked_add(1) {
+        Some(next) => {
+            NEXT_ID.fetch_max(next, Ordering::Relaxed);
+            true
+        }
+        None => false
+    }
+}
+
 
 impl Body{
     pub fn new() -> Body{
         Self {
+            id: next_id(),
+            tag: None,
+            kind: BodyKind::Massive,
             pos: Vector2::new(0.0,0.0,),
             acceleration: Vector2::new(0.0,0.0),
             velocity: Vector2::new(0.0,0.0),
             mass: 0.0,
+            charge: 0.0,
+            circulation: 0.0,
+            gas: Gas::default(),
             force: Vector2::new(0.0,0.0)
         }
     }
@@ -29,31 +103,112 @@ impl Body{
 
     pub fn with_mass(mass: f64) -> Body {
         Self {
-            pos: Vector2::new(0.0,0.0),
-            acceleration: Vector2::new(0.0,0.0),
-            velocity: Vector2::new(0.0,0.0),
             mass,
-            force: Vector2::new(0.0,0.0)
+            ..Self::new()
         }
     }
 
     pub fn with_mass_and_pos(mass: f64, pos: Vector2<f64>) -> Body {
         Self {
             pos,
-            acceleration: Vector2::new(0.0,0.0),
-            velocity: Vector2::new(0.0,0.0),
-            mass,
-            force: Vector2::new(0.0,0.0)
+            ..Self::with_mass(mass)
         }
     }
 
     pub fn with_pos(pos: Vector2<f64>) -> Body {
+        Self::with_mass_and_pos(1.0, pos)
+    }
+
+    /// A massless tracer at `pos`
+    pub fn tracer(pos: Vector2<f64>) -> Body {
         Self {
-            pos,
-            acceleration: Vector2::new(0.0,0.0),
-            velocity: Vector2::new(0.0,0.0),
-            mass: 1.0,
-            force: Vector2::new(0.0,0.0)
+            kind: BodyKind::Tracer,
+            ..Self::with_mass_and_pos(0.0, pos)
+        }
+    }
+
+    /// A gas particle of `mass` at `pos` with specific `internal_energy`
+    pub fn gas(mass: f64, pos: Vector2<f64>, internal_energy: f64) -> Body {
+        Self {
+            kind: BodyKind::Gas,
+            gas: Gas {
+                internal_energy,
+                ..Gas::default()
+            },
+            ..Self::with_mass_and_pos(mass, pos)
+        }
+    }
+
+    pub fn is_gas(&self) -> bool {
+        self.kind == BodyKind::Gas
+    }
+
+    pub fn is_tracer(&self) -> bool {
+        self.kind == BodyKind::Tracer
+    }
+
+    /// Whether the body adds to the field, tracers and bodies without mass don't
+    pub fn is_source(&self) -> bool {
+        !self.is_tracer() && self.mass != 0.0
+    }
+
+    /// The mass forces on this body are computed for: its own, or 1 for a tracer so that its force is its acceleration.
+    /// A massive body of no mass is moved like a tracer rather than dividing 0 by 0.
+    pub fn test_mass(&self) -> f64 {
+        match self.kind {
+            BodyKind::Massive | BodyKind::Gas if self.mass != 0.0 => self.mass,
+            _ => 1.0
+        }
+    }
+
+    pub fn with_tag(mut self, tag: u32) -> Body {
+        self.tag = Some(tag);
+        self
+    }
+
+    pub fn with_charge(mut self, charge: f64) -> Body {
+        self.charge = charge;
+        self
+    }
+
+    pub fn with_circulation(mut self, circulation: f64) -> Body {
+        self.circulation = circulation;
+        self
+    }
+
+    /// Radius of a uniform sphere of this mass at `density`, see `collision::Collisions`
+    pub fn radius(&self, density: f64) -> f64 {
+        (3.0 * self.mass / (4.0 * PI * density)).cbrt()
+    }
+
+    /// A copy of this body that is tracked separately from the original
+    pub fn with_new_id(mut self) -> Body {
+        self.id = next_id();
+        self
+    }
+
+    /// Combines two bodies, conserving mass, charge, circulation, momentum, internal energy and the mass-weighted position.
+    /// The result keeps the id and tag of the heavier body, or of the older one if they weigh the same.
+    /// Two bodies of no mass are averaged evenly.
+    pub fn merge(&self, other: &Body) -> Body {
+        let mass = self.mass + other.mass;
+        let (weight_a, weight_b, total) = if mass == 0.0 { (1.0, 1.0, 2.0) } else { (self.mass, other.mass, mass) };
+        let survivor = if self.mass > other.mass || (self.mass == other.mass && self.id <= other.id) { self } else { other };
+        Self {
+            id: survivor.id,
+            tag: survivor.tag,
+            kind: survivor.kind,
+            pos: ((self.pos * weight_a) + (other.pos * weight_b)) / total,
+            acceleration: ((self.acceleration * weight_a) + (other.acceleration * weight_b)) / total,
+            velocity: ((self.velocity * weight_a) + (other.velocity * weight_b)) / total,
+            mass,
+            charge: self.charge + other.charge,
+            circulation: self.circulation + other.circulation,
+            gas: Gas {
+                internal_energy: ((self.gas.internal_energy * weight_a) + (other.gas.internal_energy * weight_b)) / total,
+                ..survivor.gas
+            },
+            force: self.force + other.force
         }
     }
 
@@ -64,9 +219,153 @@ impl Body{
 
 }
 
+impl Body3{
+    pub fn with_mass_and_pos(mass: f64, pos: Vector3<f64>) -> Body3 {
+        Self {
+            id: next_id(),
+            tag: None,
+            pos,
+            acceleration: Vector3::new(0.0,0.0,0.0),
+            velocity: Vector3::new(0.0,0.0,0.0),
+            mass,
+            force: Vector3::new(0.0,0.0,0.0)
+        }
+    }
+
+    pub fn with_pos(pos: Vector3<f64>) -> Body3 {
+        Self::with_mass_and_pos(1.0, pos)
+    }
+
+    pub fn random(offset: f64, area: f64) -> Body3 {
+        Self::with_mass_and_pos(10.0f64,Vector3::new((fastrand::f64()*area) + offset, (fastrand::f64()*area) + offset, (fastrand::f64()*area) + offset))
+    }
+
+    /// The mass forces on this body are computed for, 1 for a body of no mass so that it moves like a tracer
+    pub fn test_mass(&self) -> f64 {
+        if self.mass != 0.0 { self.mass } else { 1.0 }
+    }
+
+    /// The same body placed at height `z` in the plane it moved in, keeps the id and tag
+    pub fn from_body(body: &Body, z: f64) -> Body3 {
+        Self {
+            id: body.id,
+            tag: body.tag,
+            pos: body.pos.extend(z),
+            acceleration: body.acceleration.extend(0.0),
+            velocity: body.velocity.extend(0.0),
+            mass: body.mass,
+            force: body.force.extend(0.0)
+        }
+    }
+
+    pub fn with_tag(mut self, tag: u32) -> Body3 {
+        self.tag = Some(tag);
+        self
+    }
+}
 
 impl std::fmt::Display for Body{
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result{
-        write!(f,"Pos: ({:0>20} {:0>20}) Force: ({:0>20} {:0>20})",self.pos.x,self.pos.y,self.force.x,self.force.y)
+        write!(f,"Id: {} Pos: ({:0>20} {:0>20}) Force: ({:0>20} {:0>20})",self.id,self.pos.x,self.pos.y,self.force.x,self.force.y)
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use cgmath::{Vector2, Vector3};
+    use crate::body::{reserve_ids, Body, Body3, BodyKind};
+
+    #[test]
+    fn massless_body_moves_like_a_tracer(){
+        let massless = Body::with_mass_and_pos(0.0, Vector2::new(2.0, 0.0));
+        assert_eq!(massless.kind, BodyKind::Massive);
+        assert_eq!(massless.test_mass(), 1.0);
+        assert_eq!(Body::gas(0.0, Vector2::new(2.0, 0.0), 1.0).test_mass(), 1.0);
+        assert_eq!(Body::with_mass_and_pos(3.0, Vector2::new(2.0, 0.0)).test_mass(), 3.0);
+        assert_eq!(Body3::from_body(&Body::tracer(Vector2::new(2.0, 0.0)), 1.0).test_mass(), 1.0);
+        assert_eq!(Body3::with_mass_and_pos(3.0, Vector3::new(2.0, 0.0, 0.0)).test_mass(), 3.0);
+    }
+
+    #[test]
+    fn ids_are_unique_and_copied(){
+        let body = Body::with_pos(Vector2::new(1.0,1.0));
+        let other = Body::with_pos(Vector2::new(1.0,1.0));
+        assert_ne!(body.id, other.id);
+        let copy = body;
+        assert_eq!(copy.id, body.id);
+        assert_ne!(body.with_new_id().id, body.id);
+    }
+
+    #[test]
+    fn lifted_body_keeps_identity(){
+        let mut body = Body::with_mass_and_pos(2.0, Vector2::new(1.0, 2.0)).with_tag(3);
+        body.velocity = Vector2::new(-1.0, 0.5);
+        let lifted = Body3::from_body(&body, 4.0);
+        assert_eq!(lifted.id, body.id);
+        assert_eq!(lifted.tag, Some(3));
+        assert_eq!(lifted.pos, Vector3::new(1.0, 2.0, 4.0));
+        assert_eq!(lifted.velocity, Vector3::new(-1.0, 0.5, 0.0));
+        assert_ne!(Body3::with_pos(lifted.pos).id, body.id);
+    }
+
+    #[test]
+    fn tracers_have_unit_test_mass(){
+        let tracer = Body::tracer(Vector2::new(1.0, 2.0));
+        assert!(tracer.is_tracer() && !tracer.is_source());
+        assert_eq!(tracer.mass, 0.0);
+        assert_eq!(tracer.test_mass(), 1.0);
+        let body = Body::with_mass_and_pos(3.0, Vector2::new(1.0, 2.0));
+        assert_eq!(body.kind, BodyKind::Massive);
+        assert_eq!(body.test_mass(), 3.0);
+        assert!(body.is_source() && !Body::with_mass(0.0).is_source());
+        let gas = Body::gas(2.0, Vector2::new(1.0, 2.0), 5.0);
+        assert!(gas.is_gas() && gas.is_source());
+        assert_eq!(gas.test_mass(), 2.0);
+        assert_eq!(gas.merge(&Body::gas(2.0, Vector2::new(0.0, 0.0), 1.0)).gas.internal_energy, 3.0);
+    }
+
+    #[test]
+    fn radius_from_density(){
+        let body = Body::with_mass(4.0 * std::f64::consts::PI / 3.0);
+        assert!((body.radius(1.0) - 1.0).abs() < 1e-12);
+        assert!((body.radius(1.0 / 8.0) - 2.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn reserved_ids_are_skipped(){
+        let id = Body::new().id;
+        assert!(reserve_ids(id + 100));
+        assert!(Body::new().id > id + 100);
+        assert!(!reserve_ids(u64::MAX));
+    }
+
+    #[test]
+    fn merge_conserves_mass_and_momentum(){
+        let mut heavy = Body::with_mass_and_pos(3.0, Vector2::new(0.0, 0.0)).with_tag(7);
+        let mut light = Body::with_mass_and_pos(1.0, Vector2::new(4.0, 0.0));
+        heavy.velocity = Vector2::new(1.0, 0.0);
+        light.velocity = Vector2::new(0.0, 4.0);
+        let merged = light.merge(&heavy);
+        assert_eq!(merged.id, heavy.id);
+        assert_eq!(merged.tag, Some(7));
+        assert_eq!(merged.mass, 4.0);
+        assert_eq!(merged.pos, Vector2::new(1.0, 0.0));
+        assert_eq!(merged.velocity * merged.mass, Vector2::new(3.0, 4.0));
+    }
+
+    #[test]
+    fn massless_bodies_merge_evenly(){
+        let mut first = Body::with_mass_and_pos(0.0, Vector2::new(0.0, 0.0));
+        let mut second = Body::with_mass_and_pos(0.0, Vector2::new(4.0, 2.0));
+        first.velocity = Vector2::new(1.0, 0.0);
+        second.velocity = Vector2::new(-3.0, 2.0);
+        let merged = first.merge(&second);
+        assert_eq!(merged.mass, 0.0);
+        assert_eq!(merged.kind, BodyKind::Massive);
+        assert_eq!(merged.pos, Vector2::new(2.0, 1.0));
+        assert_eq!(merged.velocity, Vector2::new(-1.0, 1.0));
+        //one of no mass adds nothing to the other
+        let heavy = Body::with_mass_and_pos(2.0, Vector2::new(1.0, 1.0));
+        assert_eq!(heavy.merge(&first).pos, heavy.pos);
     }
 }
@@ -0,0 +1,351 @@
+use std::f64::consts::PI;
+use cgmath::{InnerSpace, Vector2, Zero};
+use crate::body::Body;
+use crate::integrator::for_each_body;
+use crate::pm::erfc;
+use crate::quadtree::Rectangle;
+
+/// Points along each side of an `EwaldTable`
+const EWALD_POINTS: usize = 65;
+
+/// What happens to bodies at the edge of the domain, set with `BarnesHutRunner::set_boundary`.
+/// Every mode but `Open` keeps the tree root fixed to its rectangle.
+#[derive(Debug,Copy,Clone,PartialEq,Default)]
+pub enum Boundary {
+    /// No edge, the root grows to hold every body
+    #[default]
+    Open,
+    /// Bodies leaving through one edge come back through the opposite one, and feel the force of an
+    /// infinite lattice of copies of the domain
+    Periodic(Rectangle),
+    /// Periodic across the left and right edges only, with copies of the domain repeated along x.
+    /// The top and bottom are open, bodies may leave through them.
+    PeriodicX(Rectangle),
+    /// Bodies bounce elastically off the edges
+    Reflective(Rectangle),
+    /// Bodies leaving the domain are removed
+    Absorbing(Rectangle)
+}
+
+/// The difference between the force of an infinite periodic lattice and the plain Newtonian force of its
+/// nearest copy, sampled over the displacements the minimum image convention produces.
+///
+/// The lattice sum is done once with Ewald's method. The 1/r kernel is split into erfc(alpha r) / r, summed
+/// over nearby copies, and erf(alpha r) / r, summed in Fourier space with the in-plane transform
+/// 2 pi erfc(k / 2 alpha) / k. The mean density is removed, as it is on the `pm::PmSolver` mesh.
+#[derive(Debug,Clone)]
+pub struct EwaldTable {
+    pub width: f64,
+    pub height: f64,
+    points: usize,
+    corrections: Vec<Vector2<f64>>
+}
+
+impl Boundary {
+    /// The fixed domain, `None` when open
+    pub fn domain(&self) -> Option<Rectangle> {
+        match self {
+            Boundary::Open => None,
+            Boundary::Periodic(domain) | Boundary::PeriodicX(domain) | Boundary::Reflective(domain) | Boundary::Absorbing(domain) => Some(*domain)
+        }
+    }
+
+    /// How far apart the copies of the domain are along x and y, infinite along an axis that isn't periodic.
+    /// `None` when there are no copies.
+    pub fn period(&self) -> Option<Vector2<f64>> {
+        match self {
+            Boundary::Periodic(domain) => Some(Vector2::new(domain.width(), domain.height())),
+            Boundary::PeriodicX(domain) => Some(Vector2::new(domain.width(), f64::INFINITY)),
+            _ => None
+        }
+    }
+
+    /// Moves the bodies that crossed a periodic edge back in, other boundaries leave them where they are
+    pub fn wrap(&self, bodies: &mut [Body]){
+        if let (Some(domain), Some(period)) = (self.domain(), self.period()) {
+            wrap_lattice(&domain, period, bodies);
+        }
+    }
+
+    /// Brings every body that left the domain back into it, or removes it. Returns how many were removed.
+    pub fn apply(&self, bodies: &mut Vec<Body>) -> usize {
+        match self {
+            Boundary::Open => 0,
+            Boundary::Periodic(_) | Boundary::PeriodicX(_) => {
+                self.wrap(bodies);
+                0
+            }
+            Boundary::Reflective(domain) => {
+                reflect(domain, bodies);
+                0
+            }
+            Boundary::Absorbing(domain) => {
+                let count = bodies.len();
+                bodies.retain(|body| domain.within(body.pos));
+                count - bodies.len()
+            }
+        }
+    }
+}
+
+/// Moves every body back into `boundaries` through the opposite edge
+pub fn wrap_positions(boundaries: &Rectangle, bodies: &mut [Body]){
+    wrap_lattice(boundaries, Vector2::new(boundaries.width(), boundaries.height()), bodies);
+}
+
+/// `wrap_positions` along the axes `period` is finite on
+fn wrap_lattice(boundaries: &Rectangle, period: Vector2<f64>, bodies: &mut [Body]){
+    let wrap = |value: f64, low: f64, size: f64| {
+        if size.is_infinite() {
+            return value;
+        }
+        let wrapped = (value - low).rem_euclid(size);
+        //rem_euclid can round up to `size` for tiny negative offsets
+        if wrapped >= size { low } else { low + wrapped }
+    };
+    let tl = boundaries.tl;
+    for_each_body(bodies, |body: &mut Body| {
+        body.pos = Vector2::new(wrap(body.pos.x, tl.x, period.x), wrap(body.pos.y, tl.y, period.y));
+    });
+}
+
+/// Mirrors every body that crossed an edge back in and turns its velocity around,
+/// as often as needed for bodies that crossed the whole domain in one step
+fn reflect(boundaries: &Rectangle, bodies: &mut [Body]){
+    //position and velocity along one axis, folded into [low, low + size]
+    let fold = |value: f64, velocity: f64, low: f64, size: f64| {
+        let offset = (value - low).rem_euclid(2.0 * size);
+        let bounces = ((value - low) / size).floor() as i64;
+        let velocity = if bounces.rem_euclid(2) == 1 { -velocity } else { velocity };
+        if offset > size { (low + (2.0 * size) - offset, velocity) } else { (low + offset, velocity) }
+    };
+    let (tl, width, height) = (boundaries.tl, boundaries.width(), boundaries.height());
+    for_each_body(bodies, |body: &mut Body| {
+        let (x, vx) = fold(body.pos.x, body.velocity.x, tl.x, width);
+        let (y, vy) = fold(body.pos.y, body.velocity.y, tl.y, height);
+        body.pos = Vector2::new(x, y);
+        body.velocity = Vector2::new(vx, vy);
+    });
+}
+
+/// The copy of `d` closest to the origin on a lattice of `width` by `height`, an infinite side has one copy
+pub fn minimum_image(d: Vector2<f64>, width: f64, height: f64) -> Vector2<f64> {
+    let image = |value: f64, size: f64| if size.is_infinite() { value } else { value - (size * (value / size).round()) };
+    Vector2::new(image(d.x, width), image(d.y, height))
+}
+
+impl EwaldTable {
+    /// Tabulates the correction for a periodic domain of `width` by `height`
+    pub fn new(width: f64, height: f64) -> Self {
+        let points = EWALD_POINTS;
+        let mut corrections: Vec<Vector2<f64>> = Vec::with_capacity(points * points);
+        for j in 0..points{
+            for i in 0..points{
+                let d = Vector2::new(width * ((i as f64 / (points - 1) as f64) - 0.5), height * ((j as f64 / (points - 1) as f64) - 0.5));
+                corrections.push(lattice_correction(d, width, height, 2.0 / width.min(height)));
+            }
+        }
+        Self {
+            width,
+            height,
+            points,
+            corrections
+        }
+    }
+
+    /// The correction at the minimum image displacement `d` (r21), bilinear between the samples.
+    /// Scaled by G m_a m_b it is added to the Newtonian force on body a.
+    pub fn correction(&self, d: Vector2<f64>) -> Vector2<f64> {
+        let last = (self.points - 1) as f64;
+        let u = (((d.x / self.width) + 0.5) * last).clamp(0.0, last);
+        let v = (((d.y / self.height) + 0.5) * last).clamp(0.0, last);
+        let (i, j) = ((u as usize).min(self.points - 2), (v as usize).min(self.points - 2));
+        let (fx, fy) = (u - i as f64, v - j as f64);
+        let at = |i: usize, j: usize| self.corrections[(j * self.points) + i];
+        (at(i, j) * ((1.0 - fx) * (1.0 - fy))) + (at(i + 1, j) * (fx * (1.0 - fy)))
+            + (at(i, j + 1) * ((1.0 - fx) * fy)) + (at(i + 1, j + 1) * (fx * fy))
+    }
+}
+
+/// The force per G m_a m_b on a body at `d` from a lattice of unit masses at the origin, minus the
+/// Newtonian force of the copy at the origin. `alpha` moves work between the two sums and not the result.
+fn lattice_correction(d: Vector2<f64>, width: f64, height: f64, alpha: f64) -> Vector2<f64> {
+    let mut force: Vector2<f64> = Vector2::zero();
+
+    //erfc(alpha r) is down to 1.5e-12 by alpha r = 5
+    let reach_x = (5.0 / (alpha * width)).ceil() as i32 + 1;
+    let reach_y = (5.0 / (alpha * height)).ceil() as i32 + 1;
+    for x in -reach_x..=reach_x{
+        for y in -reach_y..=reach_y{
+            let copy = d + Vector2::new(x as f64 * width, y as f64 * height);
+            let r = copy.magnitude();
+            let short = (erfc(alpha * r) / (r * r)) + (2.0 * alpha / PI.sqrt() * (-(alpha * alpha * r * r)).exp() / r);
+            if x == 0 && y == 0 {
+                //the copy at the origin minus its Newtonian force, which goes to zero with r
+                if r > 0.0 {
+                    force += copy * ((1.0 / (r * r)) - short) / r;
+                }
+            } else {
+                force -= copy * (short / r);
+            }
+        }
+    }
+
+    //erfc(k / 2 alpha) is below 1e-17 by k = 12 alpha
+    let reach_x = (12.0 * alpha * width / (2.0 * PI)).ceil() as i32;
+    let reach_y = (12.0 * alpha * height / (2.0 * PI)).ceil() as i32;
+    let area = width * height;
+    for x in -reach_x..=reach_x{
+        for y in -reach_y..=reach_y{
+            if x == 0 && y == 0 {
+                continue;
+            }
+            let k = Vector2::new(2.0 * PI * x as f64 / width, 2.0 * PI * y as f64 / height);
+            let magnitude = k.magnitude();
+            force -= k * (2.0 * PI / area * erfc(magnitude / (2.0 * alpha)) / magnitude * k.dot(d).sin());
+        }
+    }
+    force
+}
+
+#[cfg(test)]
+mod tests{
+    use cgmath::{InnerSpace, Vector2};
+    use crate::accuracy::{relative_errors, ErrorStats};
+    use crate::bh_runner::BarnesHutRunner;
+    use crate::body::Body;
+    use crate::boundary::{lattice_correction, minimum_image, Boundary, EwaldTable};
+    use crate::pm::PmSolver;
+    use crate::quadtree::{Quadtree, Rectangle};
+    use crate::softening::Softening;
+
+    fn domain() -> Rectangle {
+        Rectangle::new(Vector2::new(0.0,0.0),Vector2::new(100.0,50.0))
+    }
+
+    #[test]
+    fn periodic_wraps(){
+        let mut bodies: Vec<Body> = vec![Body::with_pos(Vector2::new(-1.0, 120.0)), Body::with_pos(Vector2::new(100.0, 25.0))];
+        assert_eq!(Boundary::Periodic(domain()).apply(&mut bodies), 0);
+        assert!((bodies[0].pos - Vector2::new(99.0, 20.0)).magnitude() < 1e-12);
+        assert_eq!(bodies[1].pos, Vector2::new(0.0, 25.0));
+        assert_eq!(minimum_image(Vector2::new(70.0, -30.0), 100.0, 50.0), Vector2::new(-30.0, 20.0));
+    }
+
+    #[test]
+    fn periodic_x_wraps_only_x(){
+        let mut bodies: Vec<Body> = vec![Body::with_pos(Vector2::new(-1.0, 120.0)), Body::with_pos(Vector2::new(250.0, -5.0))];
+        assert_eq!(Boundary::PeriodicX(domain()).apply(&mut bodies), 0);
+        assert!((bodies[0].pos - Vector2::new(99.0, 120.0)).magnitude() < 1e-12);
+        assert_eq!(bodies[1].pos, Vector2::new(50.0, -5.0));
+        assert_eq!(Boundary::PeriodicX(domain()).period(), Some(Vector2::new(100.0, f64::INFINITY)));
+        assert_eq!(minimum_image(Vector2::new(70.0, -30.0), 100.0, f64::INFINITY), Vector2::new(-30.0, -30.0));
+        assert_eq!(Boundary::Reflective(domain()).period(), None);
+    }
+
+    #[test]
+    fn reflective_bounces(){
+        let mut bodies: Vec<Body> = vec![Body::with_pos(Vector2::new(103.0, -2.0)), Body::with_pos(Vector2::new(-130.0, 10.0))];
+        bodies[0].velocity = Vector2::new(4.0, -1.0);
+        bodies[1].velocity = Vector2::new(-5.0, 1.0);
+        assert_eq!(Boundary::Reflective(domain()).apply(&mut bodies), 0);
+        assert_eq!(bodies[0].pos, Vector2::new(97.0, 2.0));
+        assert_eq!(bodies[0].velocity, Vector2::new(-4.0, 1.0));
+        //bounced off the left edge, then the right one
+        assert_eq!(bodies[1].pos, Vector2::new(70.0, 10.0));
+        assert_eq!(bodies[1].velocity, Vector2::new(-5.0, 1.0));
+    }
+
+    #[test]
+    fn absorbing_removes(){
+        let mut bodies: Vec<Body> = vec![Body::with_pos(Vector2::new(10.0, 10.0)), Body::with_pos(Vector2::new(10.0, 60.0))];
+        let kept = bodies[0].id;
+        assert_eq!(Boundary::Absorbing(domain()).apply(&mut bodies), 1);
+        assert_eq!(bodies.len(), 1);
+        assert_eq!(bodies[0].id, kept);
+        assert_eq!(Boundary::Open.domain(), None);
+    }
+
+    #[test]
+    fn ewald_sum_does_not_depend_on_alpha(){
+        let d = Vector2::new(13.0, -31.0);
+        let a = lattice_correction(d, 100.0, 80.0, 0.02);
+        let b = lattice_correction(d, 100.0, 80.0, 0.05);
+        //as far as the 1e-7 of the erfc fit allows
+        assert!((a - b).magnitude() < 1e-6 * a.magnitude(), "{:?} {:?}", a, b);
+    }
+
+    #[test]
+    fn ewald_symmetry(){
+        let table = EwaldTable::new(100.0, 100.0);
+        //no net force from the lattice at the origin
+        assert!(table.correction(Vector2::new(0.0, 0.0)).magnitude() < 1e-12);
+        //halfway between two copies the lattice pulls equally both ways, so the correction cancels Newton
+        let halfway = table.correction(Vector2::new(50.0, 0.0));
+        assert!((halfway - Vector2::new(1.0 / 2500.0, 0.0)).magnitude() < 1e-6 / 2500.0, "{:?}", halfway);
+        //interpolation against the direct sum
+        let d = Vector2::new(12.3, -40.1);
+        let exact = lattice_correction(d, 100.0, 100.0, 0.02);
+        assert!((table.correction(d) - exact).magnitude() < 1e-3 * exact.magnitude());
+    }
+
+    #[test]
+    fn periodic_forces_match_tree_pm(){
+        let domain = Rectangle::new(Vector2::new(0.0,0.0),Vector2::new(1000.0,1000.0));
+        let mut runner: BarnesHutRunner = BarnesHutRunner::from_theta(0.3);
+        runner.params.softening = Softening::None { min_distance: 0.0 };
+        runner.set_boundary(Boundary::Periodic(domain));
+        let mut rng = fastrand::Rng::with_seed(200);
+        let mut bodies: Vec<Body> = (0..200).map(|_| Body::with_mass_and_pos(10.0, Vector2::new(rng.f64(), rng.f64()) * 1000.0)).collect();
+        let mut mesh_bodies = bodies.clone();
+        runner.evaluate_forces(&mut Quadtree::new(domain,1), &mut bodies);
+        let mut solver = PmSolver::tree_pm(128, 0.3);
+        solver.params = runner.params;
+        solver.evaluate_forces(&mut Quadtree::new(domain,1), &mut mesh_bodies);
+        let tree: Vec<Vector2<f64>> = bodies.iter().map(|body| body.force).collect();
+        let mesh: Vec<Vector2<f64>> = mesh_bodies.iter().map(|body| body.force).collect();
+        let stats = ErrorStats::from_errors(&relative_errors(&tree, &mesh));
+        assert!(stats.median < 1e-2, "{}", stats);
+    }
+
+    #[test]
+    fn periodic_pulls_across_the_edge(){
+        let domain = Rectangle::new(Vector2::new(0.0,0.0),Vector2::new(1000.0,1000.0));
+        let mut runner: BarnesHutRunner = BarnesHutRunner::from_theta(0.5);
+        runner.params.softening = Softening::None { min_distance: 0.0 };
+        runner.set_boundary(Boundary::Periodic(domain));
+        let mut qt: Quadtree = Quadtree::new(Rectangle::new(Vector2::new(0.0,0.0),Vector2::new(1.0,1.0)),1);
+        //the second body starts outside and is wrapped to x = 980, 40 away through the edge
+        let mut bodies: Vec<Body> = vec![Body::with_pos(Vector2::new(20.0, 500.0)), Body::with_pos(Vector2::new(-20.0, 500.0))];
+        runner.evaluate_forces(&mut qt, &mut bodies);
+        assert_eq!(bodies[1].pos, Vector2::new(980.0, 500.0));
+        assert_eq!(qt.boundaries, domain);
+        let newton = 1.0 / (40.0 * 40.0);
+        assert!(bodies[0].force.x < 0.0 && (bodies[0].force.x + newton).abs() < 0.01 * newton, "{:?}", bodies[0].force);
+        assert!((bodies[0].force + bodies[1].force).magnitude() < 1e-12);
+    }
+
+    #[test]
+    fn closed_boundaries(){
+        let domain = Rectangle::new(Vector2::new(0.0,0.0),Vector2::new(100.0,100.0));
+        let mut qt: Quadtree = Quadtree::new(Rectangle::new(Vector2::new(0.0,0.0),Vector2::new(1.0,1.0)),1);
+        let start: Vec<Body> = vec![Body::with_pos(Vector2::new(99.9, 50.0)), Body::with_pos(Vector2::new(10.0, 50.0))];
+        let mut reflective: BarnesHutRunner = BarnesHutRunner::new();
+        reflective.set_boundary(Boundary::Reflective(domain));
+        let mut bodies = start.clone();
+        bodies[0].velocity = Vector2::new(200.0, 0.0);
+        reflective.iterate(&mut qt, &mut bodies);
+        assert_eq!(bodies.len(), 2);
+        assert!(domain.within(bodies[0].pos) && bodies[0].velocity.x < 0.0);
+        assert_eq!(qt.boundaries, domain);
+
+        let mut absorbing: BarnesHutRunner = BarnesHutRunner::new();
+        absorbing.set_boundary(Boundary::Absorbing(domain));
+        assert_eq!(absorbing.boundary(), Boundary::Absorbing(domain));
+        let mut bodies = start.clone();
+        bodies[0].velocity = Vector2::new(200.0, 0.0);
+        absorbing.iterate(&mut qt, &mut bodies);
+        assert_eq!(bodies.len(), 1);
+        assert_eq!(bodies[0].id, start[1].id);
+    }
+}
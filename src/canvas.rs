@@ -1,5 +1,13 @@
 use cgmath::num_traits::{Saturating, SaturatingAdd};
+use cgmath::Vector3;
 use hsv::hsv_to_rgb;
+use crate::body::{Body, BodyKind};
+
+/// Colour of tracer pixels, white so they stand out from the blue to red heat of massive bodies
+pub const TRACER_HSV: (f64,f64,f64) = (0.0,0.0,1.0);
+
+/// Colours of point vortices turning counter-clockwise and clockwise, see `Canvas::draw_vortices`
+pub const VORTEX_HSV: [(f64,f64,f64); 2] = [(0.0,1.0,1.0), (240.0,1.0,1.0)];
 
 pub struct Canvas{
     pub width: u32,
@@ -11,6 +19,37 @@ pub struct Canvas{
 
 }
 
+/// An orthographic camera for drawing 3D positions on a `Canvas`.
+/// The view turns by `yaw` around the z axis and then tilts by `pitch` around the screen x axis,
+/// `center` lands in the middle of the canvas and one unit of length covers `scale` pixels.
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub struct Projection {
+    pub center: Vector3<f64>,
+    pub yaw: f64,
+    pub pitch: f64,
+    pub scale: f64
+}
+
+impl Projection {
+    pub fn new(center: Vector3<f64>, yaw: f64, pitch: f64, scale: f64) -> Self {
+        Self {
+            center,
+            yaw,
+            pitch,
+            scale
+        }
+    }
+
+    /// The pixel `pos` lands on for a canvas of `width` by `height`
+    pub fn project(&self, pos: Vector3<f64>, width: u32, height: u32) -> (i32, i32) {
+        let d = pos - self.center;
+        let x = d.x * self.yaw.cos() - d.y * self.yaw.sin();
+        let y = d.x * self.yaw.sin() + d.y * self.yaw.cos();
+        let y = y * self.pitch.cos() - d.z * self.pitch.sin();
+        (((x * self.scale) + (width as f64 / 2.0)).round() as i32, ((y * self.scale) + (height as f64 / 2.0)).round() as i32)
+    }
+}
+
 impl Canvas{
     pub fn new(width: u32, height: u32, buffer_default_color: (u8, u8, u8, u8)) -> Self {
         Self {
@@ -133,6 +172,37 @@ impl Canvas{
     }
 
 
+    /// Draws a body at its rounded position, a massive body heats its pixel up and a tracer paints it `TRACER_HSV`
+    pub fn draw_body(&mut self, body: &Body){
+        let x_pos: i32 = body.pos.x.round() as i32;
+        let y_pos: i32 = body.pos.y.round() as i32;
+        match body.kind {
+            BodyKind::Massive | BodyKind::Gas => self.increment_huemap(x_pos, y_pos, (240.0,1.0,1.0), -1.0),
+            BodyKind::Tracer => self.set_hue_safe(x_pos, y_pos, &TRACER_HSV)
+        }
+    }
+
+    /// Draws every body with `draw_body`, tracers last so the heat of massive bodies doesn't cover them
+    pub fn draw_bodies(&mut self, bodies: &[Body]){
+        for body in bodies.iter().filter(|body| !body.is_tracer()){
+            self.draw_body(body);
+        }
+        for body in bodies.iter().filter(|body| body.is_tracer()){
+            self.draw_body(body);
+        }
+    }
+
+    /// Draws point vortices, red for positive circulation and blue for negative, with tracers on top
+    pub fn draw_vortices(&mut self, bodies: &[Body]){
+        for body in bodies.iter().filter(|body| !body.is_tracer()){
+            let hsv = if body.circulation >= 0.0 { VORTEX_HSV[0] } else { VORTEX_HSV[1] };
+            self.set_hue_safe(body.pos.x.round() as i32, body.pos.y.round() as i32, &hsv);
+        }
+        for body in bodies.iter().filter(|body| body.is_tracer()){
+            self.draw_body(body);
+        }
+    }
+
     ///Draws a square using the top left x and y position of the square, and the squares width, IN HSV
     ///Unsafe
 
@@ -207,7 +277,9 @@ impl Canvas{
 
 #[cfg(test)]
 mod tests{
-    use crate::canvas::Canvas;
+    use cgmath::{Vector2, Vector3};
+    use crate::body::Body;
+    use crate::canvas::{Canvas, Projection, TRACER_HSV, VORTEX_HSV};
 
     #[test]
     fn test_indexing(){
@@ -219,6 +291,40 @@ mod tests{
         assert_eq!(canvas.get_index(1000,1000), (canvas.height*canvas.width + canvas.width) as usize);
     }
 
+    #[test]
+    fn projection(){
+        let flat = Projection::new(Vector3::new(10.0,10.0,10.0), 0.0, 0.0, 2.0);
+        assert_eq!(flat.project(Vector3::new(10.0,10.0,-50.0), 100, 80), (50, 40));
+        assert_eq!(flat.project(Vector3::new(12.0,7.0,0.0), 100, 80), (54, 34));
+        //looking along the y axis, z goes up the screen
+        let side = Projection::new(Vector3::new(0.0,0.0,0.0), 0.0, std::f64::consts::FRAC_PI_2, 1.0);
+        assert_eq!(side.project(Vector3::new(0.0,30.0,10.0), 100, 100), (50, 40));
+        let turned = Projection::new(Vector3::new(0.0,0.0,0.0), std::f64::consts::FRAC_PI_2, 0.0, 1.0);
+        assert_eq!(turned.project(Vector3::new(10.0,0.0,0.0), 100, 100), (50, 60));
+    }
+
+    #[test]
+    fn tracers_drawn_on_top(){
+        let mut canvas: Canvas = Canvas::new(100,100, (0,0,0,0));
+        let bodies: Vec<Body> = vec![Body::tracer(Vector2::new(10.2,20.0)), Body::with_pos(Vector2::new(10.0,20.0)), Body::with_pos(Vector2::new(30.0,40.0))];
+        canvas.draw_bodies(&bodies);
+        assert_eq!(*canvas.get_hue(10,20), TRACER_HSV);
+        assert_eq!(*canvas.get_hue(30,40), (239.0,1.0,1.0));
+        //off the canvas is skipped
+        canvas.draw_body(&Body::tracer(Vector2::new(-5.0,20.0)));
+    }
+
+    #[test]
+    fn vortices_coloured_by_sign(){
+        let mut canvas: Canvas = Canvas::new(100,100, (0,0,0,0));
+        let bodies: Vec<Body> = vec![Body::with_pos(Vector2::new(5.0,5.0)).with_circulation(1.0), Body::with_pos(Vector2::new(6.0,5.0)).with_circulation(-1.0), Body::tracer(Vector2::new(5.0,5.0))];
+        canvas.draw_vortices(&bodies[..2]);
+        assert_eq!(*canvas.get_hue(5,5), VORTEX_HSV[0]);
+        assert_eq!(*canvas.get_hue(6,5), VORTEX_HSV[1]);
+        canvas.draw_vortices(&bodies);
+        assert_eq!(*canvas.get_hue(5,5), TRACER_HSV);
+    }
+
     #[test]
     fn test_canvas_size(){
         let canvas: Canvas = Canvas::new(5000,5000, (0,0,0,0));
@@ -0,0 +1,271 @@
+use cgmath::{InnerSpace, MetricSpace};
+use crate::body::Body;
+use crate::quadtree::Quadtree;
+
+/// What happens to two bodies that overlap
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub enum CollisionResponse {
+    /// The pair becomes one body, see `Body::merge`
+    Merge,
+    /// The pair bounces off along the line between the centres, leaving with `restitution` times the speed
+    /// it approached with. 1 is elastic, 0 leaves them moving together.
+    Bounce { restitution: f64 }
+}
+
+/// The optional collision stage run after every step, see `BarnesHutRunner::collisions`
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub struct Collisions {
+    /// Mass per unit volume, gives every body its radius through `Body::radius`
+    pub density: f64,
+    pub response: CollisionResponse
+}
+
+/// Two bodies became one, `absorbed` is gone and `survivor` kept its id
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub struct MergeEvent {
+    pub survivor: u64,
+    pub absorbed: u64,
+    /// Simulated time at the end of the step the merge happened in
+    pub time: f64
+}
+
+impl Collisions {
+    pub fn new(density: f64, response: CollisionResponse) -> Self {
+        Self {
+            density,
+            response
+        }
+    }
+
+    /// Every pair of indices `(a, b)` with `a < b` whose spheres overlap, sorted. Tracers never collide.
+    /// `quadtree` has to be built from `bodies`.
+    pub fn overlapping_pairs(&self, quadtree: &Quadtree, bodies: &[Body]) -> Vec<(usize, usize)> {
+        let radii: Vec<f64> = bodies.iter().map(|body| body.radius(self.density)).collect();
+        let largest = radii.iter().copied().fold(0.0, f64::max);
+        let mut found: Vec<usize> = Vec::new();
+        let mut pairs: Vec<(usize, usize)> = Vec::new();
+        for (a, body) in bodies.iter().enumerate().filter(|(_, body)| !body.is_tracer()){
+            found.clear();
+            quadtree.query_radius(bodies, body.pos, radii[a] + largest, &mut found);
+            found.sort_unstable();
+            pairs.extend(found.iter()
+                .filter(|b| **b > a && !bodies[**b].is_tracer() && body.pos.distance(bodies[**b].pos) < radii[a] + radii[**b])
+                .map(|b| (a, *b)));
+        }
+        pairs
+    }
+
+    /// Resolves every overlap found in `quadtree`, which has to be built from `bodies`.
+    /// Merged bodies are removed from `bodies` and reported with `time`, a group of several overlapping
+    /// bodies ends up as one body and one event per body it absorbed.
+    pub fn resolve(&self, quadtree: &Quadtree, bodies: &mut Vec<Body>, time: f64) -> Vec<MergeEvent> {
+        let pairs = self.overlapping_pairs(quadtree, bodies);
+        match self.response {
+            CollisionResponse::Merge => merge_pairs(&pairs, bodies, time),
+            CollisionResponse::Bounce { restitution } => {
+                for (a, b) in pairs{
+                    self.bounce(bodies, a, b, restitution);
+                }
+                Vec::new()
+            }
+        }
+    }
+
+    /// Inelastic bounce of `bodies[a]` and `bodies[b]`, then pushes them apart until they just touch.
+    /// Momentum and the center of mass are kept. A body of no mass takes the whole bounce, two of them share it evenly.
+    fn bounce(&self, bodies: &mut [Body], a: usize, b: usize, restitution: f64){
+        let (first, second) = (bodies[a], bodies[b]);
+        let d = second.pos - first.pos;
+        let distance = d.magnitude();
+        //bodies on top of each other have no direction to bounce along
+        if distance == 0.0 {
+            return;
+        }
+        let normal = d / distance;
+        //the part of the bounce each body takes, the lighter one takes more
+        let total = first.mass + second.mass;
+        let (share_a, share_b) = if total == 0.0 { (0.5, 0.5) } else { (second.mass / total, first.mass / total) };
+        let approach = (second.velocity - first.velocity).dot(normal);
+        if approach < 0.0 {
+            let change = -(1.0 + restitution) * approach;
+            bodies[a].velocity -= normal * (change * share_a);
+            bodies[b].velocity += normal * (change * share_b);
+        }
+        let overlap = first.radius(self.density) + second.radius(self.density) - distance;
+        if overlap > 0.0 {
+            bodies[a].pos -= normal * (overlap * share_a);
+            bodies[b].pos += normal * (overlap * share_b);
+        }
+    }
+}
+
+/// The slot `index` ended up in after the merges so far
+fn merged_into(into: &[usize], mut index: usize) -> usize {
+    while into[index] != index {
+        index = into[index];
+    }
+    index
+}
+
+fn merge_pairs(pairs: &[(usize, usize)], bodies: &mut Vec<Body>, time: f64) -> Vec<MergeEvent> {
+    //a body that has not been merged points at itself
+    let mut into: Vec<usize> = (0..bodies.len()).collect();
+    let mut events: Vec<MergeEvent> = Vec::new();
+    for (a, b) in pairs{
+        let (a, b) = (merged_into(&into, *a), merged_into(&into, *b));
+        if a == b {
+            continue;
+        }
+        let merged = bodies[a].merge(&bodies[b]);
+        let absorbed = if merged.id == bodies[a].id { bodies[b].id } else { bodies[a].id };
+        events.push(MergeEvent {
+            survivor: merged.id,
+            absorbed,
+            time
+        });
+        //the merged body takes the first slot, the second one is dropped below
+        bodies[a] = merged;
+        into[b] = a;
+    }
+    let mut index = 0;
+    bodies.retain(|_| {
+        let keep = into[index] == index;
+        index += 1;
+        keep
+    });
+    events
+}
+
+#[cfg(test)]
+mod tests{
+    use std::f64::consts::PI;
+    use cgmath::{InnerSpace, Vector2};
+    use crate::bh_runner::BarnesHutRunner;
+    use crate::body::Body;
+    use crate::collision::{CollisionResponse, Collisions, MergeEvent};
+    use crate::quadtree::{Quadtree, Rectangle};
+
+    //unit mass gets unit radius
+    const DENSITY: f64 = 3.0 / (4.0 * PI);
+
+    fn resolve(collisions: &Collisions, bodies: &mut Vec<Body>) -> Vec<MergeEvent> {
+        let mut qt: Quadtree = Quadtree::new(Rectangle::new(Vector2::new(0.0,0.0),Vector2::new(1.0,1.0)),1);
+        qt.resize_to_fit(bodies);
+        qt.build(bodies);
+        collisions.resolve(&qt, bodies, 2.5)
+    }
+
+    fn momentum(bodies: &[Body]) -> Vector2<f64> {
+        bodies.iter().map(|body| body.velocity * body.mass).sum()
+    }
+
+    #[test]
+    fn overlapping_pair_merges(){
+        let collisions = Collisions::new(DENSITY, CollisionResponse::Merge);
+        let mut heavy = Body::with_mass_and_pos(3.0, Vector2::new(10.0, 10.0));
+        heavy.velocity = Vector2::new(1.0, 0.0);
+        let mut light = Body::with_mass_and_pos(1.0, Vector2::new(12.0, 10.0));
+        light.velocity = Vector2::new(-1.0, 2.0);
+        let far = Body::with_mass_and_pos(1.0, Vector2::new(30.0, 30.0));
+        let mut bodies: Vec<Body> = vec![light, far, heavy];
+        let before = momentum(&bodies);
+        let events = resolve(&collisions, &mut bodies);
+        assert_eq!(events, vec![MergeEvent { survivor: heavy.id, absorbed: light.id, time: 2.5 }]);
+        assert_eq!(bodies.len(), 2);
+        assert_eq!(bodies[1].id, far.id);
+        assert_eq!(bodies[0].id, heavy.id);
+        assert_eq!(bodies[0].mass, 4.0);
+        assert_eq!(bodies[0].pos, Vector2::new(10.5, 10.0));
+        assert!((momentum(&bodies) - before).magnitude() < 1e-12);
+    }
+
+    #[test]
+    fn chain_merges_into_one(){
+        let collisions = Collisions::new(DENSITY, CollisionResponse::Merge);
+        //each body only touches its neighbours
+        let mut bodies: Vec<Body> = (0..3).map(|i| Body::with_mass_and_pos(1.0, Vector2::new(10.0 + (1.9 * i as f64), 10.0))).collect();
+        let ids: Vec<u64> = bodies.iter().map(|body| body.id).collect();
+        let events = resolve(&collisions, &mut bodies);
+        assert_eq!(bodies.len(), 1);
+        assert_eq!(bodies[0].mass, 3.0);
+        assert_eq!(bodies[0].id, ids[0]);
+        assert_eq!(events.len(), 2);
+        let mut absorbed: Vec<u64> = events.iter().map(|event| event.absorbed).collect();
+        absorbed.sort();
+        assert_eq!(absorbed, vec![ids[1], ids[2]]);
+    }
+
+    #[test]
+    fn bounces_conserve_momentum(){
+        for restitution in [1.0, 0.5, 0.0]{
+            let collisions = Collisions::new(DENSITY, CollisionResponse::Bounce { restitution });
+            let mut first = Body::with_mass_and_pos(1.0, Vector2::new(10.0, 10.0));
+            first.velocity = Vector2::new(1.0, 0.5);
+            let mut second = Body::with_mass_and_pos(1.0, Vector2::new(11.5, 10.0));
+            second.velocity = Vector2::new(-1.0, 0.0);
+            let mut bodies: Vec<Body> = vec![first, second];
+            let before = momentum(&bodies);
+            assert!(resolve(&collisions, &mut bodies).is_empty());
+            assert!((momentum(&bodies) - before).magnitude() < 1e-12);
+            //separating at `restitution` times the approach speed of 2, the sideways motion is kept
+            assert!((bodies[1].velocity.x - bodies[0].velocity.x - (2.0 * restitution)).abs() < 1e-12);
+            assert_eq!(bodies[0].velocity.y, 0.5);
+            //pushed apart to touching, around the same center
+            assert!(((bodies[1].pos - bodies[0].pos).magnitude() - 2.0).abs() < 1e-12);
+            assert!((bodies[0].pos + bodies[1].pos - Vector2::new(21.5, 20.0)).magnitude() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn massless_bodies_bounce(){
+        let collisions = Collisions::new(DENSITY, CollisionResponse::Bounce { restitution: 1.0 });
+        let mut heavy = Body::with_mass_and_pos(1.0, Vector2::new(10.0, 10.0));
+        heavy.velocity = Vector2::new(0.5, 0.0);
+        let mut massless = Body::with_mass_and_pos(0.0, Vector2::new(10.5, 10.0));
+        massless.velocity = Vector2::new(-1.0, 0.0);
+        //the massless body bounces off the heavy one, which doesn't notice
+        let mut bodies: Vec<Body> = vec![heavy, massless];
+        collisions.bounce(&mut bodies, 0, 1, 1.0);
+        assert_eq!(bodies[0].velocity, heavy.velocity);
+        assert_eq!(bodies[0].pos, heavy.pos);
+        assert!((bodies[1].velocity - Vector2::new(2.0, 0.0)).magnitude() < 1e-12, "{:?}", bodies[1].velocity);
+        assert!((bodies[1].pos - Vector2::new(11.0, 10.0)).magnitude() < 1e-12, "{:?}", bodies[1].pos);
+        //two of them split it evenly
+        let mut bodies: Vec<Body> = vec![Body::with_mass_and_pos(0.0, Vector2::new(0.0, 0.0)), Body::with_mass_and_pos(0.0, Vector2::new(0.5, 0.0))];
+        bodies[0].velocity = Vector2::new(1.0, 0.0);
+        bodies[1].velocity = Vector2::new(-1.0, 0.0);
+        collisions.bounce(&mut bodies, 0, 1, 1.0);
+        assert_eq!(bodies[0].velocity, Vector2::new(-1.0, 0.0));
+        assert_eq!(bodies[1].velocity, Vector2::new(1.0, 0.0));
+        assert!(bodies.iter().all(|body| body.pos.x.is_finite() && body.pos.y.is_finite()));
+    }
+
+    #[test]
+    fn separate_bodies_are_left_alone(){
+        let collisions = Collisions::new(DENSITY, CollisionResponse::Bounce { restitution: 1.0 });
+        let mut bodies: Vec<Body> = vec![Body::with_pos(Vector2::new(0.0, 0.0)), Body::with_pos(Vector2::new(2.1, 0.0))];
+        let before = bodies.clone();
+        resolve(&collisions, &mut bodies);
+        assert_eq!(bodies[1].pos, before[1].pos);
+        assert_eq!(bodies[0].velocity, before[0].velocity);
+    }
+
+    #[test]
+    fn collisions_merge_after_update(){
+        let mut runner: BarnesHutRunner = BarnesHutRunner::new();
+        runner.collisions = Some(Collisions::new(3.0 / (4.0 * std::f64::consts::PI), CollisionResponse::Merge));
+        let mut qt: Quadtree = Quadtree::new(Rectangle::new(Vector2::new(0.0,0.0),Vector2::new(1.0,1.0)),1);
+        let mut bodies: Vec<Body> = vec![Body::with_pos(Vector2::new(10.0, 10.0)), Body::with_pos(Vector2::new(13.0, 10.0)), Body::with_pos(Vector2::new(50.0, 50.0))];
+        bodies[0].velocity = Vector2::new(1500.0, 0.0);
+        let ids: Vec<u64> = bodies.iter().map(|body| body.id).collect();
+        runner.iterate(&mut qt, &mut bodies);
+        assert_eq!(bodies.len(), 2);
+        assert_eq!(runner.merges(), &[MergeEvent { survivor: ids[0], absorbed: ids[1], time: runner.time }]);
+        assert_eq!(bodies[0].mass, 2.0);
+        assert!((bodies[0].velocity.x * 2.0 - 1500.0).abs() < 1.0);
+        //the tree was rebuilt around what is left
+        assert_eq!(qt.root().total_mass, 3.0);
+        runner.iterate(&mut qt, &mut bodies);
+        assert!(runner.merges().is_empty());
+    }
+}
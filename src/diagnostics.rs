@@ -0,0 +1,272 @@
+use cgmath::{InnerSpace, Vector2, Zero};
+use crate::bh_runner::BarnesHutRunner;
+use crate::body::{Body, Body3};
+use crate::external::external_potential_energy;
+use crate::gravity;
+use crate::gravity::PhysicsParams;
+use crate::octree::Octree;
+use crate::quadtree::Quadtree;
+use crate::simulation::Simulation;
+
+/// Conserved quantities of a system at one point in time.
+/// Angular momentum is the z component, taken about the origin.
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub struct Diagnostics {
+    pub time: f64,
+    pub kinetic_energy: f64,
+    pub potential_energy: f64,
+    /// Internal energy of the gas bodies, see `sph`
+    pub thermal_energy: f64,
+    pub momentum: Vector2<f64>,
+    pub angular_momentum: f64
+}
+
+/// A time series of `Diagnostics`, one record per call to `record_*`
+#[derive(Debug,Clone)]
+pub struct DiagnosticsLog {
+    pub records: Vec<Diagnostics>,
+    /// Up to this many bodies the potential is summed exactly, above it the tree is used
+    pub exact_limit: usize
+}
+
+pub fn kinetic_energy(bodies: &[Body]) -> f64 {
+    bodies.iter().map(|body| 0.5 * body.mass * body.velocity.magnitude2()).sum()
+}
+
+pub fn thermal_energy(bodies: &[Body]) -> f64 {
+    bodies.iter().filter(|body| body.is_gas()).map(|body| body.mass * body.gas.internal_energy).sum()
+}
+
+pub fn momentum(bodies: &[Body]) -> Vector2<f64> {
+    bodies.iter().fold(Vector2::zero(), |total, body| total + body.velocity * body.mass)
+}
+
+pub fn angular_momentum(bodies: &[Body]) -> f64 {
+    bodies.iter().map(|body| body.mass * ((body.pos.x * body.velocity.y) - (body.pos.y * body.velocity.x))).sum()
+}
+
+/// Direct O(n^2) sum over every pair
+pub fn potential_energy_exact(bodies: &[Body], params: &PhysicsParams) -> f64 {
+    let mut potential: f64 = 0.0;
+    for i in 0..bodies.len() {
+        for j in i+1..bodies.len() {
+            potential += gravity::potential_energy(&bodies[i], &bodies[j], params);
+        }
+    }
+    potential
+}
+
+/// Rebuilds `quadtree` around `bodies` and sums the tree potential of every body, halved so pairs count once
+pub fn potential_energy_tree(runner: &BarnesHutRunner, quadtree: &mut Quadtree, bodies: &[Body]) -> f64 {
+    runner.resize(quadtree, bodies);
+    runner.create_tree(quadtree, bodies);
+    0.5 * bodies.iter().map(|body| body.mass * runner.barnes_hut_potential(quadtree, bodies, body)).sum::<f64>()
+}
+
+/// Direct O(n^2) sum over every pair of 3D bodies
+pub fn potential_energy_exact3(bodies: &[Body3], params: &PhysicsParams) -> f64 {
+    let mut potential: f64 = 0.0;
+    for i in 0..bodies.len() {
+        for j in i+1..bodies.len() {
+            potential += bodies[i].mass * gravity::potential_mass_center3(&bodies[i], bodies[j].pos, bodies[j].mass, params);
+        }
+    }
+    potential
+}
+
+/// `potential_energy_tree` for 3D bodies, rebuilding `octree` around them
+pub fn potential_energy_tree3(runner: &BarnesHutRunner, octree: &mut Octree, bodies: &[Body3]) -> f64 {
+    runner.resize3(octree, bodies);
+    runner.create_octree(octree, bodies);
+    0.5 * bodies.iter().map(|body| body.mass * runner.barnes_hut_potential3(octree, bodies, body)).sum::<f64>()
+}
+
+impl Diagnostics {
+    pub fn measure(bodies: &[Body], potential_energy: f64, time: f64) -> Self {
+        Self {
+            time,
+            kinetic_energy: kinetic_energy(bodies),
+            potential_energy,
+            thermal_energy: thermal_energy(bodies),
+            momentum: momentum(bodies),
+            angular_momentum: angular_momentum(bodies)
+        }
+    }
+
+    pub fn total_energy(&self) -> f64 {
+        self.kinetic_energy + self.potential_energy + self.thermal_energy
+    }
+}
+
+impl std::fmt::Display for Diagnostics{
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result{
+        write!(f,"t: {:.4} E: {:.8e} (K: {:.8e} U: {:.8e} Th: {:.8e}) P: ({:.3e} {:.3e}) L: {:.8e}",
+               self.time,self.total_energy(),self.kinetic_energy,self.potential_energy,self.thermal_energy,self.momentum.x,self.momentum.y,self.angular_momentum)
+    }
+}
+
+impl DiagnosticsLog {
+    pub fn new() -> Self {
+        Self {
+            records: Vec::new(),
+            exact_limit: 2000
+        }
+    }
+
+    pub fn record_simulation(&mut self, simulation: &Simulation) -> Diagnostics {
+        let potential = potential_energy_exact(&simulation.bodies, &simulation.params)
+            + external_potential_energy(&simulation.external, &simulation.bodies, simulation.time, simulation.params.g);
+        self.push(Diagnostics::measure(&simulation.bodies, potential, simulation.time))
+    }
+
+    /// Measures a Barnes-Hut run, `quadtree` is rebuilt around the current positions when the tree is needed.
+    /// The potential energy includes the runner's external fields, the same goes for `record_simulation`.
+    pub fn record_runner(&mut self, runner: &BarnesHutRunner, quadtree: &mut Quadtree, bodies: &[Body]) -> Diagnostics {
+        let potential = if bodies.len() <= self.exact_limit {
+            potential_energy_exact(bodies, &runner.params)
+        } else {
+            potential_energy_tree(runner, quadtree, bodies)
+        } + external_potential_energy(&runner.external, bodies, runner.time, runner.params.g);
+        self.push(Diagnostics::measure(bodies, potential, runner.time))
+    }
+
+    fn push(&mut self, diagnostics: Diagnostics) -> Diagnostics {
+        self.records.push(diagnostics);
+        diagnostics
+    }
+
+    /// (E - E0) / |E0| for the latest record
+    pub fn relative_energy_drift(&self) -> f64 {
+        match (self.records.first(), self.records.last()) {
+            (Some(first), Some(last)) => (last.total_energy() - first.total_energy()) / first.total_energy().abs(),
+            _ => 0.0
+        }
+    }
+
+    /// The largest |E - E0| / |E0| seen so far
+    pub fn max_relative_energy_drift(&self) -> f64 {
+        match self.records.first() {
+            Some(first) => {
+                let initial = first.total_energy();
+                self.records.iter().map(|record| ((record.total_energy() - initial) / initial.abs()).abs()).fold(0.0, f64::max)
+            }
+            None => 0.0
+        }
+    }
+}
+
+impl Default for DiagnosticsLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use cgmath::{Vector2, Vector3};
+    use crate::bh_runner::BarnesHutRunner;
+    use crate::body::{Body, Body3};
+    use crate::diagnostics::{potential_energy_exact, potential_energy_exact3, potential_energy_tree, potential_energy_tree3, Diagnostics, DiagnosticsLog};
+    use crate::external::{ExternalField, Plummer};
+    use crate::gravity::PhysicsParams;
+    use crate::integrator::{ExplicitEuler, Integrator, Leapfrog};
+    use crate::octree::{Cuboid, Octree};
+    use crate::quadtree::{Quadtree, Rectangle};
+    use crate::simulation::Simulation;
+    use crate::softening::Softening;
+
+    fn binary(integrator: Box<dyn Integrator>) -> Simulation {
+        let mut simulation = Simulation::with_integrator(integrator);
+        simulation.params = PhysicsParams::new(1.0, 0.001, Softening::None { min_distance: 0.0 });
+        let speed = 0.5f64.sqrt();
+        simulation.bodies.push(Body::with_mass_and_pos(1.0, Vector2::new(0.5, 0.0)));
+        simulation.bodies.push(Body::with_mass_and_pos(1.0, Vector2::new(-0.5, 0.0)));
+        simulation.bodies[0].velocity = Vector2::new(0.0, speed);
+        simulation.bodies[1].velocity = Vector2::new(0.0, -speed);
+        simulation
+    }
+
+    #[test]
+    fn circular_binary(){
+        let simulation = binary(Box::new(Leapfrog));
+        let potential = potential_energy_exact(&simulation.bodies, &simulation.params);
+        let diagnostics = Diagnostics::measure(&simulation.bodies, potential, 0.0);
+        assert!((diagnostics.kinetic_energy - 0.5).abs() < 1e-12);
+        assert!((diagnostics.potential_energy + 1.0).abs() < 1e-12);
+        assert!((diagnostics.angular_momentum - 0.5f64.sqrt()).abs() < 1e-12);
+        assert_eq!(diagnostics.momentum, Vector2::new(0.0, 0.0));
+    }
+
+    #[test]
+    fn tree_potential_matches_exact(){
+        let mut bodies: Vec<Body> = Vec::new();
+        for _ in 0..200{
+            bodies.push(Body::random(100.0, 500.0));
+        }
+        let mut qt: Quadtree = Quadtree::new(Rectangle::new(Vector2::new(0.0,0.0),Vector2::new(1000.0,1000.0)),1);
+        let runner: BarnesHutRunner = BarnesHutRunner::from_theta(0.0);
+        let exact = potential_energy_exact(&bodies, &runner.params);
+        let tree = potential_energy_tree(&runner, &mut qt, &bodies);
+        assert!(((tree - exact) / exact).abs() < 1e-10);
+
+        let runner: BarnesHutRunner = BarnesHutRunner::from_theta(0.5);
+        let tree = potential_energy_tree(&runner, &mut qt, &bodies);
+        assert!(((tree - exact) / exact).abs() < 1e-2);
+    }
+
+    #[test]
+    fn tree_potential_matches_exact3(){
+        let mut bodies: Vec<Body3> = Vec::new();
+        for _ in 0..200{
+            bodies.push(Body3::random(100.0, 500.0));
+        }
+        let mut octree: Octree = Octree::new(Cuboid::new(Vector3::new(0.0,0.0,0.0),Vector3::new(1000.0,1000.0,1000.0)),1);
+        let runner: BarnesHutRunner = BarnesHutRunner::from_theta(0.0);
+        let exact = potential_energy_exact3(&bodies, &runner.params);
+        assert!(exact < 0.0);
+        let tree = potential_energy_tree3(&runner, &mut octree, &bodies);
+        assert!(((tree - exact) / exact).abs() < 1e-10);
+
+        let runner: BarnesHutRunner = BarnesHutRunner::from_theta(0.5);
+        let tree = potential_energy_tree3(&runner, &mut octree, &bodies);
+        assert!(((tree - exact) / exact).abs() < 1e-2);
+    }
+
+    #[test]
+    fn energy_drift_tracks_integrator(){
+        let mut drifts: Vec<f64> = Vec::new();
+        for integrator in [Box::new(ExplicitEuler) as Box<dyn Integrator>, Box::new(Leapfrog)]{
+            let mut simulation = binary(integrator);
+            let mut log = DiagnosticsLog::new();
+            log.record_simulation(&simulation);
+            for _ in 0..5000{
+                simulation.update();
+                log.record_simulation(&simulation);
+            }
+            assert_eq!(log.records.len(), 5001);
+            assert!((log.records.last().unwrap().time - 5.0).abs() < 1e-9);
+            assert!(log.records.last().unwrap().momentum.x.abs() < 1e-12);
+            drifts.push(log.max_relative_energy_drift());
+        }
+        assert!(drifts[0] > 1e-3);
+        assert!(drifts[1] < 1e-6);
+    }
+
+    #[test]
+    fn external_potential_is_part_of_the_energy(){
+        let halo = Plummer { center: Vector2::new(0.0, 0.0), mass: 3.0, scale: 1.0 };
+        let mut simulation = binary(Box::new(Leapfrog));
+        simulation.external.push(Box::new(halo));
+        let mut log = DiagnosticsLog::new();
+        let first = log.record_simulation(&simulation);
+        let own = potential_energy_exact(&simulation.bodies, &simulation.params);
+        assert!((first.potential_energy - own - (2.0 * halo.potential(Vector2::new(0.5, 0.0), 0.0, 1.0))).abs() < 1e-12);
+        //the halo pulls the binary off its circular orbit, the energy is still kept
+        for _ in 0..5000{
+            simulation.update();
+            log.record_simulation(&simulation);
+        }
+        assert!((log.records.last().unwrap().kinetic_energy - 0.5).abs() > 1e-2);
+        assert!(log.max_relative_energy_drift() < 1e-5, "{}", log.max_relative_energy_drift());
+    }
+}
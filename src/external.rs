@@ -0,0 +1,298 @@
+use cgmath::{InnerSpace, Vector2, Zero};
+use crate::body::Body;
+use crate::integrator::for_each_body;
+
+/// A fixed background potential felt by every body on top of self-gravity, see `BarnesHutRunner::external`
+/// and `Simulation::external`. Fields add up by putting several in a `Vec`, and can change with `time`.
+pub trait ExternalField: std::fmt::Debug + Send + Sync {
+    /// Acceleration of a body at `pos`, `g` is the gravitational constant of the run
+    fn acceleration(&self, pos: Vector2<f64>, time: f64, g: f64) -> Vector2<f64>;
+
+    /// Potential per unit mass at `pos`, the acceleration is minus its gradient
+    fn potential(&self, pos: Vector2<f64>, time: f64, g: f64) -> f64;
+}
+
+/// A point of `mass` fixed at `center`
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub struct PointMass {
+    pub center: Vector2<f64>,
+    pub mass: f64
+}
+
+/// Plummer sphere of `mass`, the potential is -G M / sqrt(r^2 + a^2) with `scale` a
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub struct Plummer {
+    pub center: Vector2<f64>,
+    pub mass: f64,
+    pub scale: f64
+}
+
+/// Navarro-Frenk-White halo, the potential is -G M ln(1 + r / r_s) / r with `scale` r_s and
+/// `mass` M = 4 pi rho_0 r_s^3. The enclosed mass grows without bound, so M is not the total mass.
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub struct Nfw {
+    pub center: Vector2<f64>,
+    pub mass: f64,
+    pub scale: f64
+}
+
+/// Logarithmic potential v^2 / 2 ln(r^2 + r_c^2), giving a flat rotation curve at `velocity` outside `core`
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub struct Logarithmic {
+    pub center: Vector2<f64>,
+    pub velocity: f64,
+    pub core: f64
+}
+
+/// The same `acceleration` everywhere
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub struct Uniform {
+    pub acceleration: Vector2<f64>
+}
+
+/// `field` carried along `path`, at time t it sits `path(t)` away from where it was built
+#[derive(Debug,Copy,Clone)]
+pub struct Moving<F> {
+    pub field: F,
+    pub path: fn(f64) -> Vector2<f64>
+}
+
+/// `field` with its strength multiplied by `strength(t)`
+#[derive(Debug,Copy,Clone)]
+pub struct Scaled<F> {
+    pub field: F,
+    pub strength: fn(f64) -> f64
+}
+
+impl ExternalField for PointMass {
+    fn acceleration(&self, pos: Vector2<f64>, _time: f64, g: f64) -> Vector2<f64> {
+        let d = pos - self.center;
+        let r = d.magnitude();
+        //no direction to pull in at the center
+        if r == 0.0 {
+            return Vector2::zero();
+        }
+        -d * (g * self.mass / (r * r * r))
+    }
+
+    fn potential(&self, pos: Vector2<f64>, _time: f64, g: f64) -> f64 {
+        -g * self.mass / (pos - self.center).magnitude()
+    }
+}
+
+impl ExternalField for Plummer {
+    fn acceleration(&self, pos: Vector2<f64>, _time: f64, g: f64) -> Vector2<f64> {
+        let d = pos - self.center;
+        let r2 = d.magnitude2() + (self.scale * self.scale);
+        -d * (g * self.mass / (r2 * r2.sqrt()))
+    }
+
+    fn potential(&self, pos: Vector2<f64>, _time: f64, g: f64) -> f64 {
+        -g * self.mass / ((pos - self.center).magnitude2() + (self.scale * self.scale)).sqrt()
+    }
+}
+
+impl ExternalField for Nfw {
+    fn acceleration(&self, pos: Vector2<f64>, _time: f64, g: f64) -> Vector2<f64> {
+        let d = pos - self.center;
+        let r = d.magnitude();
+        if r == 0.0 {
+            return Vector2::zero();
+        }
+        //dphi/dr = G M (ln(1 + r / r_s) / r^2 - 1 / (r (r + r_s)))
+        let slope = g * self.mass * (((r / self.scale).ln_1p() / (r * r)) - (1.0 / (r * (r + self.scale))));
+        -d * (slope / r)
+    }
+
+    fn potential(&self, pos: Vector2<f64>, _time: f64, g: f64) -> f64 {
+        let r = (pos - self.center).magnitude();
+        //ln(1 + r / r_s) / r goes to 1 / r_s at the center
+        if r == 0.0 {
+            return -g * self.mass / self.scale;
+        }
+        -g * self.mass * (r / self.scale).ln_1p() / r
+    }
+}
+
+impl ExternalField for Logarithmic {
+    fn acceleration(&self, pos: Vector2<f64>, _time: f64, _g: f64) -> Vector2<f64> {
+        let d = pos - self.center;
+        -d * (self.velocity * self.velocity / (d.magnitude2() + (self.core * self.core)))
+    }
+
+    fn potential(&self, pos: Vector2<f64>, _time: f64, _g: f64) -> f64 {
+        0.5 * self.velocity * self.velocity * ((pos - self.center).magnitude2() + (self.core * self.core)).ln()
+    }
+}
+
+impl ExternalField for Uniform {
+    fn acceleration(&self, _pos: Vector2<f64>, _time: f64, _g: f64) -> Vector2<f64> {
+        self.acceleration
+    }
+
+    fn potential(&self, pos: Vector2<f64>, _time: f64, _g: f64) -> f64 {
+        -self.acceleration.dot(pos)
+    }
+}
+
+impl<F: ExternalField> ExternalField for Moving<F> {
+    fn acceleration(&self, pos: Vector2<f64>, time: f64, g: f64) -> Vector2<f64> {
+        self.field.acceleration(pos - (self.path)(time), time, g)
+    }
+
+    fn potential(&self, pos: Vector2<f64>, time: f64, g: f64) -> f64 {
+        self.field.potential(pos - (self.path)(time), time, g)
+    }
+}
+
+impl<F: ExternalField> ExternalField for Scaled<F> {
+    fn acceleration(&self, pos: Vector2<f64>, time: f64, g: f64) -> Vector2<f64> {
+        self.field.acceleration(pos, time, g) * (self.strength)(time)
+    }
+
+    fn potential(&self, pos: Vector2<f64>, time: f64, g: f64) -> f64 {
+        self.field.potential(pos, time, g) * (self.strength)(time)
+    }
+}
+
+impl<F: ExternalField + ?Sized> ExternalField for Box<F> {
+    fn acceleration(&self, pos: Vector2<f64>, time: f64, g: f64) -> Vector2<f64> {
+        (**self).acceleration(pos, time, g)
+    }
+
+    fn potential(&self, pos: Vector2<f64>, time: f64, g: f64) -> f64 {
+        (**self).potential(pos, time, g)
+    }
+}
+
+/// The sum of every field in the list
+impl<F: ExternalField> ExternalField for Vec<F> {
+    fn acceleration(&self, pos: Vector2<f64>, time: f64, g: f64) -> Vector2<f64> {
+        self.iter().map(|field| field.acceleration(pos, time, g)).sum()
+    }
+
+    fn potential(&self, pos: Vector2<f64>, time: f64, g: f64) -> f64 {
+        self.iter().map(|field| field.potential(pos, time, g)).sum()
+    }
+}
+
+/// Adds the pull of `field` at `time` to the force of every body, a tracer feels it as a unit mass
+pub fn add_external_forces(field: &dyn ExternalField, bodies: &mut [Body], time: f64, g: f64){
+    for_each_body(bodies, |body: &mut Body| {
+        body.force += field.acceleration(body.pos, time, g) * body.test_mass();
+    });
+}
+
+/// Potential energy of `bodies` in `field` at `time`
+pub fn external_potential_energy(field: &dyn ExternalField, bodies: &[Body], time: f64, g: f64) -> f64 {
+    bodies.iter().map(|body| body.mass * field.potential(body.pos, time, g)).sum()
+}
+
+#[cfg(test)]
+mod tests{
+    use cgmath::{InnerSpace, Vector2};
+    use crate::body::Body;
+    use crate::bh_runner::BarnesHutRunner;
+    use crate::external::{add_external_forces, ExternalField, Logarithmic, Moving, Nfw, Plummer, PointMass, Scaled, Uniform};
+    use crate::gravity::PhysicsParams;
+    use crate::integrator::{Leapfrog, RungeKutta4};
+    use crate::quadtree::{Quadtree, Rectangle};
+    use crate::simulation::Simulation;
+    use crate::softening::Softening;
+
+    fn fields() -> Vec<Box<dyn ExternalField>> {
+        let center = Vector2::new(3.0, -2.0);
+        vec![
+            Box::new(PointMass { center, mass: 5.0 }),
+            Box::new(Plummer { center, mass: 5.0, scale: 2.0 }),
+            Box::new(Nfw { center, mass: 5.0, scale: 4.0 }),
+            Box::new(Logarithmic { center, velocity: 1.5, core: 1.0 }),
+            Box::new(Uniform { acceleration: Vector2::new(0.0, -9.8) }),
+            Box::new(Moving { field: Plummer { center, mass: 5.0, scale: 2.0 }, path: |t| Vector2::new(t, 0.0) }),
+            Box::new(Scaled { field: PointMass { center, mass: 5.0 }, strength: |t| 1.0 + t })
+        ]
+    }
+
+    #[test]
+    fn acceleration_is_minus_the_gradient(){
+        let h = 1e-5;
+        for field in fields(){
+            for pos in [Vector2::new(0.0, 0.0), Vector2::new(10.0, 7.0), Vector2::new(3.5, -2.0)]{
+                let gradient = Vector2::new(
+                    field.potential(pos + Vector2::new(h, 0.0), 2.0, 0.7) - field.potential(pos - Vector2::new(h, 0.0), 2.0, 0.7),
+                    field.potential(pos + Vector2::new(0.0, h), 2.0, 0.7) - field.potential(pos - Vector2::new(0.0, h), 2.0, 0.7)
+                ) / (2.0 * h);
+                let acceleration = field.acceleration(pos, 2.0, 0.7);
+                assert!((acceleration + gradient).magnitude() < 1e-6 * acceleration.magnitude().max(1.0), "{:?} at {:?}: {:?} {:?}", field, pos, acceleration, -gradient);
+            }
+        }
+    }
+
+    #[test]
+    fn fields_add_up(){
+        let pos = Vector2::new(-4.0, 1.0);
+        let all = fields();
+        let sum: Vector2<f64> = all.iter().map(|field| field.acceleration(pos, 1.0, 1.0)).sum();
+        assert!((all.acceleration(pos, 1.0, 1.0) - sum).magnitude() < 1e-12);
+        assert_eq!(Vec::<PointMass>::new().potential(pos, 1.0, 1.0), 0.0);
+    }
+
+    #[test]
+    fn time_dependent_fields(){
+        let plummer = Plummer { center: Vector2::new(0.0, 0.0), mass: 1.0, scale: 1.0 };
+        let moving = Moving { field: plummer, path: |t| Vector2::new(0.0, 2.0 * t) };
+        assert_eq!(moving.potential(Vector2::new(0.0, 6.0), 3.0, 1.0), plummer.potential(Vector2::new(0.0, 0.0), 3.0, 1.0));
+        let growing = Scaled { field: plummer, strength: |t| t * t };
+        assert_eq!(growing.acceleration(Vector2::new(1.0, 0.0), 0.0, 1.0), Vector2::new(0.0, 0.0));
+        assert_eq!(growing.acceleration(Vector2::new(1.0, 0.0), 2.0, 1.0), plummer.acceleration(Vector2::new(1.0, 0.0), 2.0, 1.0) * 4.0);
+    }
+
+    #[test]
+    fn stages_see_the_field_at_their_own_time(){
+        //a push of t^2 along x, x(t) = t^4 / 12. RK4 only gets it right when every stage samples its own time
+        let push = || Box::new(Scaled { field: Uniform { acceleration: Vector2::new(1.0, 0.0) }, strength: |t| t * t }) as Box<dyn ExternalField>;
+        let mut simulation = Simulation::with_integrator(Box::new(RungeKutta4));
+        simulation.params.dt = 0.1;
+        simulation.external.push(push());
+        simulation.bodies.push(Body::tracer(Vector2::new(0.0, 0.0)));
+        let mut runner = BarnesHutRunner::with_integrator(0.5, Box::new(RungeKutta4));
+        runner.params.dt = 0.1;
+        runner.external.push(push());
+        let mut bodies: Vec<Body> = simulation.bodies.clone();
+        let mut qt: Quadtree = Quadtree::new(Rectangle::new(Vector2::new(0.0,0.0),Vector2::new(1.0,1.0)),1);
+        for _ in 0..20{
+            simulation.update();
+            runner.force_iterate(&mut qt, &mut bodies);
+        }
+        for body in [simulation.bodies[0], bodies[0]]{
+            assert!((body.pos.x - (16.0 / 12.0)).abs() < 1e-12, "{:?}", body.pos);
+            assert!((body.velocity.x - (8.0 / 3.0)).abs() < 1e-12, "{:?}", body.velocity);
+        }
+    }
+
+    #[test]
+    fn forces_scale_with_mass(){
+        let field = Uniform { acceleration: Vector2::new(1.0, -2.0) };
+        let mut bodies: Vec<Body> = vec![Body::with_mass_and_pos(3.0, Vector2::new(0.0, 0.0)), Body::tracer(Vector2::new(1.0, 1.0))];
+        add_external_forces(&field, &mut bodies, 0.0, 1.0);
+        assert_eq!(bodies[0].force, Vector2::new(3.0, -6.0));
+        assert_eq!(bodies[1].force, Vector2::new(1.0, -2.0));
+    }
+
+    #[test]
+    fn tracer_orbits_external_point_mass(){
+        let mut runner: BarnesHutRunner = BarnesHutRunner::with_integrator(0.5, Box::new(Leapfrog));
+        runner.params = PhysicsParams::new(1.0, 0.001, Softening::None { min_distance: 0.0 });
+        runner.external.push(Box::new(PointMass { center: Vector2::new(0.0, 0.0), mass: 1.0 }));
+        let mut qt: Quadtree = Quadtree::new(Rectangle::new(Vector2::new(0.0,0.0),Vector2::new(1.0,1.0)),1);
+        let mut bodies: Vec<Body> = vec![Body::tracer(Vector2::new(1.0, 0.0))];
+        bodies[0].velocity = Vector2::new(0.0, 1.0);
+        //one full period of 2 pi
+        let steps = (2.0 * std::f64::consts::PI / runner.params.dt).round() as usize;
+        for _ in 0..steps{
+            runner.iterate(&mut qt, &mut bodies);
+            assert!((bodies[0].pos.magnitude() - 1.0).abs() < 1e-6);
+        }
+        assert!((bodies[0].pos - Vector2::new(1.0, 0.0)).magnitude() < 1e-3, "{:?}", bodies[0].pos);
+    }
+}
@@ -0,0 +1,108 @@
+use std::f64::consts::PI;
+use crate::fmm::Complex;
+
+/// In place radix-2 FFT, `data.len()` must be a power of two.
+/// The forward transform is unnormalised, the inverse one divides by the length so the two round trip.
+pub fn fft(data: &mut [Complex], inverse: bool){
+    let n = data.len();
+    assert!(n.is_power_of_two(), "fft length {} is not a power of two", n);
+
+    //bit reversal permutation
+    let mut j = 0;
+    for i in 1..n{
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            data.swap(i, j);
+        }
+    }
+
+    let sign = if inverse { 1.0 } else { -1.0 };
+    let mut length = 2;
+    while length <= n {
+        let angle = sign * 2.0 * PI / length as f64;
+        for start in (0..n).step_by(length){
+            for k in 0..length / 2 {
+                let twiddle = Complex::new((angle * k as f64).cos(), (angle * k as f64).sin());
+                let even = data[start + k];
+                let odd = data[start + k + (length / 2)] * twiddle;
+                data[start + k] = even + odd;
+                data[start + k + (length / 2)] = even - odd;
+            }
+        }
+        length <<= 1;
+    }
+
+    if inverse {
+        for value in data.iter_mut(){
+            *value = value.scale(1.0 / n as f64);
+        }
+    }
+}
+
+/// `fft` over a row major `n` x `n` grid, rows first and then columns
+pub fn fft2(data: &mut [Complex], n: usize, inverse: bool){
+    assert_eq!(data.len(), n * n);
+    for row in data.chunks_mut(n){
+        fft(row, inverse);
+    }
+    let mut column: Vec<Complex> = vec![Complex::ZERO; n];
+    for x in 0..n{
+        for (y, value) in column.iter_mut().enumerate(){
+            *value = data[(y * n) + x];
+        }
+        fft(&mut column, inverse);
+        for (y, value) in column.iter().enumerate(){
+            data[(y * n) + x] = *value;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::f64::consts::PI;
+    use crate::fft::{fft, fft2};
+    use crate::fmm::Complex;
+
+    fn close(a: &[Complex], b: &[Complex]) -> bool {
+        a.iter().zip(b.iter()).all(|(a, b)| (*a - *b).norm() < 1e-9)
+    }
+
+    #[test]
+    fn matches_naive_dft(){
+        let data: Vec<Complex> = (0..16).map(|i| Complex::new((i as f64 * 0.7).sin(), (i * i) as f64 * 0.1)).collect();
+        let naive: Vec<Complex> = (0..16).map(|k| {
+            data.iter().enumerate().fold(Complex::ZERO, |sum, (j, value)| {
+                let angle = -2.0 * PI * (j * k) as f64 / 16.0;
+                sum + (*value * Complex::new(angle.cos(), angle.sin()))
+            })
+        }).collect();
+        let mut transformed = data.clone();
+        fft(&mut transformed, false);
+        assert!(close(&transformed, &naive));
+        fft(&mut transformed, true);
+        assert!(close(&transformed, &data));
+    }
+
+    #[test]
+    fn plane_wave_lands_in_one_bin(){
+        //exp(i 2 pi (3x + 5y) / 8) only has the (3, 5) component
+        let n = 8;
+        let mut data: Vec<Complex> = (0..n * n).map(|index| {
+            let angle = 2.0 * PI * (((index % n) * 3) + ((index / n) * 5)) as f64 / n as f64;
+            Complex::new(angle.cos(), angle.sin())
+        }).collect();
+        let original = data.clone();
+        fft2(&mut data, n, false);
+        for (index, value) in data.iter().enumerate(){
+            let expected = if index == (5 * n) + 3 { (n * n) as f64 } else { 0.0 };
+            assert!((*value - Complex::new(expected, 0.0)).norm() < 1e-9, "bin {}", index);
+        }
+        fft2(&mut data, n, true);
+        assert!(close(&data, &original));
+    }
+}
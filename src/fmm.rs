@@ -0,0 +1,596 @@
+use std::ops::{Add, AddAssign, Mul, Sub};
+use cgmath::{Vector2, Zero};
+use crate::body::Body;
+use crate::gravity;
+use crate::gravity::PhysicsParams;
+use crate::integrator::{ExplicitEuler, Integrator};
+use crate::quadtree::{Node, Quadtree, ROOT};
+use crate::solver::Solver;
+
+/// Just the complex arithmetic the expansions need
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub struct Complex {
+    pub re: f64,
+    pub im: f64
+}
+
+/// A fast multipole solver on the same `Quadtree` as `BarnesHutRunner`.
+///
+/// The kernel is the 1/r of `gravity`, written with complex numbers as |z|^-1 = z^-1/2 conj(z)^-1/2,
+/// so every expansion is a double series in z and conj(z) truncated at total degree `order`.
+/// Pairs of nodes are found by a dual tree walk: two nodes whose radii add up to less than
+/// `theta` times their separation exchange a multipole to local translation, everything else is
+/// split until it reaches two leaves, which are summed directly. The work is O(n) for a fixed `order`.
+///
+/// Only the direct sums are softened, the expansions are Newtonian.
+#[derive(Debug)]
+pub struct FmmSolver {
+    pub order: usize,
+    pub theta: f64,
+    pub paused: bool,
+    pub integrator: Box<dyn Integrator>,
+    pub params: PhysicsParams,
+    pub time: f64
+}
+
+/// Coefficients `c[j][k]` of `sum c[j][k] z^j conj(z)^k` for `j + k <= order`
+#[derive(Debug,Clone)]
+struct Expansion {
+    coefficients: Vec<Complex>
+}
+
+/// Series coefficients shared by every translation at one order
+struct Tables {
+    order: usize,
+    /// Binomial coefficients `binomial[n][k]` for `n <= order`
+    binomial: Vec<Vec<f64>>,
+    /// `(1/2)_j / j!`, the coefficients of `(1 - x)^-1/2`
+    half: Vec<f64>,
+    /// `(-1)^n (j + 1/2)_n / n!`, the coefficients of `(1 + x)^-(j + 1/2)`
+    shifted: Vec<Vec<f64>>
+}
+
+/// Per evaluation state of the walk
+struct Pass<'a> {
+    quadtree: &'a Quadtree,
+    bodies: &'a [Body],
+    tables: Tables,
+    multipoles: Vec<Expansion>,
+    locals: Vec<Expansion>,
+    forces: Vec<Vector2<f64>>
+}
+
+impl Complex {
+    pub const ZERO: Complex = Complex { re: 0.0, im: 0.0 };
+    pub const ONE: Complex = Complex { re: 1.0, im: 0.0 };
+
+    pub fn new(re: f64, im: f64) -> Self {
+        Self {
+            re,
+            im
+        }
+    }
+
+    pub fn from_vector(vector: Vector2<f64>) -> Self {
+        Self::new(vector.x, vector.y)
+    }
+
+    pub fn conj(self) -> Self {
+        Self::new(self.re, -self.im)
+    }
+
+    pub fn scale(self, factor: f64) -> Self {
+        Self::new(self.re * factor, self.im * factor)
+    }
+
+    pub fn norm(self) -> f64 {
+        self.re.hypot(self.im)
+    }
+
+    pub fn inverse(self) -> Self {
+        let norm2 = (self.re * self.re) + (self.im * self.im);
+        Self::new(self.re / norm2, -self.im / norm2)
+    }
+
+    /// `[1, z, z^2, ..., z^n]`
+    pub fn powers(self, n: usize) -> Vec<Complex> {
+        let mut powers: Vec<Complex> = Vec::with_capacity(n + 1);
+        let mut power = Complex::ONE;
+        for _ in 0..=n{
+            powers.push(power);
+            power = power * self;
+        }
+        powers
+    }
+}
+
+impl Add for Complex {
+    type Output = Complex;
+    fn add(self, other: Complex) -> Complex {
+        Complex::new(self.re + other.re, self.im + other.im)
+    }
+}
+
+impl AddAssign for Complex {
+    fn add_assign(&mut self, other: Complex){
+        self.re += other.re;
+        self.im += other.im;
+    }
+}
+
+impl Sub for Complex {
+    type Output = Complex;
+    fn sub(self, other: Complex) -> Complex {
+        Complex::new(self.re - other.re, self.im - other.im)
+    }
+}
+
+impl Mul for Complex {
+    type Output = Complex;
+    fn mul(self, other: Complex) -> Complex {
+        Complex::new((self.re * other.re) - (self.im * other.im), (self.re * other.im) + (self.im * other.re))
+    }
+}
+
+impl Expansion {
+    fn new(order: usize) -> Self {
+        Self {
+            coefficients: vec![Complex::ZERO; (order + 1) * (order + 2) / 2]
+        }
+    }
+
+    /// Terms are stored by total degree, `j + k = d` starts at `d (d + 1) / 2`
+    fn index(j: usize, k: usize) -> usize {
+        let degree = j + k;
+        (degree * (degree + 1) / 2) + k
+    }
+
+    fn get(&self, j: usize, k: usize) -> Complex {
+        self.coefficients[Self::index(j, k)]
+    }
+
+    fn add(&mut self, j: usize, k: usize, value: Complex){
+        self.coefficients[Self::index(j, k)] += value;
+    }
+}
+
+impl Tables {
+    fn new(order: usize) -> Self {
+        let mut binomial: Vec<Vec<f64>> = vec![vec![1.0]];
+        for n in 1..=order{
+            let previous = &binomial[n - 1];
+            let row: Vec<f64> = (0..=n).map(|k| {
+                if k == 0 || k == n { 1.0 } else { previous[k - 1] + previous[k] }
+            }).collect();
+            binomial.push(row);
+        }
+        let mut half: Vec<f64> = vec![1.0];
+        for j in 1..=order{
+            half.push(half[j - 1] * (j as f64 - 0.5) / j as f64);
+        }
+        let shifted: Vec<Vec<f64>> = (0..=order).map(|j| {
+            let mut row: Vec<f64> = vec![1.0];
+            for n in 1..=order{
+                row.push(-row[n - 1] * (j as f64 + 0.5 + (n - 1) as f64) / n as f64);
+            }
+            row
+        }).collect();
+        Self {
+            order,
+            binomial,
+            half,
+            shifted
+        }
+    }
+}
+
+impl FmmSolver {
+    pub fn new() -> FmmSolver {
+        Self::with_order(8, 0.5)
+    }
+
+    pub fn with_order(order: usize, theta: f64) -> FmmSolver {
+        Self {
+            order,
+            theta,
+            paused: false,
+            integrator: Box::new(ExplicitEuler),
+            params: PhysicsParams::default(),
+            time: 0.0
+        }
+    }
+
+    /// Overwrites the force on every body using the tree as it was last built
+    pub fn tree_forces(&self, quadtree: &Quadtree, bodies: &mut [Body]){
+        let mut pass = Pass {
+            quadtree,
+            bodies,
+            tables: Tables::new(self.order),
+            multipoles: vec![Expansion::new(self.order); quadtree.nodes.len()],
+            locals: vec![Expansion::new(self.order); quadtree.nodes.len()],
+            forces: vec![Vector2::zero(); bodies.len()]
+        };
+        pass.upward();
+        pass.interact(ROOT, ROOT, self);
+        pass.downward(&self.params);
+        let forces = pass.forces;
+        for (body, force) in bodies.iter_mut().zip(forces){
+            body.force = force;
+        }
+    }
+
+    pub fn update(&mut self, quadtree: &mut Quadtree, bodies: &mut [Body]){
+        self.tree_forces(quadtree, bodies);
+        self.integrator.step(bodies, &self.params, &mut |bodies: &mut [Body], _| {
+            self.evaluate_forces(quadtree, bodies);
+        });
+        self.time += self.params.dt;
+    }
+
+    pub fn evaluate_forces(&self, quadtree: &mut Quadtree, bodies: &mut [Body]){
+        quadtree.resize_to_fit(bodies);
+        quadtree.build(bodies);
+        self.tree_forces(quadtree, bodies);
+    }
+}
+
+impl Default for FmmSolver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Where the expansions of `node` are taken. A node of only tracers has no center of mass and uses the middle
+/// of its rectangle, so the tracers in it still get a local expansion.
+fn expansion_center(node: &Node) -> Vector2<f64> {
+    node.center_of_mass.unwrap_or(node.boundaries.midpoint())
+}
+
+impl Pass<'_> {
+    /// Multipoles of the leaves from their bodies, then of every other node from its children
+    fn upward(&mut self){
+        let order = self.tables.order;
+        for id in (0..self.quadtree.nodes.len()).rev(){
+            let node = &self.quadtree.nodes[id];
+            let center = match node.center_of_mass {
+                Some(center) => center,
+                None => continue
+            };
+            let mut multipole = Expansion::new(order);
+            match node.children {
+                None => {
+                    for body in node.bodies.iter().map(|index| &self.bodies[*index]).filter(|body| body.is_source()){
+                        let w = Complex::from_vector(body.pos - center);
+                        let powers = w.powers(order);
+                        let conj_powers = w.conj().powers(order);
+                        for (j, power) in powers.iter().enumerate(){
+                            for (k, conj_power) in conj_powers[..=order - j].iter().enumerate(){
+                                multipole.add(j, k, (*power * *conj_power).scale(body.mass));
+                            }
+                        }
+                    }
+                }
+                Some(children) => {
+                    for child in children{
+                        if let Some(child_center) = self.quadtree.nodes[child].center_of_mass {
+                            self.shift_multipole(&mut multipole, &self.multipoles[child], Complex::from_vector(child_center - center));
+                        }
+                    }
+                }
+            }
+            self.multipoles[id] = multipole;
+        }
+    }
+
+    /// Adds `source`, taken about a point `delta` away from the new center, to `target` (M2M)
+    fn shift_multipole(&self, target: &mut Expansion, source: &Expansion, delta: Complex){
+        let order = self.tables.order;
+        let powers = delta.powers(order);
+        let conj_powers = delta.conj().powers(order);
+        for j in 0..=order{
+            for k in 0..=order - j{
+                let mut sum = Complex::ZERO;
+                for a in 0..=j{
+                    for b in 0..=k{
+                        let factor = self.tables.binomial[j][a] * self.tables.binomial[k][b];
+                        sum += (powers[j - a] * conj_powers[k - b] * source.get(a, b)).scale(factor);
+                    }
+                }
+                target.add(j, k, sum);
+            }
+        }
+    }
+
+    /// Adds the field of the multipole of `source` to the local expansion of `target`,
+    /// `separation` is target center minus source center (M2L)
+    fn translate(&mut self, target: usize, source: usize, separation: Complex){
+        let order = self.tables.order;
+        let inverse = separation.inverse();
+        let powers = inverse.powers(2 * order);
+        let conj_powers = inverse.conj().powers(2 * order);
+        let scale = 1.0 / separation.norm();
+        let multipole = &self.multipoles[source];
+        let local = &mut self.locals[target];
+        for n in 0..=order{
+            for l in 0..=order - n{
+                let mut sum = Complex::ZERO;
+                for j in 0..=order{
+                    for k in 0..=order - j{
+                        let factor = self.tables.half[j] * self.tables.half[k] * self.tables.shifted[j][n] * self.tables.shifted[k][l];
+                        sum += (multipole.get(j, k) * powers[j + n] * conj_powers[k + l]).scale(factor);
+                    }
+                }
+                local.add(n, l, sum.scale(scale));
+            }
+        }
+    }
+
+    /// The dual tree walk, `a == b` handles the interaction of a node with itself
+    fn interact(&mut self, a: usize, b: usize, solver: &FmmSolver){
+        let quadtree = self.quadtree;
+        let node_a = &quadtree.nodes[a];
+        let node_b = &quadtree.nodes[b];
+        //one side may hold only tracers, which still feel the other
+        if node_a.center_of_mass.is_none() && node_b.center_of_mass.is_none() {
+            return;
+        }
+        let (center_a, center_b) = (expansion_center(node_a), expansion_center(node_b));
+
+        if a == b {
+            match node_a.children {
+                None => self.direct(node_a, node_a, &solver.params),
+                Some(children) => {
+                    for (i, child) in children.iter().enumerate(){
+                        for other in &children[i..]{
+                            self.interact(*child, *other, solver);
+                        }
+                    }
+                }
+            }
+            return;
+        }
+
+        let separation = Complex::from_vector(center_a - center_b);
+        let radii = node_a.boundaries.max_extent_from(center_a) + node_b.boundaries.max_extent_from(center_b);
+        if radii < solver.theta * separation.norm() {
+            self.translate(a, b, separation);
+            self.translate(b, a, Complex::ZERO - separation);
+            return;
+        }
+
+        match (node_a.children, node_b.children) {
+            (None, None) => self.direct(node_a, node_b, &solver.params),
+            (Some(children), None) => {
+                for child in children{
+                    self.interact(child, b, solver);
+                }
+            }
+            (None, Some(children)) => {
+                for child in children{
+                    self.interact(a, child, solver);
+                }
+            }
+            (Some(children_a), Some(children_b)) => {
+                if node_a.boundaries.width() >= node_b.boundaries.width() {
+                    for child in children_a{
+                        self.interact(child, b, solver);
+                    }
+                } else {
+                    for child in children_b{
+                        self.interact(a, child, solver);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Sums two leaves pair by pair, a leaf with itself counts every pair once
+    fn direct(&mut self, leaf_a: &Node, leaf_b: &Node, params: &PhysicsParams){
+        let same = std::ptr::eq(leaf_a, leaf_b);
+        for (i, index_a) in leaf_a.bodies.iter().enumerate(){
+            let others = if same { &leaf_b.bodies[i + 1..] } else { &leaf_b.bodies[..] };
+            for index_b in others{
+                let body_a = &self.bodies[*index_a];
+                let body_b = &self.bodies[*index_b];
+                if body_a.id == body_b.id {
+                    continue;
+                }
+                //a tracer only feels the other body
+                match (body_a.is_source(), body_b.is_source()) {
+                    (true, true) => {
+                        let force = gravity::force_mass_center(body_a, body_b.pos, body_b.mass, params);
+                        self.forces[*index_a] += force;
+                        self.forces[*index_b] -= force;
+                    }
+                    (true, false) => self.forces[*index_b] += gravity::force_mass_center(body_b, body_a.pos, body_a.mass, params),
+                    (false, true) => self.forces[*index_a] += gravity::force_mass_center(body_a, body_b.pos, body_b.mass, params),
+                    (false, false) => {}
+                }
+            }
+        }
+    }
+
+    /// Passes every local expansion down to the children (L2L) and evaluates it at the bodies of the leaves
+    fn downward(&mut self, params: &PhysicsParams){
+        let order = self.tables.order;
+        let quadtree = self.quadtree;
+        for (id, node) in quadtree.nodes.iter().enumerate(){
+            let center = expansion_center(node);
+            match node.children {
+                Some(children) => {
+                    for child in children{
+                        let shifted = self.shift_local(&self.locals[id], Complex::from_vector(expansion_center(&quadtree.nodes[child]) - center));
+                        for (target, value) in self.locals[child].coefficients.iter_mut().zip(shifted.coefficients){
+                            *target += value;
+                        }
+                    }
+                }
+                None => {
+                    let local = &self.locals[id];
+                    for index in &node.bodies{
+                        let body = &self.bodies[*index];
+                        let t = Complex::from_vector(body.pos - center);
+                        let powers = t.powers(order);
+                        let conj_powers = t.conj().powers(order);
+                        //d/dt of sum L t^n conj(t)^l, the gradient is (2 Re, -2 Im) of it
+                        let mut derivative = Complex::ZERO;
+                        for n in 1..=order{
+                            for (l, conj_power) in conj_powers[..=order - n].iter().enumerate(){
+                                derivative += (local.get(n, l) * powers[n - 1] * *conj_power).scale(n as f64);
+                            }
+                        }
+                        self.forces[*index] += Vector2::new(2.0 * derivative.re, -2.0 * derivative.im) * (params.g * body.test_mass());
+                    }
+                }
+            }
+        }
+    }
+
+    /// `local` re-expanded about a point `delta` away from its center (L2L)
+    fn shift_local(&self, local: &Expansion, delta: Complex) -> Expansion {
+        let order = self.tables.order;
+        let powers = delta.powers(order);
+        let conj_powers = delta.conj().powers(order);
+        let mut shifted = Expansion::new(order);
+        for n in 0..=order{
+            for l in 0..=order - n{
+                let coefficient = local.get(n, l);
+                for a in 0..=n{
+                    for b in 0..=l{
+                        let factor = self.tables.binomial[n][a] * self.tables.binomial[l][b];
+                        shifted.add(a, b, (coefficient * powers[n - a] * conj_powers[l - b]).scale(factor));
+                    }
+                }
+            }
+        }
+        shifted
+    }
+}
+
+impl Solver for FmmSolver {
+    fn params(&self) -> &PhysicsParams {
+        &self.params
+    }
+
+    fn params_mut(&mut self) -> &mut PhysicsParams {
+        &mut self.params
+    }
+
+    fn time(&self) -> f64 {
+        self.time
+    }
+
+    fn evaluate_forces(&self, quadtree: &mut Quadtree, bodies: &mut [Body]){
+        FmmSolver::evaluate_forces(self, quadtree, bodies);
+    }
+
+    fn iterate(&mut self, quadtree: &mut Quadtree, bodies: &mut Vec<Body>){
+        if self.paused {
+            return;
+        }
+        quadtree.resize_to_fit(bodies);
+        quadtree.build(bodies);
+        self.update(quadtree, bodies);
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use cgmath::Vector2;
+    use crate::accuracy::{exact_forces, relative_errors, ErrorStats};
+    use crate::bh_runner::BarnesHutRunner;
+    use crate::body::Body;
+    use crate::fmm::{Complex, FmmSolver};
+    use crate::gravity::PhysicsParams;
+    use crate::integrator::Leapfrog;
+    use crate::quadtree::{Quadtree, Rectangle};
+    use crate::softening::Softening;
+    use crate::solver::Solver;
+
+    fn newtonian() -> PhysicsParams {
+        PhysicsParams::new(1.0, 0.01, Softening::None { min_distance: 0.0 })
+    }
+
+    fn fmm_errors(solver: &FmmSolver, limit: usize, bodies: &[Body]) -> ErrorStats {
+        let mut qt: Quadtree = Quadtree::new(Rectangle::new(Vector2::new(0.0,0.0),Vector2::new(1.0,1.0)),limit);
+        let mut fmm_bodies: Vec<Body> = bodies.to_vec();
+        solver.evaluate_forces(&mut qt, &mut fmm_bodies);
+        let approximate: Vec<Vector2<f64>> = fmm_bodies.iter().map(|body| body.force).collect();
+        ErrorStats::from_errors(&relative_errors(&approximate, &exact_forces(bodies, &solver.params)))
+    }
+
+    #[test]
+    fn complex_arithmetic(){
+        let z = Complex::new(3.0, 4.0);
+        assert_eq!(z.norm(), 5.0);
+        assert_eq!(z * z.conj(), Complex::new(25.0, 0.0));
+        assert_eq!(z.powers(2), vec![Complex::ONE, z, Complex::new(-7.0, 24.0)]);
+        let product = z * z.inverse();
+        assert!((product.re - 1.0).abs() < 1e-15 && product.im.abs() < 1e-15);
+    }
+
+    #[test]
+    fn matches_direct_summation(){
+        let mut solver = FmmSolver::with_order(8, 0.5);
+        solver.params = newtonian();
+        let mut rng = fastrand::Rng::with_seed(400);
+        let bodies: Vec<Body> = (0..400).map(|_| Body::with_mass_and_pos(10.0, Vector2::new(rng.f64(), rng.f64()) * 1000.0)).collect();
+        for limit in [2, 8]{
+            let stats = fmm_errors(&solver, limit, &bodies);
+            assert!(stats.percentile_99 < 1e-4, "limit {}: {}", limit, stats);
+        }
+
+        //the uniform grid from generate_square, with the default softening on the near field
+        let mut grid: Vec<Body> = Vec::new();
+        BarnesHutRunner::new().generate_square(&mut grid, 20, 100.0, 100.0);
+        let mut solver = FmmSolver::with_order(8, 0.5);
+        solver.params.softening = Softening::Plummer { epsilon: 0.01 };
+        assert!(fmm_errors(&solver, 4, &grid).percentile_99 < 1e-3);
+    }
+
+    #[test]
+    fn error_falls_with_order(){
+        let mut rng = fastrand::Rng::with_seed(500);
+        let bodies: Vec<Body> = (0..500).map(|_| Body::with_mass_and_pos(10.0, Vector2::new(rng.f64(), rng.f64()) * 1000.0)).collect();
+        let mut errors: Vec<f64> = Vec::new();
+        for order in [2, 4, 8]{
+            let mut solver = FmmSolver::with_order(order, 0.6);
+            solver.params = newtonian();
+            errors.push(fmm_errors(&solver, 4, &bodies).median);
+        }
+        assert!(errors[1] < errors[0] && errors[2] < errors[1], "{:?}", errors);
+    }
+
+    #[test]
+    fn lone_body_feels_no_force(){
+        let solver = FmmSolver::new();
+        let mut qt: Quadtree = Quadtree::new(Rectangle::new(Vector2::new(0.0,0.0),Vector2::new(1.0,1.0)),1);
+        let mut bodies: Vec<Body> = vec![Body::with_mass_and_pos(10.0, Vector2::new(3.0, 4.0))];
+        solver.evaluate_forces(&mut qt, &mut bodies);
+        assert_eq!(bodies[0].force, Vector2::new(0.0, 0.0));
+    }
+
+    #[test]
+    fn solvers_are_interchangeable(){
+        let mut rng = fastrand::Rng::with_seed(100);
+        let initial: Vec<Body> = (0..100).map(|_| Body::with_mass_and_pos(10.0, Vector2::new(rng.f64(), rng.f64()) * 300.0)).collect();
+        let mut solvers: Vec<Box<dyn Solver>> = vec![
+            Box::new(BarnesHutRunner::with_integrator(0.0, Box::new(Leapfrog))),
+            Box::new(FmmSolver { integrator: Box::new(Leapfrog), ..FmmSolver::with_order(8, 0.5) })
+        ];
+        let mut results: Vec<Vec<Body>> = Vec::new();
+        for solver in solvers.iter_mut(){
+            *solver.params_mut() = newtonian();
+            let mut qt: Quadtree = Quadtree::new(Rectangle::new(Vector2::new(0.0,0.0),Vector2::new(1.0,1.0)),8);
+            let mut bodies: Vec<Body> = initial.clone();
+            for _ in 0..5{
+                solver.iterate(&mut qt, &mut bodies);
+            }
+            assert!((solver.time() - 0.05).abs() < 1e-12);
+            results.push(bodies);
+        }
+        for (tree, fmm) in results[0].iter().zip(results[1].iter()){
+            assert!((tree.pos.x - fmm.pos.x).abs() < 1e-6 && (tree.pos.y - fmm.pos.y).abs() < 1e-6);
+        }
+    }
+}
@@ -0,0 +1,283 @@
+use cgmath::{InnerSpace, Vector2, Zero};
+use crate::body::Body;
+use crate::gravity;
+use crate::gravity::PhysicsParams;
+use crate::quadtree::Quadtree;
+
+/// A pairwise interaction the tree walk and the direct sums can work with, set with
+/// `BarnesHutRunner::law` and `Simulation::law`. `Gravity` is the default.
+///
+/// The force on body a from body b is `coupling(a) * strength(b) * kernel(a - b)`, the kernel has to be odd
+/// so the pair feels equal and opposite fields. A node of the tree is
+/// seen through the `Multipole` of its sources, built up from the leaves by `combine`.
+/// `FmmSolver` and `PmSolver` only do gravity.
+pub trait ForceLaw: std::fmt::Debug + Send + Sync {
+    /// How strongly a body sources the interaction, its mass for gravity. Bodies of strength 0 are skipped.
+    fn strength(&self, body: &Body) -> f64;
+
+    /// How strongly a body feels the interaction
+    fn coupling(&self, body: &Body) -> f64;
+
+    /// The softened field of a unit source at separation `d` (r21)
+    fn kernel(&self, d: Vector2<f64>, params: &PhysicsParams) -> Vector2<f64>;
+
+    /// `Some(c)` when the kernel is `c d / r^3` outside the softening. Laws like that get dipole and quadrupole
+    /// terms from the default `multipole_field` and `quadrupole_field`, and the Ewald correction when periodic.
+    fn inverse_square(&self, _params: &PhysicsParams) -> Option<f64> {
+        None
+    }
+
+    /// Whether this is Newtonian gravity, for the integrators that only know how to take gravity's jerk
+    fn is_gravity(&self) -> bool {
+        false
+    }
+
+    /// The moments of several parts taken together. By default they are summed about the center of their
+    /// absolute strengths, which for masses is the center of mass and leaves no dipole.
+    /// `None` when none of the parts has any strength.
+    fn combine(&self, parts: &[Multipole]) -> Option<Multipole> {
+        let magnitude: f64 = parts.iter().map(|part| part.magnitude).sum();
+        if magnitude == 0.0 {
+            return None;
+        }
+        let center = parts.iter().map(|part| part.center * part.magnitude).sum::<Vector2<f64>>() / magnitude;
+        Some(parts.iter().fold(Multipole::empty(center), |total, part| total.add(&part.shifted(center))))
+    }
+
+    /// The multipole of every node of `quadtree` by arena index, `bodies` is the slice the tree was built from
+    fn node_multipoles(&self, quadtree: &Quadtree, bodies: &[Body]) -> Vec<Option<Multipole>> {
+        let mut multipoles: Vec<Option<Multipole>> = vec![None; quadtree.nodes.len()];
+        //children always come after their parent in the arena
+        for (index, node) in quadtree.nodes.iter().enumerate().rev(){
+            let parts: Vec<Multipole> = match node.children {
+                Some(children) => children.iter().filter_map(|child| multipoles[*child]).collect(),
+                None => node.bodies.iter()
+                    .map(|index| Multipole::point(bodies[*index].pos, self.strength(&bodies[*index])))
+                    .filter(|point| point.strength != 0.0)
+                    .collect()
+            };
+            multipoles[index] = self.combine(&parts);
+        }
+        multipoles
+    }
+
+    /// The field of a node at `d` (r21) from its center, to first order. Nodes are only used far from the
+    /// body, so only the monopole is softened.
+    fn multipole_field(&self, d: Vector2<f64>, multipole: &Multipole, params: &PhysicsParams) -> Vector2<f64> {
+        let monopole = self.kernel(d, params) * multipole.strength;
+        match self.inverse_square(params) {
+            Some(c) if !multipole.dipole.is_zero() => {
+                let r2 = d.magnitude2();
+                let r3 = r2 * r2.sqrt();
+                //minus the gradient of c (p . d) / r^3
+                monopole + ((d * (3.0 * multipole.dipole.dot(d) / r2)) - multipole.dipole) * (c / r3)
+            }
+            _ => monopole
+        }
+    }
+
+    /// The second order correction to `multipole_field`, used with `MultipoleOrder::Quadrupole`.
+    /// Zero unless the law is `inverse_square`.
+    fn quadrupole_field(&self, d: Vector2<f64>, multipole: &Multipole, params: &PhysicsParams) -> Vector2<f64> {
+        match self.inverse_square(params) {
+            Some(c) => gravity::quadrupole_field(d, multipole.quadrupole) * -c,
+            None => Vector2::zero()
+        }
+    }
+}
+
+/// The moments of a group of sources about `center`, as a `ForceLaw` sums them
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub struct Multipole {
+    pub center: Vector2<f64>,
+    /// Sum of the strengths
+    pub strength: f64,
+    /// Sum of the absolute strengths, what the opening criteria see as the mass of a node
+    pub magnitude: f64,
+    /// Sum of s (x - center)
+    pub dipole: Vector2<f64>,
+    /// Sum of s dx dy about `center` as `[xx, xy, yy]`, like `quadtree::Node::quadrupole`
+    pub quadrupole: [f64; 3]
+}
+
+/// Newtonian attraction between masses, tracers feel it as a unit mass
+#[derive(Debug,Copy,Clone,Default)]
+pub struct Gravity;
+
+/// Electrostatics between the `charge` of the bodies, like charges repel with `k q_a q_b / r^2`.
+/// The softening of `PhysicsParams` applies, G does not. Tracers feel the field with their charge but don't source it.
+#[derive(Debug,Copy,Clone)]
+pub struct Coulomb {
+    pub k: f64
+}
+
+impl Multipole {
+    /// No sources, with moments taken about `center`
+    pub fn empty(center: Vector2<f64>) -> Self {
+        Self {
+            center,
+            strength: 0.0,
+            magnitude: 0.0,
+            dipole: Vector2::zero(),
+            quadrupole: [0.0; 3]
+        }
+    }
+
+    /// A single source of `strength` at `pos`
+    pub fn point(pos: Vector2<f64>, strength: f64) -> Self {
+        Self {
+            strength,
+            magnitude: strength.abs(),
+            ..Self::empty(pos)
+        }
+    }
+
+    /// The same sources with their moments taken about `center` instead
+    pub fn shifted(&self, center: Vector2<f64>) -> Self {
+        let offset = self.center - center;
+        let (p, s) = (self.dipole, self.strength);
+        Self {
+            center,
+            strength: s,
+            magnitude: self.magnitude,
+            dipole: p + (offset * s),
+            quadrupole: [
+                self.quadrupole[0] + (2.0 * p.x * offset.x) + (s * offset.x * offset.x),
+                self.quadrupole[1] + (p.x * offset.y) + (p.y * offset.x) + (s * offset.x * offset.y),
+                self.quadrupole[2] + (2.0 * p.y * offset.y) + (s * offset.y * offset.y)
+            ]
+        }
+    }
+
+    /// Both groups together, `other` has to be taken about the same center
+    fn add(&self, other: &Multipole) -> Self {
+        Self {
+            center: self.center,
+            strength: self.strength + other.strength,
+            magnitude: self.magnitude + other.magnitude,
+            dipole: self.dipole + other.dipole,
+            quadrupole: [self.quadrupole[0] + other.quadrupole[0], self.quadrupole[1] + other.quadrupole[1], self.quadrupole[2] + other.quadrupole[2]]
+        }
+    }
+}
+
+impl ForceLaw for Gravity {
+    fn strength(&self, body: &Body) -> f64 {
+        if body.is_tracer() { 0.0 } else { body.mass }
+    }
+
+    fn coupling(&self, body: &Body) -> f64 {
+        body.test_mass()
+    }
+
+    fn kernel(&self, d: Vector2<f64>, params: &PhysicsParams) -> Vector2<f64> {
+        d * -(params.g * params.softening.force_factor(d.magnitude2()))
+    }
+
+    fn inverse_square(&self, params: &PhysicsParams) -> Option<f64> {
+        Some(-params.g)
+    }
+
+    fn is_gravity(&self) -> bool {
+        true
+    }
+
+    /// The quadtree already holds the center of mass and second moments of every node
+    fn node_multipoles(&self, quadtree: &Quadtree, _bodies: &[Body]) -> Vec<Option<Multipole>> {
+        quadtree.nodes.iter().map(|node| node.center_of_mass.map(|center| Multipole {
+            center,
+            strength: node.total_mass,
+            magnitude: node.total_mass,
+            dipole: Vector2::zero(),
+            quadrupole: node.quadrupole
+        })).collect()
+    }
+}
+
+impl Coulomb {
+    pub fn new(k: f64) -> Self {
+        Self {
+            k
+        }
+    }
+}
+
+impl ForceLaw for Coulomb {
+    fn strength(&self, body: &Body) -> f64 {
+        if body.is_tracer() { 0.0 } else { body.charge }
+    }
+
+    fn coupling(&self, body: &Body) -> f64 {
+        body.charge
+    }
+
+    fn kernel(&self, d: Vector2<f64>, params: &PhysicsParams) -> Vector2<f64> {
+        d * (self.k * params.softening.force_factor(d.magnitude2()))
+    }
+
+    fn inverse_square(&self, _params: &PhysicsParams) -> Option<f64> {
+        Some(self.k)
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use cgmath::{InnerSpace, Vector2};
+    use crate::force_law::{Coulomb, ForceLaw, Gravity, Multipole};
+    use crate::gravity::PhysicsParams;
+    use crate::softening::Softening;
+
+    fn params() -> PhysicsParams {
+        PhysicsParams::new(1.0, 0.001, Softening::None { min_distance: 0.0 })
+    }
+
+    /// The exact field of `points` at `pos`
+    fn direct(law: &dyn ForceLaw, points: &[Multipole], pos: Vector2<f64>) -> Vector2<f64> {
+        points.iter().map(|point| law.kernel(pos - point.center, &params()) * point.strength).sum()
+    }
+
+    #[test]
+    fn shifting_keeps_the_moments(){
+        let points = [Multipole::point(Vector2::new(1.0, 2.0), 3.0), Multipole::point(Vector2::new(-2.0, 0.5), -1.0)];
+        let law = Coulomb::new(1.0);
+        let about_origin = points.iter().fold(Multipole::empty(Vector2::new(0.0, 0.0)), |total, point| total.add(&point.shifted(Vector2::new(0.0, 0.0))));
+        assert_eq!(about_origin.dipole, Vector2::new(5.0, 5.5));
+        assert_eq!(about_origin.quadrupole, [3.0 - 4.0, 6.0 + 1.0, 12.0 - 0.25]);
+        //combining about the center of |q| and shifting back lands on the same moments
+        let combined = law.combine(&points).unwrap().shifted(Vector2::new(0.0, 0.0));
+        assert!((combined.dipole - about_origin.dipole).magnitude() < 1e-12);
+        for (a, b) in combined.quadrupole.iter().zip(about_origin.quadrupole.iter()){
+            assert!((a - b).abs() < 1e-12);
+        }
+        assert_eq!(combined.magnitude, 4.0);
+    }
+
+    #[test]
+    fn neutral_pair_is_a_dipole(){
+        let law = Coulomb::new(2.0);
+        let points = [Multipole::point(Vector2::new(0.5, 0.0), 1.0), Multipole::point(Vector2::new(-0.5, 0.0), -1.0)];
+        let multipole = law.combine(&points).unwrap();
+        assert_eq!(multipole.strength, 0.0);
+        for pos in [Vector2::new(30.0, 0.0), Vector2::new(0.0, 30.0), Vector2::new(-18.0, 24.0)]{
+            let exact = direct(&law, &points, pos);
+            let d = pos - multipole.center;
+            let dipole = law.multipole_field(d, &multipole, &params());
+            let quadrupole = dipole + law.quadrupole_field(d, &multipole, &params());
+            assert!((dipole - exact).magnitude() < 1e-2 * exact.magnitude(), "{:?} {:?}", dipole, exact);
+            assert!((quadrupole - exact).magnitude() <= (dipole - exact).magnitude());
+        }
+    }
+
+    #[test]
+    fn gravity_attracts_and_coulomb_repels(){
+        let d = Vector2::new(3.0, 4.0);
+        assert!((Gravity.kernel(d, &params()) - (d * (-1.0 / 125.0))).magnitude() < 1e-15);
+        assert!((Coulomb::new(1.0).kernel(d, &params()) - (d * (1.0 / 125.0))).magnitude() < 1e-15);
+        //masses have no dipole about their center of mass
+        let points = [Multipole::point(Vector2::new(1.0, 0.0), 2.0), Multipole::point(Vector2::new(-2.0, 3.0), 1.0)];
+        let multipole = Gravity.combine(&points).unwrap();
+        assert_eq!(multipole.center, Vector2::new(0.0, 1.0));
+        assert!(multipole.dipole.magnitude() < 1e-12);
+        assert!(Gravity.combine(&[Multipole::point(Vector2::new(1.0, 0.0), 0.0)]).is_none());
+    }
+}
@@ -1,52 +1,243 @@
-use cgmath::{Vector2};
-use crate::body::Body;
+use cgmath::{InnerSpace, Vector2, Vector3};
+use crate::body::{Body, Body3};
+use crate::force_law::{ForceLaw, Gravity};
+use crate::softening::Softening;
 
-const G: f64 = 1.00;
-const DT: f64 = 0.001;
+/// Newton's constant in SI units (m^3 kg^-1 s^-2)
+pub const G_SI: f64 = 6.67430e-11;
 
-const EPSILON: f64 = 1.0;
-//const MIN: f64 = 0.0f64;
-pub fn calculate_force(bodies: &mut[Body]){
-    let d = bodies[0].pos - bodies[1].pos; //r21
-    let d_mag = ((d.x * d.x) + (d.y * d.y) + EPSILON).sqrt();
-    // let d_mag = d.magnitude(); // | r21 |
-    let force: Vector2<f64> = d * ((G * bodies[0].mass * bodies[1].mass)/(d_mag.powi(3)));
-    //TODO bring back the force vector to figure out the issue with DT
-    bodies[0].force-=force;
-    bodies[1].force+=force;
+/// The units a simulation is expressed in, given as the size of one unit in SI.
+/// G is derived from these, so positions, velocities and masses can be entered in the chosen units directly.
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub struct UnitSystem {
+    pub length: f64,
+    pub time: f64,
+    pub mass: f64
 }
 
-pub fn calculate_force_single(body_a: &mut Body, body_b: &mut Body){
-    let d = body_a.pos - body_b.pos; //r21
-    let d_mag = ((d.x * d.x) + (d.y * d.y) + EPSILON).sqrt();
-    let force: Vector2<f64> = d * ((G * body_a.mass * body_b.mass)/(d_mag.powi(3)));
-    body_a.force-=force;
-    body_b.force+=force;
+/// The constants used by the force and integration code.
+/// Held by `BarnesHutRunner` and `Simulation`, and can be changed between steps.
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub struct PhysicsParams {
+    pub g: f64,
+    pub dt: f64,
+    pub softening: Softening
+}
+
+impl UnitSystem {
+    pub fn new(length: f64, time: f64, mass: f64) -> Self {
+        Self {
+            length,
+            time,
+            mass
+        }
+    }
+
+    /// SI units, G = 6.674e-11
+    pub fn si() -> Self {
+        Self::new(1.0, 1.0, 1.0)
+    }
+
+    /// Astronomical units, Julian years and solar masses, G ~ 4 pi^2
+    pub fn astronomical() -> Self {
+        Self::new(1.495978707e11, 3.15576e7, 1.98847e30)
+    }
+
+    /// Kiloparsecs, megayears and solar masses, G ~ 4.50e-12
+    pub fn galactic() -> Self {
+        Self::new(3.085_677_581_491_367e19, 3.15576e13, 1.98847e30)
+    }
+
+    pub fn gravitational_constant(&self) -> f64 {
+        G_SI * self.mass * self.time * self.time / (self.length * self.length * self.length)
+    }
+}
+
+impl PhysicsParams {
+    pub fn new(g: f64, dt: f64, softening: Softening) -> Self {
+        Self {
+            g,
+            dt,
+            softening
+        }
+    }
+
+    pub fn with_units(units: UnitSystem, dt: f64, softening: Softening) -> Self {
+        Self::new(units.gravitational_constant(), dt, softening)
+    }
+}
+
+impl Default for PhysicsParams {
+    /// The values that used to be hard coded in this file
+    fn default() -> Self {
+        Self::new(1.0, 0.001, Softening::default())
+    }
+}
+
+pub fn calculate_force(bodies: &mut[Body], params: &PhysicsParams){
+    let (first, rest) = bodies.split_at_mut(1);
+    calculate_force_single(&mut first[0], &mut rest[0], params);
+}
+
+/// Adds the pull of each body on the other, a tracer only feels the pull and doesn't exert one
+pub fn calculate_force_single(body_a: &mut Body, body_b: &mut Body, params: &PhysicsParams){
+    calculate_force_law(&Gravity, body_a, body_b, params);
+}
+
+/// `calculate_force_single` under any force law, a body without strength only feels the other one
+pub fn calculate_force_law(law: &dyn ForceLaw, body_a: &mut Body, body_b: &mut Body, params: &PhysicsParams){
+    let (strength_a, strength_b) = (law.strength(body_a), law.strength(body_b));
+    //two tracers may sit on top of each other, where the kernel blows up
+    if strength_a == 0.0 && strength_b == 0.0 {
+        return;
+    }
+    let field = law.kernel(body_a.pos - body_b.pos, params); //r21
+    if strength_b != 0.0 {
+        body_a.force += field * (law.coupling(body_a) * strength_b);
+    }
+    if strength_a != 0.0 {
+        body_b.force -= field * (strength_a * law.coupling(body_b));
+    }
 }
 
 //Although this is similar to the code above, I wanted to have a different function
 //to avoid having to allocate a new body when I want to calculate the force,
 //I can also just pass the center and mass fields to this function from a body
 //But this can be fixed later
-pub fn calculate_force_mass_center(body_a: &mut Body, center: Vector2<f64>, mass: f64){
-    let d = body_a.pos - center; //r21
-    let d_mag = ((d.x * d.x) + (d.y * d.y) + EPSILON).sqrt();
-    let force: Vector2<f64> = d * ((G * body_a.mass * mass)/(d_mag.powi(3)));
-    body_a.force-=force;
+pub fn calculate_force_mass_center(law: &dyn ForceLaw, body_a: &mut Body, center: Vector2<f64>, strength: f64, params: &PhysicsParams){
+    body_a.force += law.kernel(body_a.pos - center, params) * (law.coupling(body_a) * strength);
+}
+
+/// The force on `body_a` from `mass` at `center`, for callers that can't hold the body mutably
+pub fn force_mass_center(body_a: &Body, center: Vector2<f64>, mass: f64, params: &PhysicsParams) -> Vector2<f64> {
+    attraction(body_a.pos - center, body_a.test_mass() * mass, params)
 }
 
-pub fn apply_force( body_a: &mut Body){
+/// `force_mass_center` in three dimensions
+pub fn force_mass_center3(body_a: &Body3, center: Vector3<f64>, mass: f64, params: &PhysicsParams) -> Vector3<f64> {
+    attraction(body_a.pos - center, body_a.test_mass() * mass, params)
+}
+
+/// The force along the separation `d` (r21) between two masses whose product is `masses`,
+/// written once so the 2D and 3D code share the softening kernels
+fn attraction<V: InnerSpace<Scalar = f64>>(d: V, masses: f64, params: &PhysicsParams) -> V {
+    let factor = params.softening.force_factor(d.magnitude2());
+    d * -(params.g * masses * factor)
+}
+
+/// Potential energy of a pair, -G m_a m_b / r with the configured softening
+pub fn potential_energy(body_a: &Body, body_b: &Body, params: &PhysicsParams) -> f64 {
+    let d = body_a.pos - body_b.pos;
+    -params.g * body_a.mass * body_b.mass * params.softening.potential((d.x * d.x) + (d.y * d.y))
+}
+
+/// Potential per unit mass at `body_a` due to `mass` at `center`
+pub fn potential_mass_center(body_a: &Body, center: Vector2<f64>, mass: f64, params: &PhysicsParams) -> f64 {
+    let d = body_a.pos - center;
+    -params.g * mass * params.softening.potential((d.x * d.x) + (d.y * d.y))
+}
+
+/// `potential_mass_center` in three dimensions
+pub fn potential_mass_center3(body_a: &Body3, center: Vector3<f64>, mass: f64, params: &PhysicsParams) -> f64 {
+    -params.g * mass * params.softening.potential((body_a.pos - center).magnitude2())
+}
+
+/// The quadrupole correction to `force_mass_center` for a node with second moments `moments` about `center`,
+/// see `quadtree::Node::quadrupole`. Nodes are only accepted far from the body, so this term is not softened.
+pub fn force_quadrupole(body_a: &Body, center: Vector2<f64>, moments: [f64; 3], params: &PhysicsParams) -> Vector2<f64> {
+    quadrupole_field(body_a.pos - center, moments) * (params.g * body_a.test_mass())
+}
+
+/// The quadrupole part of the field of `moments` at `r` (r21) from their center, for G = 1
+pub fn quadrupole_field(r: Vector2<f64>, moments: [f64; 3]) -> Vector2<f64> {
+    let r2 = r.magnitude2();
+    let r5 = r2 * r2 * r2.sqrt();
+    let s_r = Vector2::new((moments[0] * r.x) + (moments[1] * r.y), (moments[1] * r.x) + (moments[2] * r.y));
+    let trace = moments[0] + moments[2];
+    //F = -m grad(phi), with phi from `potential_quadrupole`
+    ((s_r * 3.0) + (r * ((1.5 * trace) - (7.5 * r.dot(s_r) / r2)))) / r5
+}
+
+/// The quadrupole correction to `potential_mass_center`, per unit mass
+pub fn potential_quadrupole(body_a: &Body, center: Vector2<f64>, moments: [f64; 3], params: &PhysicsParams) -> f64 {
+    let r = body_a.pos - center;
+    let r2 = r.magnitude2();
+    let r3 = r2 * r2.sqrt();
+    let s_r = Vector2::new((moments[0] * r.x) + (moments[1] * r.y), (moments[1] * r.x) + (moments[2] * r.y));
+    let trace = moments[0] + moments[2];
+    -params.g * ((1.5 * r.dot(s_r) / (r2 * r3)) - (0.5 * trace / r3))
+}
+
+/// Explicit Euler step, see `integrator::ExplicitEuler`
+pub fn apply_force(body_a: &mut Body, params: &PhysicsParams){
     // F = mA -> A = F/m
-    body_a.acceleration = body_a.force/body_a.mass;
-    body_a.pos += body_a.velocity * DT;
-    body_a.velocity += body_a.acceleration * DT;
+    body_a.acceleration = body_a.force/body_a.test_mass();
+    body_a.pos += body_a.velocity * params.dt;
+    body_a.velocity += body_a.acceleration * params.dt;
     //there must be a better way
     body_a.force.x = 0.0f64;
     body_a.force.y = 0.0f64;
 }
 
+#[cfg(test)]
+mod tests{
+    use cgmath::{InnerSpace, Vector2};
+    use crate::body::Body;
+    use crate::gravity::{force_mass_center, force_quadrupole, potential_mass_center, potential_quadrupole, PhysicsParams, UnitSystem, G_SI};
+    use crate::softening::Softening;
 
+    #[test]
+    fn quadrupole_expansion_matches_two_masses(){
+        //two unit masses at (+-1, 0) seen from far away
+        let params = PhysicsParams::new(1.0, 0.001, Softening::None { min_distance: 0.0 });
+        let center = Vector2::new(0.0, 0.0);
+        let moments = [2.0, 0.0, 0.0];
+        for pos in [Vector2::new(20.0, 0.0), Vector2::new(0.0, 20.0), Vector2::new(-12.0, 16.0)]{
+            let body = Body::with_mass_and_pos(1.0, pos);
+            let exact = force_mass_center(&body, Vector2::new(1.0, 0.0), 1.0, &params) + force_mass_center(&body, Vector2::new(-1.0, 0.0), 1.0, &params);
+            let monopole = force_mass_center(&body, center, 2.0, &params);
+            let quadrupole = monopole + force_quadrupole(&body, center, moments, &params);
+            assert!((quadrupole - exact).magnitude() < 0.05 * (monopole - exact).magnitude());
 
+            let exact = potential_mass_center(&body, Vector2::new(1.0, 0.0), 1.0, &params) + potential_mass_center(&body, Vector2::new(-1.0, 0.0), 1.0, &params);
+            let monopole = potential_mass_center(&body, center, 2.0, &params);
+            let quadrupole = monopole + potential_quadrupole(&body, center, moments, &params);
+            assert!((quadrupole - exact).abs() < 0.05 * (monopole - exact).abs());
+        }
+    }
 
+    #[test]
+    fn quadrupole_force_is_gradient_of_potential(){
+        let params = PhysicsParams::new(2.0, 0.001, Softening::default());
+        let center = Vector2::new(1.0, -2.0);
+        let moments = [3.0, -1.5, 0.5];
+        let step = 1e-5;
+        let body = Body::with_mass_and_pos(1.0, Vector2::new(7.0, 3.0));
+        let force = force_quadrupole(&body, center, moments, &params);
+        let potential_at = |offset: Vector2<f64>| potential_quadrupole(&Body::with_mass_and_pos(1.0, body.pos + offset), center, moments, &params);
+        let gradient = Vector2::new(
+            (potential_at(Vector2::new(step, 0.0)) - potential_at(Vector2::new(-step, 0.0))) / (2.0 * step),
+            (potential_at(Vector2::new(0.0, step)) - potential_at(Vector2::new(0.0, -step))) / (2.0 * step)
+        );
+        assert!((force + gradient).magnitude() < 1e-6 * force.magnitude());
+    }
 
+    #[test]
+    fn si_units(){
+        assert_eq!(UnitSystem::si().gravitational_constant(), G_SI);
+    }
 
+    #[test]
+    fn astronomical_units(){
+        let g = UnitSystem::astronomical().gravitational_constant();
+        let four_pi_squared = 4.0 * std::f64::consts::PI * std::f64::consts::PI;
+        assert!((g - four_pi_squared).abs() / four_pi_squared < 1e-3);
+    }
+
+    #[test]
+    fn galactic_units(){
+        let params = PhysicsParams::with_units(UnitSystem::galactic(), 0.01, Softening::Plummer { epsilon: 0.1 });
+        assert!((params.g - 4.4986e-12).abs() < 1e-15);
+        assert_eq!(params.dt, 0.01);
+    }
+}
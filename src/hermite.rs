@@ -0,0 +1,308 @@
+use cgmath::{InnerSpace, Vector2, Zero};
+use crate::body::Body;
+use crate::gravity::PhysicsParams;
+use crate::simulation::pairwise_jerks;
+
+/// The fourth order Hermite predictor-corrector (Makino & Aarseth 1992) for few-body problems, stepped with
+/// `Simulation::update_hermite`. Forces and jerks come from `simulation::pairwise_jerks`, so it is always
+/// gravity: `Simulation` refuses any other `law` and any `external` field.
+///
+/// `params.dt` is split into shared substeps of `eta` times the shortest |a| / |j| of any body, the last one
+/// cut short so the step ends exactly at `params.dt`. Every substep takes `iterations` force evaluations.
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub struct Hermite {
+    /// Accuracy parameter of the substep, smaller is more accurate
+    pub eta: f64,
+    /// How often the corrector is applied, 1 is the classic PEC scheme. Iterating makes the scheme close to
+    /// time symmetric, which keeps orbits from precessing.
+    pub iterations: usize,
+    /// Substeps are never shorter than this, or than `params.dt` times the machine epsilon, so that bodies
+    /// falling onto each other without softening can't stall the step
+    pub min_dt: f64,
+    /// A step needing more substeps than this gives up with `HermiteError::TooManySubsteps`
+    pub max_substeps: usize
+}
+
+/// Why `Hermite` can't take or finish a step
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub enum HermiteError {
+    /// The force law is not gravity
+    NotGravity,
+    /// There are external fields, which have no jerk
+    ExternalFields,
+    /// The step ran out of substeps, the bodies are left `elapsed` into it
+    TooManySubsteps { elapsed: f64 }
+}
+
+/// What a body looked like at the start of a substep
+struct HermiteState {
+    pos: Vector2<f64>,
+    velocity: Vector2<f64>,
+    acceleration: Vector2<f64>,
+    jerk: Vector2<f64>
+}
+
+impl Hermite {
+    pub fn new(eta: f64) -> Self {
+        Self {
+            eta,
+            iterations: 2,
+            min_dt: 0.0,
+            max_substeps: 100_000
+        }
+    }
+
+    /// The substep for bodies with these accelerations and `jerks`, unbounded when nothing changes
+    pub fn timestep(&self, bodies: &[Body], jerks: &[Vector2<f64>]) -> f64 {
+        bodies.iter().zip(jerks.iter())
+            .filter(|(body, jerk)| !body.acceleration.is_zero() && !jerk.is_zero())
+            .map(|(body, jerk)| self.eta * body.acceleration.magnitude() / jerk.magnitude())
+            .fold(f64::INFINITY, f64::min)
+    }
+
+    /// Advances `bodies` by `params.dt` and returns the number of substeps taken.
+    /// Forces are cleared when the step returns, `acceleration` keeps the last evaluated value.
+    pub fn step(&self, bodies: &mut [Body], params: &PhysicsParams) -> Result<usize, HermiteError> {
+        let mut jerks = accelerations_and_jerks(bodies, params);
+        //anything shorter would not move `elapsed` on
+        let floor = self.min_dt.max(params.dt * f64::EPSILON);
+        let mut elapsed = 0.0;
+        let mut substeps = 0;
+        let result = loop {
+            if substeps == self.max_substeps {
+                break Err(HermiteError::TooManySubsteps { elapsed });
+            }
+            let remaining = params.dt - elapsed;
+            let dt = self.timestep(bodies, &jerks).max(floor).min(remaining);
+            let start: Vec<HermiteState> = bodies.iter().zip(jerks.iter()).map(|(body, jerk)| HermiteState {
+                pos: body.pos,
+                velocity: body.velocity,
+                acceleration: body.acceleration,
+                jerk: *jerk
+            }).collect();
+
+            //predict with the Taylor series, then correct with the forces and jerks at the prediction
+            for (body, state) in bodies.iter_mut().zip(start.iter()){
+                body.pos += (state.velocity * dt) + (state.acceleration * (dt * dt / 2.0)) + (state.jerk * (dt * dt * dt / 6.0));
+                body.velocity += (state.acceleration * dt) + (state.jerk * (dt * dt / 2.0));
+            }
+            for _ in 0..self.iterations.max(1){
+                jerks = accelerations_and_jerks(bodies, params);
+                for ((body, jerk), state) in bodies.iter_mut().zip(jerks.iter()).zip(start.iter()){
+                    body.velocity = state.velocity + ((state.acceleration + body.acceleration) * (dt / 2.0)) + ((state.jerk - jerk) * (dt * dt / 12.0));
+                    body.pos = state.pos + ((state.velocity + body.velocity) * (dt / 2.0)) + ((state.acceleration - body.acceleration) * (dt * dt / 12.0));
+                }
+            }
+            substeps += 1;
+
+            if dt == remaining {
+                break Ok(substeps);
+            }
+            elapsed += dt;
+        };
+        for body in bodies.iter_mut(){
+            body.force = Vector2::zero();
+        }
+        result
+    }
+}
+
+impl std::fmt::Display for HermiteError{
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result{
+        match self {
+            HermiteError::NotGravity => write!(f,"hermite only integrates gravity"),
+            HermiteError::ExternalFields => write!(f,"hermite does not take external fields"),
+            HermiteError::TooManySubsteps { elapsed } => write!(f,"hermite ran out of substeps {} into the step",elapsed)
+        }
+    }
+}
+
+impl std::error::Error for HermiteError {}
+
+/// Sets the acceleration of every body and returns their jerks
+fn accelerations_and_jerks(bodies: &mut [Body], params: &PhysicsParams) -> Vec<Vector2<f64>> {
+    let mut jerks = pairwise_jerks(bodies, params);
+    for (body, jerk) in bodies.iter_mut().zip(jerks.iter_mut()){
+        body.acceleration = body.force / body.test_mass();
+        *jerk /= body.test_mass();
+    }
+    jerks
+}
+
+#[cfg(test)]
+mod tests{
+    use std::f64::consts::PI;
+    use cgmath::{InnerSpace, Vector2};
+    use crate::body::Body;
+    use crate::external::PointMass;
+    use crate::force_law::Coulomb;
+    use crate::gravity::PhysicsParams;
+    use crate::hermite::{Hermite, HermiteError};
+    use crate::simulation::{pairwise_forces, pairwise_jerks, Simulation};
+    use crate::softening::Softening;
+
+    //a tracer on an orbit of a = 1, e = 0.5 around a unit mass, starting at apocenter, the period is 2 pi
+    fn kepler() -> Simulation {
+        let mut simulation = Simulation::new();
+        simulation.params = PhysicsParams::new(1.0, 2.0 * PI, Softening::None { min_distance: 0.0 });
+        simulation.bodies.push(Body::with_mass_and_pos(1.0, Vector2::new(0.0, 0.0)));
+        simulation.bodies.push(Body::tracer(Vector2::new(1.5, 0.0)));
+        simulation.bodies[1].velocity = Vector2::new(0.0, (1.0f64 / 3.0).sqrt());
+        simulation
+    }
+
+    //two unit masses a distance 1 apart falling onto each other, they pass at a pericenter of about 1e-6
+    fn close_encounter() -> Simulation {
+        let mut simulation = Simulation::new();
+        simulation.params = PhysicsParams::new(1.0, 1.0, Softening::None { min_distance: 0.0 });
+        simulation.bodies.push(Body::with_mass_and_pos(1.0, Vector2::new(-0.5, 0.0)));
+        simulation.bodies.push(Body::with_mass_and_pos(1.0, Vector2::new(0.5, 0.0)));
+        simulation.bodies[0].velocity = Vector2::new(0.25, -1e-3);
+        simulation.bodies[1].velocity = Vector2::new(-0.25, 1e-3);
+        simulation
+    }
+
+    fn energy(bodies: &[Body]) -> f64 {
+        let kinetic: f64 = bodies.iter().map(|body| 0.5 * body.mass * body.velocity.magnitude2()).sum();
+        kinetic - (bodies[0].mass * bodies[1].mass / (bodies[0].pos - bodies[1].pos).magnitude())
+    }
+
+    #[test]
+    fn rejects_other_force_laws(){
+        //the step is refused and nothing moves
+        let mut simulation = kepler();
+        simulation.law = Box::new(Coulomb::new(1.0));
+        let start = simulation.bodies[1];
+        assert_eq!(simulation.update_hermite(&Hermite::new(0.01)), Err(HermiteError::NotGravity));
+        assert_eq!(simulation.bodies[1].pos, start.pos);
+        assert_eq!(simulation.time, 0.0);
+    }
+
+    #[test]
+    fn rejects_external_fields(){
+        let mut simulation = kepler();
+        simulation.external.push(Box::new(PointMass { center: Vector2::new(5.0, 0.0), mass: 1.0 }));
+        let start = simulation.bodies[1];
+        assert_eq!(simulation.update_hermite(&Hermite::new(0.01)), Err(HermiteError::ExternalFields));
+        assert_eq!(simulation.bodies[1].pos, start.pos);
+        assert_eq!(simulation.time, 0.0);
+    }
+
+    #[test]
+    fn close_encounter_keeps_the_energy(){
+        let mut simulation = close_encounter();
+        let start = energy(&simulation.bodies);
+        simulation.update_hermite(&Hermite::new(0.01)).unwrap();
+        //through pericenter and back out
+        let separation = (simulation.bodies[0].pos - simulation.bodies[1].pos).magnitude();
+        assert!(separation > 0.1, "{}", separation);
+        assert!(((energy(&simulation.bodies) - start) / start).abs() < 1e-6, "{} {}", energy(&simulation.bodies), start);
+        assert!((simulation.bodies[0].pos + simulation.bodies[1].pos).magnitude() < 1e-9);
+    }
+
+    #[test]
+    fn close_encounter_is_bounded(){
+        let mut hermite = Hermite::new(0.01);
+        hermite.max_substeps = 100;
+        let mut simulation = close_encounter();
+        let error = simulation.update_hermite(&hermite).unwrap_err();
+        match error {
+            HermiteError::TooManySubsteps { elapsed } => {
+                assert!(elapsed > 0.0 && elapsed < 1.0, "{}", elapsed);
+                assert_eq!(simulation.time, elapsed);
+            }
+            _ => panic!("{:?}", error)
+        }
+        assert!(simulation.bodies.iter().all(|body| body.pos.x.is_finite() && body.force == Vector2::new(0.0, 0.0)));
+
+        //a floor on the substep takes the step in a bounded number of substeps, at the cost of accuracy
+        let mut hermite = Hermite::new(0.01);
+        hermite.min_dt = 0.01;
+        let mut simulation = close_encounter();
+        let substeps = hermite.step(&mut simulation.bodies, &simulation.params).unwrap();
+        assert!(substeps <= 100, "{}", substeps);
+        assert!(simulation.bodies.iter().all(|body| body.pos.x.is_finite()));
+    }
+
+    #[test]
+    fn jerk_is_the_rate_of_the_force(){
+        let params = PhysicsParams::new(2.0, 0.001, Softening::Plummer { epsilon: 0.3 });
+        let mut bodies: Vec<Body> = (0..5).map(|i| {
+            let mut body = Body::with_mass_and_pos(1.0 + i as f64, Vector2::new((i * i) as f64 * 0.4, i as f64 * 0.7 - 1.0));
+            body.velocity = Vector2::new(0.3 * i as f64, 1.0 - 0.5 * i as f64);
+            body
+        }).collect();
+        bodies.push(Body::tracer(Vector2::new(0.5, 0.5)));
+        let jerks = pairwise_jerks(&mut bodies, &params);
+        let step = 1e-6;
+        let mut forces: Vec<Vec<Vector2<f64>>> = Vec::new();
+        for sign in [1.0, -1.0]{
+            let mut moved: Vec<Body> = bodies.clone();
+            for body in moved.iter_mut(){
+                body.pos += body.velocity * (sign * step);
+            }
+            pairwise_forces(&mut moved, &params);
+            forces.push(moved.iter().map(|body| body.force).collect());
+        }
+        let mut naive: Vec<Body> = bodies.clone();
+        pairwise_forces(&mut naive, &params);
+        for (i, jerk) in jerks.iter().enumerate(){
+            assert_eq!(bodies[i].force, naive[i].force);
+            let difference = (forces[0][i] - forces[1][i]) / (2.0 * step);
+            assert!((difference - jerk).magnitude() < 1e-6 * jerk.magnitude(), "{:?} != {:?}", difference, jerk);
+        }
+    }
+
+    #[test]
+    fn kepler_orbit_closes(){
+        let mut simulation = kepler();
+        let hermite = Hermite::new(0.002);
+        let start = simulation.bodies[1];
+        for _ in 0..1000{
+            simulation.update_hermite(&hermite).unwrap();
+        }
+        let end = simulation.bodies[1];
+        assert!((end.pos - start.pos).magnitude() < 1e-8, "{:?}", end.pos - start.pos);
+        assert!((end.velocity - start.velocity).magnitude() < 1e-8, "{:?}", end.velocity - start.velocity);
+        assert!((simulation.time - 2000.0 * PI).abs() < 1e-9);
+        assert_eq!(simulation.bodies[0].pos, Vector2::new(0.0, 0.0));
+        let energy = |body: &Body| (0.5 * body.velocity.magnitude2()) - (1.0 / body.pos.magnitude());
+        assert!((energy(&end) - energy(&start)).abs() < 1e-10);
+    }
+
+    #[test]
+    fn iterating_the_corrector_stops_the_precession(){
+        //the Runge-Lenz vector points at pericenter
+        let runge_lenz = |body: &Body| {
+            let l = (body.pos.x * body.velocity.y) - (body.pos.y * body.velocity.x);
+            Vector2::new(body.velocity.y * l, -body.velocity.x * l) - body.pos.normalize()
+        };
+        let mut precession: Vec<f64> = Vec::new();
+        for iterations in [1, 2]{
+            let mut simulation = kepler();
+            let mut hermite = Hermite::new(0.01);
+            hermite.iterations = iterations;
+            let start = runge_lenz(&simulation.bodies[1]);
+            for _ in 0..100{
+                simulation.update_hermite(&hermite).unwrap();
+            }
+            precession.push((runge_lenz(&simulation.bodies[1]) - start).magnitude());
+        }
+        assert!(precession[1] < 0.1 * precession[0], "{:?}", precession);
+    }
+
+    #[test]
+    fn substeps_shrink_at_pericenter(){
+        let mut simulation = kepler();
+        let hermite = Hermite::new(0.02);
+        //|a| / |j| is r / v at the apses, nine times shorter at pericenter
+        simulation.params.dt = 0.2;
+        let apocenter = hermite.step(&mut simulation.bodies, &simulation.params).unwrap();
+        simulation.params.dt = PI - 0.3;
+        hermite.step(&mut simulation.bodies, &simulation.params).unwrap();
+        simulation.params.dt = 0.2;
+        let pericenter = hermite.step(&mut simulation.bodies, &simulation.params).unwrap();
+        assert!(pericenter > 5 * apocenter, "{} {}", pericenter, apocenter);
+        assert!(simulation.bodies.iter().all(|body| body.force == Vector2::new(0.0, 0.0)));
+    }
+}
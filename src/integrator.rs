@@ -0,0 +1,497 @@
+use std::ops::AddAssign;
+use cgmath::{Vector2, Vector3, VectorSpace, Zero};
+use rayon::prelude::*;
+use crate::body::{Body, Body3};
+use crate::gravity::PhysicsParams;
+
+/// Advances a set of bodies through one timestep of `params.dt`, `step` in 2D and `step3` in 3D.
+///
+/// On entry every body's `force` must hold the force at its current position.
+/// Multi-stage schemes call `forces` to re-evaluate `force` at intermediate positions, handing it the time since
+/// the start of the step the positions belong to, so time dependent fields can be sampled at the right moment.
+/// The callback overwrites the force of every body it is given.
+/// Forces are cleared when the step returns, `acceleration` keeps the last evaluated value.
+pub trait Integrator: std::fmt::Debug + Send + Sync {
+    fn step(&self, bodies: &mut [Body], params: &PhysicsParams, forces: &mut dyn FnMut(&mut [Body], f64));
+
+    fn step3(&self, bodies: &mut [Body3], params: &PhysicsParams, forces: &mut dyn FnMut(&mut [Body3], f64));
+}
+
+/// What the integrators move, implemented by `Body` and `Body3` so every scheme is written once
+pub trait Particle: Send + Sync {
+    type Vector: VectorSpace<Scalar = f64> + AddAssign + Send + Sync;
+
+    /// The state an integrator updates, borrowed all at once
+    fn motion(&mut self) -> Motion<'_, Self::Vector>;
+
+    /// The mass `force` is divided by, see `Body::test_mass`
+    fn inertia(&self) -> f64;
+}
+
+pub struct Motion<'a, V> {
+    pub pos: &'a mut V,
+    pub velocity: &'a mut V,
+    pub acceleration: &'a mut V,
+    pub force: &'a mut V,
+    /// Stepped with the same stages as the velocity at the rate `energy_rate`, the internal energy of a gas body
+    pub energy: Option<&'a mut f64>,
+    pub energy_rate: f64
+}
+
+/// First order, position is moved with the old velocity and then the velocity is updated.
+/// This is what `gravity::apply_force` has always done.
+#[derive(Debug,Copy,Clone,Default)]
+pub struct ExplicitEuler;
+
+/// Kick-drift-kick leapfrog, second order and symplectic.
+#[derive(Debug,Copy,Clone,Default)]
+pub struct Leapfrog;
+
+/// Velocity Verlet, second order and symplectic.
+#[derive(Debug,Copy,Clone,Default)]
+pub struct VelocityVerlet;
+
+/// Classical fourth order Runge-Kutta, needs three extra force evaluations per step.
+#[derive(Debug,Copy,Clone,Default)]
+pub struct RungeKutta4;
+
+/// Forest-Ruth, three leapfrog steps of dt / (2 - 2^(1/3)), the middle one backwards.
+/// Fourth order and symplectic, three force evaluations per step.
+#[derive(Debug,Copy,Clone,Default)]
+pub struct ForestRuth;
+
+/// Yoshida's sixth order composition of seven leapfrog steps (solution A of Yoshida 1990).
+/// Symplectic, seven force evaluations per step.
+#[derive(Debug,Copy,Clone,Default)]
+pub struct Yoshida6;
+
+impl Particle for Body {
+    type Vector = Vector2<f64>;
+
+    fn motion(&mut self) -> Motion<'_, Vector2<f64>> {
+        let energy = if self.is_gas() { Some(&mut self.gas.internal_energy) } else { None };
+        Motion { pos: &mut self.pos, velocity: &mut self.velocity, acceleration: &mut self.acceleration, force: &mut self.force, energy, energy_rate: self.gas.energy_rate }
+    }
+
+    fn inertia(&self) -> f64 {
+        self.test_mass()
+    }
+}
+
+impl Particle for Body3 {
+    type Vector = Vector3<f64>;
+
+    fn motion(&mut self) -> Motion<'_, Vector3<f64>> {
+        Motion { pos: &mut self.pos, velocity: &mut self.velocity, acceleration: &mut self.acceleration, force: &mut self.force, energy: None, energy_rate: 0.0 }
+    }
+
+    fn inertia(&self) -> f64 {
+        self.test_mass()
+    }
+}
+
+
+/// Runs `f` on every body. When the step is running inside a rayon thread pool
+/// (see `BarnesHutRunner::set_threads`) the bodies are spread over that pool, otherwise it stays on the calling thread.
+/// Every body is updated independently, so both paths give identical results.
+pub fn for_each_body<B: Send, F: Fn(&mut B) + Send + Sync>(bodies: &mut [B], f: F){
+    if rayon::current_thread_index().is_some() {
+        bodies.par_iter_mut().for_each(f);
+    } else {
+        bodies.iter_mut().for_each(f);
+    }
+}
+
+/// `for_each_body` for loops that also need a per-body value from a side buffer
+pub fn for_each_body_with<B: Send, T: Sync, F: Fn(&mut B, &T) + Send + Sync>(bodies: &mut [B], values: &[T], f: F){
+    if rayon::current_thread_index().is_some() {
+        bodies.par_iter_mut().zip(values.par_iter()).for_each(|(body, value)| f(body, value));
+    } else {
+        bodies.iter_mut().zip(values.iter()).for_each(|(body, value)| f(body, value));
+    }
+}
+
+fn update_acceleration<P: Particle>(bodies: &mut [P]){
+    for_each_body(bodies, |body| {
+        let inertia = body.inertia();
+        let motion = body.motion();
+        *motion.acceleration = *motion.force / inertia;
+    });
+}
+
+fn clear_forces<P: Particle>(bodies: &mut [P]){
+    for_each_body(bodies, |body| *body.motion().force = P::Vector::zero());
+}
+
+fn kick<P: Particle>(bodies: &mut [P], dt: f64){
+    for_each_body(bodies, |body| {
+        let motion = body.motion();
+        *motion.velocity += *motion.acceleration * dt;
+        if let Some(energy) = motion.energy {
+            *energy += motion.energy_rate * dt;
+        }
+    });
+}
+
+fn drift<P: Particle>(bodies: &mut [P], dt: f64){
+    for_each_body(bodies, |body| {
+        let motion = body.motion();
+        *motion.pos += *motion.velocity * dt;
+    });
+}
+
+fn explicit_euler<P: Particle>(bodies: &mut [P], params: &PhysicsParams){
+    update_acceleration(bodies);
+    drift(bodies, params.dt);
+    kick(bodies, params.dt);
+    clear_forces(bodies);
+}
+
+fn leapfrog<P: Particle>(bodies: &mut [P], params: &PhysicsParams, forces: &mut dyn FnMut(&mut [P], f64)){
+    compose(bodies, params, forces, &[1.0]);
+}
+
+/// Kick-drift-kick leapfrog steps of `weights` times dt in a row, the forces of one step are the start of the next.
+/// Symmetric weights give a time reversible scheme.
+fn compose<P: Particle>(bodies: &mut [P], params: &PhysicsParams, forces: &mut dyn FnMut(&mut [P], f64), weights: &[f64]){
+    update_acceleration(bodies);
+    let mut elapsed = 0.0;
+    for weight in weights{
+        let dt = params.dt * weight;
+        elapsed += dt;
+        kick(bodies, dt/2.0);
+        drift(bodies, dt);
+        forces(bodies, elapsed);
+        update_acceleration(bodies);
+        kick(bodies, dt/2.0);
+    }
+    clear_forces(bodies);
+}
+
+fn velocity_verlet<P: Particle>(bodies: &mut [P], params: &PhysicsParams, forces: &mut dyn FnMut(&mut [P], f64)){
+    let dt = params.dt;
+    update_acceleration(bodies);
+    let old_rates: Vec<(P::Vector, f64)> = bodies.iter_mut().map(|body| {
+        let motion = body.motion();
+        (*motion.acceleration, motion.energy_rate)
+    }).collect();
+    for_each_body(bodies, |body| {
+        let motion = body.motion();
+        *motion.pos += (*motion.velocity * dt) + (*motion.acceleration * (dt * dt / 2.0));
+    });
+    forces(bodies, dt);
+    update_acceleration(bodies);
+    for_each_body_with(bodies, &old_rates, |body, (old_acceleration, old_energy_rate)| {
+        let motion = body.motion();
+        *motion.velocity += (*old_acceleration + *motion.acceleration) * (dt / 2.0);
+        if let Some(energy) = motion.energy {
+            *energy += (old_energy_rate + motion.energy_rate) * (dt / 2.0);
+        }
+    });
+    clear_forces(bodies);
+}
+
+fn runge_kutta4<P: Particle>(bodies: &mut [P], params: &PhysicsParams, forces: &mut dyn FnMut(&mut [P], f64)){
+    let dt = params.dt;
+    //the start state and the weighted sum of derivatives, per body
+    let mut states: Vec<RungeKuttaState<P::Vector>> = bodies.iter_mut().map(|body| {
+        let motion = body.motion();
+        RungeKuttaState {
+            start_pos: *motion.pos,
+            start_vel: *motion.velocity,
+            start_energy: motion.energy.as_deref().copied().unwrap_or(0.0),
+            sum_pos: P::Vector::zero(),
+            sum_vel: P::Vector::zero(),
+            sum_energy: 0.0
+        }
+    }).collect();
+
+    //each stage samples the derivative (velocity, acceleration) at the current state,
+    //adds it to the weighted sum and moves the state to the next sample point
+    let stages: [(f64, f64); 4] = [(1.0, dt/2.0), (2.0, dt/2.0), (2.0, dt), (1.0, 0.0)];
+    for (stage, (weight, offset)) in stages.iter().enumerate(){
+        if stage > 0 {
+            forces(bodies, stages[stage - 1].1);
+        }
+        update_acceleration(bodies);
+        for (body, state) in bodies.iter_mut().zip(states.iter_mut()){
+            let motion = body.motion();
+            state.sum_pos += *motion.velocity * *weight;
+            state.sum_vel += *motion.acceleration * *weight;
+            state.sum_energy += motion.energy_rate * *weight;
+        }
+        for_each_body_with(bodies, &states, |body, state| {
+            let motion = body.motion();
+            let velocity = *motion.velocity;
+            *motion.pos = state.start_pos + velocity * *offset;
+            *motion.velocity = state.start_vel + *motion.acceleration * *offset;
+            if let Some(energy) = motion.energy {
+                *energy = state.start_energy + motion.energy_rate * *offset;
+            }
+        });
+    }
+
+    for_each_body_with(bodies, &states, |body, state| {
+        let motion = body.motion();
+        *motion.pos = state.start_pos + state.sum_pos * (dt / 6.0);
+        *motion.velocity = state.start_vel + state.sum_vel * (dt / 6.0);
+        if let Some(energy) = motion.energy {
+            *energy = state.start_energy + state.sum_energy * (dt / 6.0);
+        }
+    });
+    clear_forces(bodies);
+}
+
+fn forest_ruth<P: Particle>(bodies: &mut [P], params: &PhysicsParams, forces: &mut dyn FnMut(&mut [P], f64)){
+    let theta = 1.0 / (2.0 - 2.0f64.cbrt());
+    compose(bodies, params, forces, &[theta, 1.0 - (2.0 * theta), theta]);
+}
+
+fn yoshida6<P: Particle>(bodies: &mut [P], params: &PhysicsParams, forces: &mut dyn FnMut(&mut [P], f64)){
+    let (w1, w2, w3) = (0.784_513_610_477_557_3, 0.235_573_213_359_358_1, -1.177_679_984_178_871);
+    let w0 = 1.0 - (2.0 * (w1 + w2 + w3));
+    compose(bodies, params, forces, &[w1, w2, w3, w0, w3, w2, w1]);
+}
+
+impl Integrator for ExplicitEuler {
+    fn step(&self, bodies: &mut [Body], params: &PhysicsParams, _forces: &mut dyn FnMut(&mut [Body], f64)){
+        explicit_euler(bodies, params);
+    }
+
+    fn step3(&self, bodies: &mut [Body3], params: &PhysicsParams, _forces: &mut dyn FnMut(&mut [Body3], f64)){
+        explicit_euler(bodies, params);
+    }
+}
+
+impl Integrator for Leapfrog {
+    fn step(&self, bodies: &mut [Body], params: &PhysicsParams, forces: &mut dyn FnMut(&mut [Body], f64)){
+        leapfrog(bodies, params, forces);
+    }
+
+    fn step3(&self, bodies: &mut [Body3], params: &PhysicsParams, forces: &mut dyn FnMut(&mut [Body3], f64)){
+        leapfrog(bodies, params, forces);
+    }
+}
+
+impl Integrator for VelocityVerlet {
+    fn step(&self, bodies: &mut [Body], params: &PhysicsParams, forces: &mut dyn FnMut(&mut [Body], f64)){
+        velocity_verlet(bodies, params, forces);
+    }
+
+    fn step3(&self, bodies: &mut [Body3], params: &PhysicsParams, forces: &mut dyn FnMut(&mut [Body3], f64)){
+        velocity_verlet(bodies, params, forces);
+    }
+}
+
+impl Integrator for RungeKutta4 {
+    fn step(&self, bodies: &mut [Body], params: &PhysicsParams, forces: &mut dyn FnMut(&mut [Body], f64)){
+        runge_kutta4(bodies, params, forces);
+    }
+
+    fn step3(&self, bodies: &mut [Body3], params: &PhysicsParams, forces: &mut dyn FnMut(&mut [Body3], f64)){
+        runge_kutta4(bodies, params, forces);
+    }
+}
+
+impl Integrator for ForestRuth {
+    fn step(&self, bodies: &mut [Body], params: &PhysicsParams, forces: &mut dyn FnMut(&mut [Body], f64)){
+        forest_ruth(bodies, params, forces);
+    }
+
+    fn step3(&self, bodies: &mut [Body3], params: &PhysicsParams, forces: &mut dyn FnMut(&mut [Body3], f64)){
+        forest_ruth(bodies, params, forces);
+    }
+}
+
+impl Integrator for Yoshida6 {
+    fn step(&self, bodies: &mut [Body], params: &PhysicsParams, forces: &mut dyn FnMut(&mut [Body], f64)){
+        yoshida6(bodies, params, forces);
+    }
+
+    fn step3(&self, bodies: &mut [Body3], params: &PhysicsParams, forces: &mut dyn FnMut(&mut [Body3], f64)){
+        yoshida6(bodies, params, forces);
+    }
+}
+
+struct RungeKuttaState<V> {
+    start_pos: V,
+    start_vel: V,
+    start_energy: f64,
+    sum_pos: V,
+    sum_vel: V,
+    sum_energy: f64
+}
+
+#[cfg(test)]
+mod tests{
+    use cgmath::{InnerSpace, Vector2};
+    use crate::body::Body;
+    use crate::gravity::PhysicsParams;
+    use crate::softening::Softening;
+    use crate::integrator::{ExplicitEuler, ForestRuth, Integrator, Leapfrog, RungeKutta4, VelocityVerlet, Yoshida6};
+    use crate::simulation::Simulation;
+
+    //unit mass on a unit spring, x(t) = cos(t)
+    fn spring(bodies: &mut [Body]){
+        for body in bodies.iter_mut(){
+            body.force = -body.pos * body.mass;
+        }
+    }
+
+    fn run(integrator: &dyn Integrator, steps: usize, dt: f64) -> Body {
+        let mut bodies = vec![Body::with_mass_and_pos(1.0, Vector2::new(1.0, 0.0))];
+        let params = PhysicsParams::new(1.0, dt, Softening::default());
+        for _ in 0..steps{
+            spring(&mut bodies);
+            integrator.step(&mut bodies, &params, &mut |bodies: &mut [Body], _| spring(bodies));
+        }
+        bodies[0]
+    }
+
+    fn energy(body: &Body) -> f64 {
+        0.5 * body.velocity.magnitude2() + 0.5 * body.pos.magnitude2()
+    }
+
+    #[test]
+    fn euler_matches_apply_force(){
+        let body = run(&ExplicitEuler, 1, 0.1);
+        assert_eq!(body.pos, Vector2::new(1.0, 0.0));
+        assert_eq!(body.velocity, Vector2::new(-0.1, 0.0));
+    }
+
+    #[test]
+    fn tracer_acceleration_is_its_force(){
+        let mut bodies = vec![Body::tracer(Vector2::new(1.0, 0.0))];
+        let params = PhysicsParams::new(1.0, 0.1, Softening::default());
+        bodies[0].force = Vector2::new(-1.0, 0.5);
+        ExplicitEuler.step(&mut bodies, &params, &mut |bodies: &mut [Body], _| spring(bodies));
+        assert_eq!(bodies[0].velocity, Vector2::new(-0.1, 0.05));
+    }
+
+    #[test]
+    fn rk4_follows_analytic_solution(){
+        let steps = 1000;
+        let dt = 0.01;
+        let body = run(&RungeKutta4, steps, dt);
+        let t = steps as f64 * dt;
+        assert!((body.pos.x - t.cos()).abs() < 1e-9);
+        assert!((body.velocity.x + t.sin()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn symplectic_schemes_conserve_energy(){
+        for integrator in [&Leapfrog as &dyn Integrator, &VelocityVerlet]{
+            let body = run(integrator, 100000, 0.01);
+            assert!((energy(&body) - 0.5).abs() < 1e-4, "{:?} drifted to {}", integrator, energy(&body));
+        }
+        let body = run(&ExplicitEuler, 100000, 0.01);
+        assert!(energy(&body) > 1.0);
+    }
+
+    #[test]
+    fn second_order_schemes_agree(){
+        let leapfrog = run(&Leapfrog, 500, 0.01);
+        let verlet = run(&VelocityVerlet, 500, 0.01);
+        assert!((leapfrog.pos - verlet.pos).magnitude() < 1e-12);
+        assert!((leapfrog.velocity - verlet.velocity).magnitude() < 1e-12);
+    }
+
+    #[test]
+    fn compositions_reach_their_order(){
+        //halving the step cuts the error at t = 10 by 2^order
+        for (integrator, order) in [(&Leapfrog as &dyn Integrator, 2), (&ForestRuth, 4), (&Yoshida6, 6)]{
+            let errors: Vec<f64> = [0.2, 0.1].iter().map(|dt| {
+                let body = run(integrator, (10.0 / dt) as usize, *dt);
+                (body.pos.x - 10.0f64.cos()).abs()
+            }).collect();
+            let measured = (errors[0] / errors[1]).log2();
+            assert!((measured - order as f64).abs() < 0.3, "{:?} is of order {}", integrator, measured);
+        }
+    }
+
+    //unit mass around a fixed unit mass at the origin
+    fn kepler(bodies: &mut [Body]){
+        for body in bodies.iter_mut(){
+            body.force = -body.pos / body.pos.magnitude().powi(3);
+        }
+    }
+
+    #[test]
+    fn compositions_reach_their_order_on_a_kepler_orbit(){
+        //a = 1, e = 0.5 from apocenter, after one period of 2 pi it is back where it started.
+        //the sub-steps of a composition only cancel their errors when the force is nonlinear
+        for (integrator, order) in [(&Leapfrog as &dyn Integrator, 2), (&ForestRuth, 4), (&Yoshida6, 6)]{
+            let errors: Vec<f64> = [400, 800].iter().map(|steps| {
+                let mut bodies = vec![Body::with_mass_and_pos(1.0, Vector2::new(1.5, 0.0))];
+                bodies[0].velocity = Vector2::new(0.0, (1.0f64 / 3.0).sqrt());
+                let params = PhysicsParams::new(1.0, 2.0 * std::f64::consts::PI / *steps as f64, Softening::default());
+                for _ in 0..*steps{
+                    kepler(&mut bodies);
+                    integrator.step(&mut bodies, &params, &mut |bodies: &mut [Body], _| kepler(bodies));
+                }
+                (bodies[0].pos - Vector2::new(1.5, 0.0)).magnitude()
+            }).collect();
+            let measured = (errors[0] / errors[1]).log2();
+            assert!((measured - order as f64).abs() < 0.1, "{:?} is of order {}", integrator, measured);
+        }
+    }
+
+    #[test]
+    fn compositions_are_time_reversible(){
+        for integrator in [&ForestRuth as &dyn Integrator, &Yoshida6, &Leapfrog]{
+            let mut bodies = vec![Body::with_mass_and_pos(1.0, Vector2::new(1.0, 0.5))];
+            bodies[0].velocity = Vector2::new(0.2, -0.3);
+            let params = PhysicsParams::new(1.0, 0.1, Softening::default());
+            for _ in 0..1000{
+                spring(&mut bodies);
+                integrator.step(&mut bodies, &params, &mut |bodies: &mut [Body], _| spring(bodies));
+            }
+            bodies[0].velocity = -bodies[0].velocity;
+            for _ in 0..1000{
+                spring(&mut bodies);
+                integrator.step(&mut bodies, &params, &mut |bodies: &mut [Body], _| spring(bodies));
+            }
+            assert!((bodies[0].pos - Vector2::new(1.0, 0.5)).magnitude() < 1e-12, "{:?} ended at {:?}", integrator, bodies[0].pos);
+            assert!((bodies[0].velocity + Vector2::new(0.2, -0.3)).magnitude() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn internal_energy_takes_the_velocity_stages(){
+        //du/dt is -dv/dt along x, so u + v.x only stays put if both are stepped the same way
+        let heating = |bodies: &mut [Body]| {
+            spring(bodies);
+            for body in bodies.iter_mut(){
+                body.gas.energy_rate = body.pos.x;
+            }
+        };
+        for integrator in [&ExplicitEuler as &dyn Integrator, &Leapfrog, &VelocityVerlet, &RungeKutta4, &ForestRuth, &Yoshida6]{
+            let mut bodies = vec![Body::gas(1.0, Vector2::new(1.0, 0.0), 2.0)];
+            let params = PhysicsParams::new(1.0, 0.1, Softening::default());
+            for _ in 0..100{
+                heating(&mut bodies);
+                integrator.step(&mut bodies, &params, &mut |bodies: &mut [Body], _| heating(bodies));
+            }
+            let body = bodies[0];
+            assert!((body.gas.internal_energy + body.velocity.x - 2.0).abs() < 1e-12, "{:?} {} {}", integrator, body.gas.internal_energy, body.velocity.x);
+            assert!((body.gas.internal_energy - 2.0).abs() > 0.1);
+        }
+    }
+
+    #[test]
+    fn massless_body_moves_like_a_tracer(){
+        for integrator in [Box::new(ExplicitEuler) as Box<dyn Integrator>, Box::new(Leapfrog), Box::new(RungeKutta4)]{
+            let mut simulation = Simulation::with_integrator(integrator);
+            simulation.bodies.push(Body::with_mass_and_pos(1.0, Vector2::new(0.0, 0.0)));
+            simulation.bodies.push(Body::with_mass_and_pos(0.0, Vector2::new(2.0, 0.0)));
+            simulation.bodies.push(Body::tracer(Vector2::new(2.0, 0.0)));
+            for _ in 0..10{
+                simulation.update();
+            }
+            let (massless, tracer) = (simulation.bodies[1], simulation.bodies[2]);
+            assert!(massless.pos.x < 2.0, "{:?}", massless.pos);
+            assert_eq!(massless.pos, tracer.pos);
+            assert_eq!(massless.velocity, tracer.velocity);
+        }
+    }
+}
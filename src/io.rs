@@ -0,0 +1,238 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Error, ErrorKind, Write};
+use std::path::Path;
+use cgmath::Vector2;
+use crate::body;
+use crate::body::{Body, BodyKind};
+
+/// Bodies are stored one per line as whitespace separated columns:
+///
+/// `id tag mass x y vx vy charge circulation kind`
+///
+/// `tag` is `-` for untagged bodies and `kind` is one of `massive`, `tracer` or `gas`. Lines with only
+/// `mass x y vx vy` get fresh ids, which makes it easy to write starting values by hand. Lines written
+/// before the charge, the circulation or the kind were stored stop after `vy`, `charge` or `circulation`,
+/// they are still read, the missing values are 0 and bodies of no mass in them are tracers.
+/// Empty lines and lines starting with `#` are skipped.
+/// Only the kind of gas bodies is stored, they come back with the default `Gas` state.
+pub const HEADER: &str = "# id tag mass x y vx vy charge circulation kind";
+
+pub fn write_bodies<W: Write>(writer: &mut W, bodies: &[Body]) -> std::io::Result<()> {
+    writeln!(writer, "{}", HEADER)?;
+    for body in bodies{
+        let tag = match body.tag {
+            Some(tag) => tag.to_string(),
+            None => "-".to_string()
+        };
+        writeln!(writer, "{} {} {:e} {:e} {:e} {:e} {:e} {:e} {:e} {}", body.id, tag, body.mass, body.pos.x, body.pos.y, body.velocity.x, body.velocity.y, body.charge, body.circulation, kind_name(body.kind))?;
+    }
+    Ok(())
+}
+
+/// Reads bodies written by `write_bodies`, ids are kept and never handed out again afterwards.
+/// An id that repeats one read before it, or one handed out to an earlier line without an id, is an error,
+/// the forces leave out the other body of the same id.
+pub fn read_bodies<R: BufRead>(reader: R) -> std::io::Result<Vec<Body>> {
+    let mut bodies: Vec<Body> = Vec::new();
+    let mut ids: HashSet<u64> = HashSet::new();
+    for (number, line) in reader.lines().enumerate(){
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let columns: Vec<&str> = line.split_whitespace().collect();
+        let (id, tag, values) = match columns.len() {
+            7..=10 => (Some(parse::<u64>(columns[0], number)?), parse_tag(columns[1], number)?, &columns[2..]),
+            5 => (None, None, &columns[..]),
+            count => return Err(invalid(number, &format!("expected 5 or 7 to 10 columns, found {}", count)))
+        };
+        let mut body = Body::with_mass_and_pos(parse(values[0], number)?, Vector2::new(parse(values[1], number)?, parse(values[2], number)?));
+        body.velocity = Vector2::new(parse(values[3], number)?, parse(values[4], number)?);
+        if let Some(charge) = values.get(5) {
+            body.charge = parse(charge, number)?;
+        }
+        if let Some(circulation) = values.get(6) {
+            body.circulation = parse(circulation, number)?;
+        }
+        body.tag = tag;
+        body.kind = match values.get(7) {
+            Some(kind) => parse_kind(kind, number)?,
+            None if body.mass == 0.0 => BodyKind::Tracer,
+            None => BodyKind::Massive
+        };
+        if let Some(id) = id {
+            if !body::reserve_ids(id) {
+                return Err(invalid(number, &format!("id {} leaves no ids to hand out", id)));
+            }
+            body.id = id;
+        }
+        if !ids.insert(body.id) {
+            return Err(invalid(number, &format!("id {} is already taken", body.id)));
+        }
+        bodies.push(body);
+    }
+    Ok(bodies)
+}
+
+pub fn save_bodies<P: AsRef<Path>>(path: P, bodies: &[Body]) -> std::io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    write_bodies(&mut writer, bodies)?;
+    writer.flush()
+}
+
+pub fn load_bodies<P: AsRef<Path>>(path: P) -> std::io::Result<Vec<Body>> {
+    read_bodies(BufReader::new(File::open(path)?))
+}
+
+fn parse<T: std::str::FromStr>(value: &str, line: usize) -> std::io::Result<T> {
+    value.parse::<T>().map_err(|_| invalid(line, &format!("could not parse '{}'", value)))
+}
+
+fn parse_tag(value: &str, line: usize) -> std::io::Result<Option<u32>> {
+    match value {
+        "-" => Ok(None),
+        _ => Ok(Some(parse(value, line)?))
+    }
+}
+
+fn kind_name(kind: BodyKind) -> &'static str {
+    match kind {
+        BodyKind::Massive => "massive",
+        BodyKind::Tracer => "tracer",
+        BodyKind::Gas => "gas"
+    }
+}
+
+fn parse_kind(value: &str, line: usize) -> std::io::Result<BodyKind> {
+    match value {
+        "massive" => Ok(BodyKind::Massive),
+        "tracer" => Ok(BodyKind::Tracer),
+        "gas" => Ok(BodyKind::Gas),
+        _ => Err(invalid(line, &format!("unknown kind '{}'", value)))
+    }
+}
+
+fn invalid(line: usize, message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("line {}: {}", line + 1, message))
+}
+
+#[cfg(test)]
+mod tests{
+    use std::io::ErrorKind;
+    use cgmath::Vector2;
+    use crate::body::{Body, BodyKind};
+    use crate::io::{read_bodies, write_bodies};
+
+    #[test]
+    fn round_trip_keeps_ids(){
+        let mut bodies: Vec<Body> = vec![
+            Body::with_mass_and_pos(2.5, Vector2::new(1.0, -3.25)).with_tag(4),
+            Body::with_mass_and_pos(0.1, Vector2::new(1e-7, 6.0e12)),
+            Body::tracer(Vector2::new(-4.0, 8.5))
+        ];
+        bodies[1].velocity = Vector2::new(-0.3, 1.0 / 3.0);
+        let mut buffer: Vec<u8> = Vec::new();
+        write_bodies(&mut buffer, &bodies).unwrap();
+        let loaded = read_bodies(buffer.as_slice()).unwrap();
+        assert_eq!(loaded.len(), 3);
+        for (original, copy) in bodies.iter().zip(loaded.iter()){
+            assert_eq!(original.id, copy.id);
+            assert_eq!(original.tag, copy.tag);
+            assert_eq!(original.mass, copy.mass);
+            assert_eq!(original.pos, copy.pos);
+            assert_eq!(original.velocity, copy.velocity);
+            assert_eq!(original.kind, copy.kind);
+        }
+    }
+
+    #[test]
+    fn loaded_ids_are_not_reused(){
+        let loaded = read_bodies("# starting values\n900000 - 1 0 0 0 0\n\n1 2 3 4 5\n".as_bytes()).unwrap();
+        assert_eq!(loaded[0].id, 900000);
+        assert_eq!(loaded[1].mass, 1.0);
+        assert_eq!(loaded[1].velocity, Vector2::new(4.0, 5.0));
+        assert!(loaded[1].id > 900000);
+        assert!(Body::new().id > 900000);
+    }
+
+    #[test]
+    fn repeated_ids_are_errors(){
+        let error = read_bodies("5 - 1 0 0 0 0\n6 - 1 0 0 0 0\n5 - 2 1 0 0 0\n".as_bytes()).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert!(error.to_string().starts_with("line 3:"), "{}", error);
+    }
+
+    #[test]
+    fn malformed_lines_are_errors(){
+        assert!(read_bodies("1 2 3".as_bytes()).is_err());
+        assert!(read_bodies("1 x 1 0 0 0 0".as_bytes()).is_err());
+        assert!(read_bodies("1 2 3 4 five".as_bytes()).is_err());
+        assert!(read_bodies("1 - 1 0 0 0 0 x".as_bytes()).is_err());
+        assert!(read_bodies("1 - 1 0 0 0 0 0 0 dust".as_bytes()).is_err());
+        assert!(read_bodies("1 - 1 0 0 0 0 0 0 gas 0".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn round_trip_keeps_charge(){
+        let bodies: Vec<Body> = vec![
+            Body::with_mass_and_pos(1.0, Vector2::new(0.0, 0.0)).with_charge(-1.5e-3),
+            Body::with_mass_and_pos(2.0, Vector2::new(1.0, 0.0))
+        ];
+        let mut buffer: Vec<u8> = Vec::new();
+        write_bodies(&mut buffer, &bodies).unwrap();
+        let loaded = read_bodies(buffer.as_slice()).unwrap();
+        assert_eq!(loaded[0].charge, -1.5e-3);
+        assert_eq!(loaded[1].charge, 0.0);
+        //the older columns still load, without a charge
+        let legacy = read_bodies("7 - 1 0 0 0 0\n1 0 0 0 0\n".as_bytes()).unwrap();
+        assert_eq!(legacy[0].id, 7);
+        assert!(legacy.iter().all(|body| body.charge == 0.0));
+    }
+
+    #[test]
+    fn round_trip_keeps_circulation(){
+        let bodies: Vec<Body> = vec![
+            Body::with_mass_and_pos(0.0, Vector2::new(0.0, 0.0)).with_circulation(0.25),
+            Body::with_mass_and_pos(0.0, Vector2::new(1.0, 0.0)).with_circulation(-1.0 / 3.0)
+        ];
+        let mut buffer: Vec<u8> = Vec::new();
+        write_bodies(&mut buffer, &bodies).unwrap();
+        let loaded = read_bodies(buffer.as_slice()).unwrap();
+        assert_eq!(loaded[0].circulation, 0.25);
+        assert_eq!(loaded[1].circulation, -1.0 / 3.0);
+        let legacy = read_bodies("8 - 1 0 0 0 0 2\n".as_bytes()).unwrap();
+        assert_eq!(legacy[0].charge, 2.0);
+        assert_eq!(legacy[0].circulation, 0.0);
+    }
+
+    #[test]
+    fn round_trip_keeps_the_kind(){
+        let mut bodies: Vec<Body> = vec![
+            Body::with_mass_and_pos(0.0, Vector2::new(0.0, 0.0)).with_circulation(1.0),
+            Body::tracer(Vector2::new(1.0, 0.0)),
+            Body::gas(0.0, Vector2::new(2.0, 0.0), 1.0),
+            Body::tracer(Vector2::new(3.0, 0.0))
+        ];
+        bodies[3].mass = 4.0;
+        let mut buffer: Vec<u8> = Vec::new();
+        write_bodies(&mut buffer, &bodies).unwrap();
+        let loaded = read_bodies(buffer.as_slice()).unwrap();
+        for (original, copy) in bodies.iter().zip(loaded.iter()){
+            assert_eq!(original.kind, copy.kind);
+            assert_eq!(original.mass, copy.mass);
+        }
+        assert_eq!(loaded[0].kind, BodyKind::Massive);
+        //without the column a body of no mass is still taken for a tracer
+        let legacy = read_bodies("0 0 0 0 0\n9 - 0 0 0 0 0 0 1\n".as_bytes()).unwrap();
+        assert!(legacy.iter().all(|body| body.kind == BodyKind::Tracer));
+    }
+
+    #[test]
+    fn last_id_is_an_error(){
+        let line = format!("{} - 1 0 0 0 0", u64::MAX);
+        assert_eq!(read_bodies(line.as_bytes()).unwrap_err().kind(), ErrorKind::InvalidData);
+        assert!(Body::new().id < u64::MAX);
+    }
+}
@@ -1,8 +1,26 @@
 pub mod body;
 pub mod quadtree;
+pub mod octree;
 pub mod gravity;
+pub mod force_law;
+pub mod softening;
 pub mod simulation;
 pub mod bh_runner;
+pub mod solver;
+pub mod fmm;
+pub mod fft;
+pub mod pm;
+pub mod boundary;
+pub mod collision;
+pub mod external;
+pub mod vortex;
+pub mod sph;
+pub mod integrator;
+pub mod timestep;
+pub mod hermite;
+pub mod diagnostics;
+pub mod accuracy;
+pub mod io;
 
 pub mod canvas;
 // pub mod barnes_hut_runner;
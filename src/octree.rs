@@ -0,0 +1,285 @@
+use cgmath::Vector3;
+use crate::body::Body3;
+
+/// The root is always the first node of the arena
+pub const ROOT: usize = 0;
+
+const MIN_SIZE: f64 = 1.0;
+
+/// An axis aligned box, the 3D counterpart of `quadtree::Rectangle`
+#[derive(PartialEq,Debug,Clone,Copy)]
+pub struct Cuboid {
+    pub min: Vector3<f64>,
+    pub max: Vector3<f64>
+}
+
+/// One box of the tree. Leaves hold indices into the body slice the tree was built from,
+/// internal nodes hold the arena indices of their eight children, numbered by `Cuboid::octant`.
+#[derive(Debug,Clone)]
+pub struct OctreeNode {
+    pub boundaries: Cuboid,
+    pub children: Option<[usize; 8]>,
+    pub bodies: Vec<usize>,
+    pub center_of_mass: Option<Vector3<f64>>,
+    pub total_mass: f64
+}
+
+/// The 3D counterpart of `quadtree::Quadtree`, stored the same way as a flat arena of nodes
+#[derive(Debug)]
+pub struct Octree {
+    pub boundaries: Cuboid,
+    pub limit: usize,
+    pub nodes: Vec<OctreeNode>,
+    spare: Vec<Vec<usize>>
+}
+
+impl Cuboid {
+    pub fn new(min: Vector3<f64>, max: Vector3<f64>) -> Self {
+        Self {
+            min,
+            max
+        }
+    }
+
+    pub fn within(&self, pos: Vector3<f64>) -> bool {
+        pos.x >= self.min.x && pos.x < self.max.x &&
+        pos.y >= self.min.y && pos.y < self.max.y &&
+        pos.z >= self.min.z && pos.z < self.max.z
+    }
+
+    /// `within` including the far faces, where a body at the largest coordinate sits
+    pub fn contains(&self, pos: Vector3<f64>) -> bool {
+        pos.x >= self.min.x && pos.x <= self.max.x &&
+        pos.y >= self.min.y && pos.y <= self.max.y &&
+        pos.z >= self.min.z && pos.z <= self.max.z
+    }
+
+    pub fn midpoint(&self) -> Vector3<f64> {
+        self.min + ((self.max - self.min) / 2.0f64)
+    }
+
+    pub fn width(&self) -> f64 {
+        self.max.x - self.min.x
+    }
+
+    /// Distance from `pos` to the farthest corner
+    pub fn max_extent_from(&self, pos: Vector3<f64>) -> f64 {
+        let x = (pos.x - self.min.x).abs().max((self.max.x - pos.x).abs());
+        let y = (pos.y - self.min.y).abs().max((self.max.y - pos.y).abs());
+        let z = (pos.z - self.min.z).abs().max((self.max.z - pos.z).abs());
+        ((x * x) + (y * y) + (z * z)).sqrt()
+    }
+
+    /// Which eighth `pos` falls into, bit 0 is set above the midpoint in x, bit 1 in y and bit 2 in z.
+    /// Positions on the midpoint go to the lower half, like `quadtree::Node::subtree_index`.
+    pub fn octant(&self, pos: Vector3<f64>) -> usize {
+        let midpoint = self.midpoint();
+        ((pos.x > midpoint.x) as usize) | (((pos.y > midpoint.y) as usize) << 1) | (((pos.z > midpoint.z) as usize) << 2)
+    }
+
+    /// The eight children in `octant` order
+    pub fn suboctants(&self) -> [Cuboid; 8] {
+        let midpoint = self.midpoint();
+        std::array::from_fn(|octant| {
+            let pick = |bit: usize, low: f64, mid: f64, high: f64| if octant & bit == 0 { (low, mid) } else { (mid, high) };
+            let (min_x, max_x) = pick(1, self.min.x, midpoint.x, self.max.x);
+            let (min_y, max_y) = pick(2, self.min.y, midpoint.y, self.max.y);
+            let (min_z, max_z) = pick(4, self.min.z, midpoint.z, self.max.z);
+            Cuboid::new(Vector3::new(min_x, min_y, min_z), Vector3::new(max_x, max_y, max_z))
+        })
+    }
+}
+
+impl OctreeNode {
+    pub fn new(boundaries: Cuboid) -> Self {
+        Self {
+            boundaries,
+            children: None,
+            bodies: Vec::new(),
+            center_of_mass: None,
+            total_mass: 0.0,
+        }
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        self.children.is_none()
+    }
+}
+
+impl Octree {
+    pub fn new(boundaries: Cuboid, limit: usize) -> Self {
+        Self {
+            boundaries,
+            limit,
+            nodes: vec![OctreeNode::new(boundaries)],
+            spare: Vec::new(),
+        }
+    }
+
+    /// Removes every node but the root, which takes the current `boundaries`
+    pub fn clear(&mut self){
+        for mut node in self.nodes.drain(1..){
+            node.bodies.clear();
+            self.spare.push(node.bodies);
+        }
+        let root = &mut self.nodes[ROOT];
+        root.boundaries = self.boundaries;
+        root.children = None;
+        root.bodies.clear();
+        root.center_of_mass = None;
+        root.total_mass = 0.0;
+    }
+
+    pub fn root(&self) -> &OctreeNode {
+        &self.nodes[ROOT]
+    }
+
+    /// The children of `node`, empty for a leaf
+    pub fn subtrees<'a>(&'a self, node: &'a OctreeNode) -> impl Iterator<Item = &'a OctreeNode> + 'a {
+        node.children.iter().flat_map(move |children| children.iter().map(move |child| &self.nodes[*child]))
+    }
+
+    pub fn split(&mut self, node: usize){
+        if !self.nodes[node].is_leaf() {
+            return;
+        }
+        let first = self.nodes.len();
+        for boundaries in self.nodes[node].boundaries.suboctants(){
+            let mut child = OctreeNode::new(boundaries);
+            if let Some(bodies) = self.spare.pop() {
+                child.bodies = bodies;
+            }
+            self.nodes.push(child);
+        }
+        self.nodes[node].children = Some(std::array::from_fn(|octant| first + octant));
+    }
+
+    /// Inserts `bodies[index]`, the tree only stores the index
+    pub fn insert(&mut self, bodies: &[Body3], index: usize){
+        self.insert_below(ROOT, bodies, index);
+    }
+
+    fn insert_below(&mut self, node: usize, bodies: &[Body3], index: usize){
+        let mut leaf = node;
+        while let Some(children) = self.nodes[leaf].children {
+            leaf = children[self.nodes[leaf].boundaries.octant(bodies[index].pos)];
+        }
+        self.nodes[leaf].bodies.push(index);
+
+        if self.nodes[leaf].bodies.len() > self.limit {
+            //below the minimum size the leaf just holds more than the limit
+            if self.nodes[leaf].boundaries.width() <= MIN_SIZE {
+                return;
+            }
+            self.split(leaf);
+            let mut held = std::mem::take(&mut self.nodes[leaf].bodies);
+            for other in held.drain(..){
+                self.insert_below(leaf, bodies, other);
+            }
+            self.spare.push(held);
+        }
+    }
+
+    /// Rebuilds the tree around every body and computes the masses
+    pub fn build(&mut self, bodies: &[Body3]){
+        self.clear();
+        for index in 0..bodies.len(){
+            self.insert(bodies, index);
+        }
+        self.update_mass(bodies);
+    }
+
+    /// Computes the center of mass and total mass of every node, children come after their parent
+    pub fn update_mass(&mut self, bodies: &[Body3]){
+        for node in (0..self.nodes.len()).rev(){
+            let mut center_of_mass: Option<Vector3<f64>> = None;
+            let mut total_mass: f64 = 0.0;
+            let mut add = |center: Vector3<f64>, mass: f64| {
+                let sum_mass = total_mass + mass;
+                center_of_mass = Some(((center_of_mass.unwrap_or(center) * total_mass) + (center * mass)) / sum_mass);
+                total_mass = sum_mass;
+            };
+            match self.nodes[node].children {
+                Some(children) => {
+                    for child in children{
+                        if let Some(center) = self.nodes[child].center_of_mass {
+                            add(center, self.nodes[child].total_mass);
+                        }
+                    }
+                }
+                None => {
+                    //bodies without mass stay in the leaf but don't count, a leaf of only those has no center
+                    for index in self.nodes[node].bodies.iter().filter(|index| bodies[**index].mass != 0.0){
+                        add(bodies[*index].pos, bodies[*index].mass);
+                    }
+                }
+            }
+            self.nodes[node].center_of_mass = center_of_mass;
+            self.nodes[node].total_mass = total_mass;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use cgmath::Vector3;
+    use crate::body::Body3;
+    use crate::octree::{Cuboid, Octree};
+
+    fn unit_box(size: f64) -> Cuboid {
+        Cuboid::new(Vector3::new(0.0f64,0.0f64,0.0f64),Vector3::new(size,size,size))
+    }
+
+    #[test]
+    fn suboctants_cover_the_box(){
+        let cuboid = unit_box(8.0);
+        let suboctants = cuboid.suboctants();
+        for (octant, sub) in suboctants.iter().enumerate(){
+            assert_eq!(sub.width(), 4.0);
+            assert_eq!(cuboid.octant(sub.midpoint()), octant);
+        }
+        assert_eq!(suboctants[7].max, cuboid.max);
+        assert_eq!(cuboid.octant(cuboid.midpoint()), 0);
+    }
+
+    #[test]
+    fn insert_splits_into_eight(){
+        let mut octree: Octree = Octree::new(unit_box(400.0),1);
+        let bodies: Vec<Body3> = vec![
+            Body3::with_pos(Vector3::new(10.0,10.0,10.0)),
+            Body3::with_pos(Vector3::new(300.0,10.0,300.0))
+        ];
+        octree.build(&bodies);
+        assert_eq!(octree.subtrees(octree.root()).count(), 8);
+        assert_eq!(octree.nodes[octree.root().children.unwrap()[5]].bodies, vec![1]);
+        assert_eq!(octree.root().center_of_mass, Some(Vector3::new(155.0,10.0,155.0)));
+        assert_eq!(octree.root().total_mass, 2.0);
+    }
+
+    #[test]
+    fn min_size_leaf_keeps_every_body_once(){
+        let mut octree: Octree = Octree::new(unit_box(4.0),1);
+        let bodies: Vec<Body3> = (1..4).map(|i| Body3::with_pos(Vector3::new(0.1,0.1,0.1) * i as f64)).collect();
+        octree.build(&bodies);
+        let total: usize = octree.nodes.iter().map(|node| node.bodies.len()).sum();
+        assert_eq!(total, 3);
+        assert_eq!(octree.root().total_mass, 3.0);
+        octree.build(&bodies);
+        assert_eq!(octree.root().total_mass, 3.0);
+    }
+
+    #[test]
+    fn massless_leaf_has_no_center(){
+        let mut octree: Octree = Octree::new(unit_box(400.0),1);
+        let bodies: Vec<Body3> = vec![
+            Body3::with_mass_and_pos(0.0, Vector3::new(10.0,10.0,10.0)),
+            Body3::with_mass_and_pos(0.0, Vector3::new(20.0,10.0,10.0)),
+            Body3::with_pos(Vector3::new(300.0,10.0,300.0))
+        ];
+        octree.build(&bodies);
+        let massless = &octree.nodes[octree.root().children.unwrap()[0]];
+        assert!(massless.center_of_mass.is_none() && massless.total_mass == 0.0);
+        assert_eq!(octree.root().center_of_mass, Some(Vector3::new(300.0,10.0,300.0)));
+        assert_eq!(octree.root().total_mass, 1.0);
+    }
+}
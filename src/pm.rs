@@ -0,0 +1,389 @@
+use std::f64::consts::PI;
+use cgmath::{MetricSpace, Vector2, Zero};
+use rayon::prelude::*;
+use crate::body::Body;
+use crate::fft::fft2;
+use crate::fmm::Complex;
+use crate::gravity;
+use crate::gravity::PhysicsParams;
+use crate::boundary::wrap_positions;
+use crate::integrator::{ExplicitEuler, Integrator};
+use crate::quadtree::{Node, Quadtree, Rectangle};
+use crate::solver::Solver;
+
+/// The short range force is dropped beyond this many split scales, where it is below 0.05% of Newton's
+const CUTOFF: f64 = 6.0;
+
+/// A particle-mesh solver for periodic boxes. The box is `Quadtree::boundaries`, which this solver never resizes.
+///
+/// Masses are spread over a `grid` x `grid` mesh with cloud-in-cell weights, the potential comes from
+/// an FFT of the mesh with the Fourier transform of the 1/r kernel of `gravity`, 2 pi / k, and the
+/// gradient is taken in Fourier space and interpolated back with the same weights. The mean density is
+/// removed, as in any periodic box. Forces are smoothed on the scale of a cell and not softened further.
+///
+/// With a `split` this is TreePM: the mesh only carries the long range part, 2 pi erfc(k r_s) / k,
+/// and the quadtree adds the rest, erfc(r / 2 r_s) / r, from every body and periodic image within
+/// `CUTOFF` split scales. The short range part is softened with `params.softening`.
+#[derive(Debug)]
+pub struct PmSolver {
+    /// Cells along each side of the mesh, a power of two
+    pub grid: usize,
+    /// The TreePM split scale r_s in mesh cells, `None` leaves everything to the mesh
+    pub split: Option<f64>,
+    /// Opening angle of the short range tree walk
+    pub theta: f64,
+    pub paused: bool,
+    pub integrator: Box<dyn Integrator>,
+    pub params: PhysicsParams,
+    pub time: f64
+}
+
+/// One short range walk, shared by the walks of every body
+struct ShortRange<'a> {
+    solver: &'a PmSolver,
+    quadtree: &'a Quadtree,
+    bodies: &'a [Body],
+    split: f64
+}
+
+impl PmSolver {
+    /// Plain PM on a `grid` x `grid` mesh, rounded up to a power of two
+    pub fn new(grid: usize) -> PmSolver {
+        Self {
+            grid: grid.next_power_of_two(),
+            split: None,
+            theta: 0.5,
+            paused: false,
+            integrator: Box::new(ExplicitEuler),
+            params: PhysicsParams::default(),
+            time: 0.0
+        }
+    }
+
+    /// TreePM with the usual split of 1.25 cells
+    pub fn tree_pm(grid: usize, theta: f64) -> PmSolver {
+        Self {
+            split: Some(1.25),
+            theta,
+            ..Self::new(grid)
+        }
+    }
+
+    fn cell(&self, boundaries: &Rectangle) -> Vector2<f64> {
+        Vector2::new(boundaries.width(), boundaries.height()) / self.grid as f64
+    }
+
+    /// r_s as a length, zero without a split
+    fn split_scale(&self, boundaries: &Rectangle) -> f64 {
+        let cell = self.cell(boundaries);
+        self.split.map_or(0.0, |split| split * cell.x.max(cell.y))
+    }
+
+    /// The four mesh cells around `pos` with their cloud-in-cell weights, wrapped around the box
+    fn cloud_in_cell(&self, boundaries: &Rectangle, pos: Vector2<f64>) -> [(usize, f64); 4] {
+        let cell = self.cell(boundaries);
+        let n = self.grid as i64;
+        //cell centres sit half a cell in from the edges
+        let u = ((pos.x - boundaries.tl.x) / cell.x) - 0.5;
+        let v = ((pos.y - boundaries.tl.y) / cell.y) - 0.5;
+        let (x, y) = (u.floor(), v.floor());
+        let (fx, fy) = (u - x, v - y);
+        let (x, y) = (x as i64, y as i64);
+        let index = |x: i64, y: i64| ((y.rem_euclid(n) * n) + x.rem_euclid(n)) as usize;
+        [
+            (index(x, y), (1.0 - fx) * (1.0 - fy)),
+            (index(x + 1, y), fx * (1.0 - fy)),
+            (index(x, y + 1), (1.0 - fx) * fy),
+            (index(x + 1, y + 1), fx * fy)
+        ]
+    }
+
+    /// The force on every body from the mesh, only the long range part when there is a split
+    pub fn mesh_forces(&self, boundaries: &Rectangle, bodies: &[Body]) -> Vec<Vector2<f64>> {
+        let n = self.grid;
+        let cell = self.cell(boundaries);
+        let split = self.split_scale(boundaries);
+        let mut density: Vec<Complex> = vec![Complex::ZERO; n * n];
+        for body in bodies.iter().filter(|body| body.is_source()){
+            for (index, weight) in self.cloud_in_cell(boundaries, body.pos){
+                density[index].re += body.mass * weight;
+            }
+        }
+        fft2(&mut density, n, false);
+
+        let mut field_x: Vec<Complex> = vec![Complex::ZERO; n * n];
+        let mut field_y: Vec<Complex> = vec![Complex::ZERO; n * n];
+        let frequency = |i: usize| if i <= n / 2 { i as f64 } else { i as f64 - n as f64 };
+        for (index, mass) in density.iter().enumerate(){
+            let (i, j) = (index % n, index / n);
+            let kx = 2.0 * PI * frequency(i) / boundaries.width();
+            let ky = 2.0 * PI * frequency(j) / boundaries.height();
+            let k = kx.hypot(ky);
+            if k == 0.0 {
+                continue;
+            }
+            //cloud-in-cell smooths once when assigning and again when interpolating
+            let window = (sinc(kx * cell.x / 2.0) * sinc(ky * cell.y / 2.0)).powi(2);
+            let potential = mass.scale(-self.params.g * 2.0 * PI * erfc(k * split) / (k * window * window));
+            //a = -grad(phi), the Nyquist terms have no well defined derivative
+            if i != n / 2 {
+                field_x[index] = Complex::new(0.0, -kx) * potential;
+            }
+            if j != n / 2 {
+                field_y[index] = Complex::new(0.0, -ky) * potential;
+            }
+        }
+        fft2(&mut field_x, n, true);
+        fft2(&mut field_y, n, true);
+
+        let area = cell.x * cell.y;
+        bodies.iter().map(|body| {
+            let acceleration = self.cloud_in_cell(boundaries, body.pos).iter().fold(Vector2::zero(), |sum: Vector2<f64>, (index, weight)| {
+                sum + (Vector2::new(field_x[*index].re, field_y[*index].re) * *weight)
+            });
+            acceleration * (body.test_mass() / area)
+        }).collect()
+    }
+
+    /// The TreePM short range force on every body from the tree as it was last built, zero without a split
+    pub fn short_range_forces(&self, quadtree: &Quadtree, bodies: &[Body]) -> Vec<Vector2<f64>> {
+        let walk = ShortRange {
+            solver: self,
+            quadtree,
+            bodies,
+            split: self.split_scale(&quadtree.boundaries)
+        };
+        if walk.split == 0.0 {
+            return vec![Vector2::zero(); bodies.len()];
+        }
+        if rayon::current_thread_index().is_some() {
+            bodies.par_iter().map(|body| walk.force(body)).collect()
+        } else {
+            bodies.iter().map(|body| walk.force(body)).collect()
+        }
+    }
+
+    /// Overwrites the force on every body using the tree as it was last built
+    pub fn tree_forces(&self, quadtree: &Quadtree, bodies: &mut [Body]){
+        let mut forces = self.mesh_forces(&quadtree.boundaries, bodies);
+        if self.split.is_some() {
+            for (force, short) in forces.iter_mut().zip(self.short_range_forces(quadtree, bodies)){
+                *force += short;
+            }
+        }
+        for (body, force) in bodies.iter_mut().zip(forces){
+            body.force = force;
+        }
+    }
+
+    pub fn update(&mut self, quadtree: &mut Quadtree, bodies: &mut [Body]){
+        self.tree_forces(quadtree, bodies);
+        self.integrator.step(bodies, &self.params, &mut |bodies: &mut [Body], _| {
+            self.evaluate_forces(quadtree, bodies);
+        });
+        wrap_positions(&quadtree.boundaries, bodies);
+        self.time += self.params.dt;
+    }
+
+    /// Wraps the bodies back into the box, rebuilds the tree and overwrites the force on every body
+    pub fn evaluate_forces(&self, quadtree: &mut Quadtree, bodies: &mut [Body]){
+        wrap_positions(&quadtree.boundaries, bodies);
+        quadtree.build(bodies);
+        self.tree_forces(quadtree, bodies);
+    }
+}
+
+impl ShortRange<'_> {
+    /// Walks the tree once for every copy of the box that comes within the cutoff of `body`
+    fn force(&self, body: &Body) -> Vector2<f64> {
+        let boundaries = self.quadtree.boundaries;
+        let cutoff = CUTOFF * self.split;
+        let reach_x = (cutoff / boundaries.width()).ceil() as i32;
+        let reach_y = (cutoff / boundaries.height()).ceil() as i32;
+        let mut force: Vector2<f64> = Vector2::zero();
+        for x in -reach_x..=reach_x{
+            for y in -reach_y..=reach_y{
+                let image = Vector2::new(x as f64 * boundaries.width(), y as f64 * boundaries.height());
+                self.accumulate(self.quadtree.root(), body, image, &mut force);
+            }
+        }
+        force
+    }
+
+    /// Adds the force from the copy of `node` shifted by `image`
+    fn accumulate(&self, node: &Node, body: &Body, image: Vector2<f64>, force: &mut Vector2<f64>){
+        let pos = body.pos - image;
+        let cutoff = CUTOFF * self.split;
+        if node.boundaries.distance_to(pos) > cutoff {
+            return;
+        }
+        let params = &self.solver.params;
+        if node.is_leaf() {
+            for other_body in node.bodies.iter().map(|index| &self.bodies[*index]){
+                let distance = pos.distance(other_body.pos);
+                //a body only skips itself, its periodic copies still pull on it
+                if (other_body.id == body.id && image.is_zero()) || !other_body.is_source() || distance > cutoff {
+                    continue;
+                }
+                *force += gravity::force_mass_center(body, other_body.pos + image, other_body.mass, params) * short_range_factor(distance, self.split);
+            }
+            return;
+        }
+        if let Some(com) = node.center_of_mass {
+            let distance = pos.distance(com);
+            if node.boundaries.contains(pos) || node.boundaries.width() > self.solver.theta * distance {
+                for subtree in self.quadtree.child_nodes(node){
+                    self.accumulate(subtree, body, image, force);
+                }
+            } else {
+                *force += gravity::force_mass_center(body, com + image, node.total_mass, params) * short_range_factor(distance, self.split);
+            }
+        }
+    }
+}
+
+/// The share of the Newtonian force left to the tree at `distance`, the force of erfc(r / 2 r_s) / r
+fn short_range_factor(distance: f64, split: f64) -> f64 {
+    let x = distance / (2.0 * split);
+    erfc(x) + (2.0 * x / PI.sqrt() * (-x * x).exp())
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 { 1.0 } else { x.sin() / x }
+}
+
+/// The complementary error function, Chebyshev fit from Numerical Recipes with a relative error below 1.2e-7
+pub(crate) fn erfc(x: f64) -> f64 {
+    let z = x.abs();
+    let t = 1.0 / (1.0 + (0.5 * z));
+    let series = -1.26551223 + (t * (1.00002368 + (t * (0.37409196 + (t * (0.09678418 + (t * (-0.18628806
+        + (t * (0.27886807 + (t * (-1.13520398 + (t * (1.48851587 + (t * (-0.82215223 + (t * 0.17087277)))))))))))))))));
+    let value = t * (-(z * z) + series).exp();
+    if x >= 0.0 { value } else { 2.0 - value }
+}
+
+impl Solver for PmSolver {
+    fn params(&self) -> &PhysicsParams {
+        &self.params
+    }
+
+    fn params_mut(&mut self) -> &mut PhysicsParams {
+        &mut self.params
+    }
+
+    fn time(&self) -> f64 {
+        self.time
+    }
+
+    fn evaluate_forces(&self, quadtree: &mut Quadtree, bodies: &mut [Body]){
+        PmSolver::evaluate_forces(self, quadtree, bodies);
+    }
+
+    fn iterate(&mut self, quadtree: &mut Quadtree, bodies: &mut Vec<Body>){
+        if self.paused {
+            return;
+        }
+        wrap_positions(&quadtree.boundaries, bodies);
+        quadtree.build(bodies);
+        self.update(quadtree, bodies);
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use cgmath::{InnerSpace, Vector2};
+    use crate::accuracy::{exact_forces, relative_errors, ErrorStats};
+    use crate::body::Body;
+    use crate::gravity::PhysicsParams;
+    use crate::pm::{erfc, PmSolver};
+    use crate::quadtree::{Quadtree, Rectangle};
+    use crate::softening::Softening;
+    use crate::solver::Solver;
+
+    fn newtonian() -> PhysicsParams {
+        PhysicsParams::new(1.0, 0.01, Softening::None { min_distance: 0.0 })
+    }
+
+    fn periodic_box(size: f64) -> Quadtree {
+        Quadtree::new(Rectangle::new(Vector2::new(0.0,0.0),Vector2::new(size,size)),4)
+    }
+
+    fn solvers(grid: usize) -> [PmSolver; 2] {
+        let mut pm = PmSolver::new(grid);
+        pm.params = newtonian();
+        let mut tree_pm = PmSolver::tree_pm(grid, 0.5);
+        tree_pm.params = newtonian();
+        [pm, tree_pm]
+    }
+
+    #[test]
+    fn erfc_values(){
+        assert!((erfc(0.0) - 1.0).abs() < 1e-7);
+        assert!((erfc(1.0) - 0.157_299_207).abs() < 1e-7);
+        assert!((erfc(-1.0) - 1.842_700_793).abs() < 1e-7);
+        assert!((erfc(3.0) / 2.209_049_7e-5 - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn pair_matches_newton_far_from_the_edges(){
+        //the box is large enough that the periodic copies barely matter
+        for separation in [10.0, 60.0, 200.0]{
+            for solver in solvers(256){
+                if solver.split.is_none() && separation < 100.0 {
+                    //plain PM is smoothed over a few cells, TreePM is least accurate near the split at about 1%
+                    continue;
+                }
+                let mut qt = periodic_box(4000.0);
+                let mut bodies: Vec<Body> = vec![
+                    Body::with_mass_and_pos(5.0, Vector2::new(2000.0, 2000.0)),
+                    Body::with_mass_and_pos(3.0, Vector2::new(2000.0 + (separation * 0.6), 2000.0 + (separation * 0.8)))
+                ];
+                solver.evaluate_forces(&mut qt, &mut bodies);
+                let expected = exact_forces(&bodies, &solver.params);
+                for (body, exact) in bodies.iter().zip(expected){
+                    let error = (body.force - exact).magnitude() / exact.magnitude();
+                    assert!(error < 0.02, "split {:?} separation {}: {}", solver.split, separation, error);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn cluster_matches_direct_summation(){
+        let [_, solver] = solvers(256);
+        //a fixed cluster, a random one now and then puts a body where its pulls nearly cancel
+        let mut rng = fastrand::Rng::with_seed(300);
+        let bodies: Vec<Body> = (0..300).map(|_| Body::with_mass_and_pos(10.0, Vector2::new(rng.f64(), rng.f64()) * 400.0 + Vector2::new(1800.0, 1800.0))).collect();
+        let mut pm_bodies = bodies.clone();
+        solver.evaluate_forces(&mut periodic_box(4000.0), &mut pm_bodies);
+        let approximate: Vec<Vector2<f64>> = pm_bodies.iter().map(|body| body.force).collect();
+        let stats = ErrorStats::from_errors(&relative_errors(&approximate, &exact_forces(&bodies, &solver.params)));
+        assert!(stats.median < 5e-3 && stats.percentile_99 < 0.05, "{}", stats);
+    }
+
+    #[test]
+    fn attracts_across_the_edge(){
+        for solver in solvers(64){
+            let mut bodies: Vec<Body> = vec![
+                Body::with_mass_and_pos(1.0, Vector2::new(20.0, 500.0)),
+                Body::with_mass_and_pos(1.0, Vector2::new(980.0, 500.0))
+            ];
+            solver.evaluate_forces(&mut periodic_box(1000.0), &mut bodies);
+            //the bodies are 40 apart through the edge and 960 apart through the box
+            assert!(bodies[0].force.x < 0.0 && bodies[1].force.x > 0.0, "split {:?}", solver.split);
+            assert!((bodies[0].force.x + bodies[1].force.x).abs() < 1e-3 * bodies[1].force.x);
+        }
+    }
+
+    #[test]
+    fn iterate_wraps_positions(){
+        let mut solver: Box<dyn Solver> = Box::new(PmSolver::tree_pm(32, 0.5));
+        let mut qt = periodic_box(100.0);
+        let mut bodies: Vec<Body> = vec![Body::with_mass_and_pos(1.0, Vector2::new(99.5, 0.2)), Body::with_mass_and_pos(1.0, Vector2::new(50.0, 50.0))];
+        bodies[0].velocity = Vector2::new(1000.0, -1000.0);
+        solver.iterate(&mut qt, &mut bodies);
+        assert!(qt.boundaries.within(bodies[0].pos), "{:?}", bodies[0].pos);
+        assert!((bodies[0].pos - Vector2::new(0.5, 99.2)).magnitude() < 1e-3);
+        assert_eq!(qt.boundaries, Rectangle::new(Vector2::new(0.0,0.0),Vector2::new(100.0,100.0)));
+    }
+}
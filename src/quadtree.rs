@@ -1,26 +1,61 @@
+use std::ops::Deref;
 use cgmath::{Vector2};
+use rayon::prelude::*;
 use crate::body::Body;
 
-const A: usize = 0;
-const B: usize = 1;
-const C: usize = 2;
-const D: usize = 3;
+pub const A: usize = 0;
+pub const B: usize = 1;
+pub const C: usize = 2;
+pub const D: usize = 3;
+
+/// The root is always the first node of the arena
+pub const ROOT: usize = 0;
 
 const MIN_SIZE: f64 = 1.0;
 
+/// Two bits per level, a `u128` key describes the path through at most 64 levels
+const MAX_KEY_LEVELS: usize = 64;
+
 #[derive(PartialEq,Debug,Clone,Copy)]
 pub struct Rectangle {
     pub tl: Vector2<f64>,
     pub br: Vector2<f64>
 }
+
+/// One square of the tree. Leaves hold indices into the body slice the tree was built from,
+/// internal nodes hold the arena indices of their four children in `A`, `B`, `C`, `D` order.
+#[derive(Debug,Clone)]
+pub struct Node {
+    pub boundaries: Rectangle,
+    pub children: Option<[usize; 4]>,
+    pub bodies: Vec<usize>,
+    pub center_of_mass: Option<Vector2<f64>>,
+    pub total_mass: f64,
+    /// Second moments of the mass about `center_of_mass` as `[xx, xy, yy]`, sum of m * dx * dy
+    pub quadrupole: [f64; 3]
+}
+
+/// A quadtree stored as a flat arena of nodes, it never copies the bodies it is built from.
+/// `center_of_mass` and `total_mass` mirror the root once the masses are up to date.
 #[derive(Debug)]
 pub struct Quadtree{
     pub boundaries: Rectangle,
     pub limit: usize,
-    pub subtrees: Vec<Box<Quadtree>>,
-    pub bodies: Vec<Body>,
+    pub nodes: Vec<Node>,
     pub center_of_mass: Option<Vector2<f64>>,
-    pub total_mass: f64
+    pub total_mass: f64,
+    spare: Vec<Vec<usize>>,
+    keys: Vec<(u128, usize)>,
+    /// Bodies handed over by value through `insert`
+    owned: Vec<Body>
+}
+
+/// A node seen together with its tree, so it can be walked like a tree of its own.
+/// Derefs to the `Node` for the boundaries, masses and body indices.
+#[derive(Debug,Clone,Copy)]
+pub struct Subtree<'a> {
+    tree: &'a Quadtree,
+    index: usize
 }
 
 
@@ -38,6 +73,11 @@ impl Rectangle{
                 obj_pos.y < self.br.y
     }
 
+    /// `within` including the bottom and right edges, where a body at the largest coordinate sits
+    pub fn contains(&self, pos: Vector2<f64>) -> bool {
+        pos.x >= self.tl.x && pos.x <= self.br.x && pos.y >= self.tl.y && pos.y <= self.br.y
+    }
+
 
 
     pub fn midpoint(&self) -> Vector2<f64> {
@@ -52,6 +92,20 @@ impl Rectangle{
         return self.br.y - self.tl.y;
     }
 
+    /// Distance from `pos` to the farthest corner
+    pub fn max_extent_from(&self, pos: Vector2<f64>) -> f64 {
+        let x = (pos.x - self.tl.x).abs().max((self.br.x - pos.x).abs());
+        let y = (pos.y - self.tl.y).abs().max((self.br.y - pos.y).abs());
+        x.hypot(y)
+    }
+
+    /// Distance from `pos` to the nearest point of the rectangle, zero inside it
+    pub fn distance_to(&self, pos: Vector2<f64>) -> f64 {
+        let x = (self.tl.x - pos.x).max(pos.x - self.br.x).max(0.0);
+        let y = (self.tl.y - pos.y).max(pos.y - self.br.y).max(0.0);
+        x.hypot(y)
+    }
+
 
 
 
@@ -72,212 +126,426 @@ impl Rectangle{
 }
 
 
+impl Node {
+    pub fn new(boundaries: Rectangle) -> Self {
+        Self {
+            boundaries,
+            children: None,
+            bodies: Vec::new(),
+            center_of_mass: None,
+            total_mass: 0.0,
+            quadrupole: [0.0; 3],
+        }
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        self.children.is_none()
+    }
+
+    /// Which of the four children `pos` falls into, `None` for a leaf
+    pub fn subtree_index(&self, pos: Vector2<f64>) -> Option<usize>{
+        if self.is_leaf() {
+            return None;
+        }
+        let midpoint = self.boundaries.midpoint();
+        if pos.x <= midpoint.x {
+            if pos.y <= midpoint.y {
+                Some(A)
+            } else {
+                Some(C)
+            }
+        } else if pos.y <= midpoint.y {
+            Some(B)
+        } else {
+            Some(D)
+        }
+    }
+}
+
+
 impl Quadtree {
     pub fn new(boundaries: Rectangle, limit: usize) -> Self {
         Self {
             boundaries,
             limit,
-            subtrees: Vec::new(),
-            bodies: vec![],
+            nodes: vec![Node::new(boundaries)],
             center_of_mass: None,
             total_mass: 0.0,
+            spare: Vec::new(),
+            keys: Vec::new(),
+            owned: Vec::new(),
         }
     }
 
+    /// Removes every node but the root, which takes the current `boundaries`.
+    /// The leaf index lists are kept around so the next build doesn't have to allocate them again.
     pub fn clear(&mut self){
-        self.bodies.clear();
-        for subtree in self.subtrees.iter_mut(){
-            subtree.clear();
+        for mut node in self.nodes.drain(1..){
+            node.bodies.clear();
+            self.spare.push(node.bodies);
         }
-        self.subtrees.clear();
+        let root = &mut self.nodes[ROOT];
+        root.boundaries = self.boundaries;
+        root.children = None;
+        root.bodies.clear();
+        root.center_of_mass = None;
+        root.total_mass = 0.0;
+        root.quadrupole = [0.0; 3];
+        self.center_of_mass = None;
+        self.total_mass = 0.0;
+        self.owned.clear();
+    }
+
+    /// Grows `boundaries` into a square holding every body, it never shrinks.
+    /// Takes effect on the next `clear` or `build`.
+    pub fn resize_to_fit(&mut self, bodies: &[Body]){
+        let mut smallest: f64 = self.boundaries.tl.x;
+        let mut largest: f64 = self.boundaries.br.x;
+        for body in bodies{
+            if body.pos.x > largest{
+                largest = body.pos.x;
+            }
+
+            if body.pos.x < smallest{
+                smallest = body.pos.x;
+            }
+
+            if body.pos.y > largest{
+                largest = body.pos.y;
+            }
 
+            if body.pos.y < smallest{
+                smallest = body.pos.y;
+            }
+        }
+
+        self.boundaries.tl.x = smallest;
+        self.boundaries.tl.y = smallest;
+        self.boundaries.br.x = largest;
+        self.boundaries.br.y = largest;
     }
 
+    pub fn root(&self) -> &Node {
+        &self.nodes[ROOT]
+    }
 
+    /// The whole tree as a `Subtree`
+    pub fn view(&self) -> Subtree<'_> {
+        Subtree{ tree: self, index: ROOT }
+    }
 
+    /// The children of the root in `A`, `B`, `C`, `D` order, empty while the root is a leaf
+    pub fn subtrees(&self) -> Vec<Subtree<'_>> {
+        self.view().subtrees()
+    }
 
+    /// The children of `node`, empty for a leaf
+    pub fn child_nodes<'a>(&'a self, node: &'a Node) -> impl Iterator<Item = &'a Node> + 'a {
+        node.children.iter().flat_map(move |children| children.iter().map(move |child| &self.nodes[*child]))
+    }
 
+    /// Which of the root's children `pos` falls into, `None` while the root is a leaf
+    pub fn subtree_index(&self, pos: Vector2<f64>) -> Option<usize>{
+        self.root().subtree_index(pos)
+    }
+
+    /// Pushes the index of every body within `radius` of `pos` onto `found`, `bodies` is the slice the tree
+    /// was built from. Only nodes whose square comes within `radius` are visited, so bodies lying outside
+    /// `boundaries` can be missed.
+    pub fn query_radius(&self, bodies: &[Body], pos: Vector2<f64>, radius: f64, found: &mut Vec<usize>){
+        let mut stack: Vec<usize> = vec![ROOT];
+        while let Some(node) = stack.pop() {
+            let node = &self.nodes[node];
+            if node.boundaries.distance_to(pos) > radius {
+                continue;
+            }
+            match node.children {
+                Some(children) => stack.extend(children),
+                None => found.extend(node.bodies.iter().copied().filter(|index| {
+                    let d = bodies[*index].pos - pos;
+                    (d.x * d.x) + (d.y * d.y) <= radius * radius
+                }))
+            }
+        }
+    }
 
     pub fn split(&mut self){
+        self.split_node(ROOT);
+    }
+
+    fn split_node(&mut self, node: usize){
         //split into four
-        if self.subtrees.len() != 0 {
+        if !self.nodes[node].is_leaf() {
             return;
         }
 
-        let subranges = self.boundaries.subranges();
-        self.subtrees.push(Box::new(Quadtree::new(subranges.0,self.limit)));
-        self.subtrees.push(Box::new(Quadtree::new(subranges.1,self.limit)));
-        self.subtrees.push(Box::new(Quadtree::new(subranges.2,self.limit)));
-        self.subtrees.push(Box::new(Quadtree::new(subranges.3,self.limit)));
+        let subranges = self.nodes[node].boundaries.subranges();
+        let first = self.nodes.len();
+        for boundaries in [subranges.0, subranges.1, subranges.2, subranges.3]{
+            let mut child = Node::new(boundaries);
+            if let Some(bodies) = self.spare.pop() {
+                child.bodies = bodies;
+            }
+            self.nodes.push(child);
+        }
+        self.nodes[node].children = Some([first, first + 1, first + 2, first + 3]);
     }
 
+    /// Inserts a body the tree keeps for itself, for building a tree by hand.
+    /// Don't mix with `insert_index` or `build`, their indices point into another slice.
     pub fn insert(&mut self, body: Body) {
-        //after we insert the body, check if we need to split
-        if self.subtrees.len() == 0 {
-            self.bodies.push(body);
-        } else {
-            match self.subtree_index(body.pos){
-                Some(i) => {self.subtrees[i].insert(body)},
-                None => {return;}
-            }
-        }
+        self.owned.push(body);
+        let owned = std::mem::take(&mut self.owned);
+        self.insert_below(ROOT, &owned, owned.len() - 1);
+        self.owned = owned;
+    }
 
+    /// Inserts `bodies[index]`, the tree only stores the index
+    pub fn insert_index(&mut self, bodies: &[Body], index: usize) {
+        self.insert_below(ROOT, bodies, index);
+    }
 
-        let mut body_opt: Option<Body>;
-        //if we split
-        if self.bodies.len() > self.limit {
+    fn insert_below(&mut self, node: usize, bodies: &[Body], index: usize) {
+        //walk down to the leaf that holds the position
+        let mut leaf = node;
+        while let Some(i) = self.nodes[leaf].subtree_index(bodies[index].pos) {
+            leaf = self.nodes[leaf].children.unwrap()[i];
+        }
+        self.nodes[leaf].bodies.push(index);
 
+        //if we split
+        if self.nodes[leaf].bodies.len() > self.limit {
             //so we dont keep dividing and get a stack overflow error
-            if self.boundaries.width() <= MIN_SIZE {
-                self.bodies.push(body);
+            //the body is already stored, this leaf just holds more than the limit
+            if self.nodes[leaf].boundaries.width() <= MIN_SIZE {
                 return;
             }
-            self.split();
-            while !self.bodies.is_empty() {
-                body_opt = self.bodies.pop();
-                match body_opt {
-                    Some(body) => {self.insert(body)},
-                    None => {}
-                }
+            self.split_node(leaf);
+            let mut held = std::mem::take(&mut self.nodes[leaf].bodies);
+            for other in held.drain(..){
+                self.insert_below(leaf, bodies, other);
             }
+            self.spare.push(held);
         }
     }
 
+    /// `update_mass_from` for the bodies added through `insert`
     pub fn update_mass(&mut self){
-        //base case
-        if self.subtrees.is_empty() {
-            self.calculate_center_leaf();
-            return;
-        }
+        let owned = std::mem::take(&mut self.owned);
+        self.update_mass_from(&owned);
+        self.owned = owned;
+    }
 
-        //we aren't at the bottom, so we go further down
-        for subtree in &mut self.subtrees{
-            subtree.update_mass();
+    /// Computes the center of mass and total mass of every node.
+    /// Children are always stored after their parent, so one reverse pass is bottom up.
+    pub fn update_mass_from(&mut self, bodies: &[Body]){
+        for node in (0..self.nodes.len()).rev(){
+            self.update_node_mass(node, bodies);
         }
+        self.mirror_root();
+    }
+
+    fn mirror_root(&mut self){
+        self.center_of_mass = self.nodes[ROOT].center_of_mass;
+        self.total_mass = self.nodes[ROOT].total_mass;
+    }
 
-        //then on the way up we calculate the center based on its children
-        self.calculate_center_node();
+    fn update_node_mass(&mut self, node: usize, bodies: &[Body]){
+        if self.nodes[node].is_leaf() {
+            self.calculate_center_leaf(node, bodies);
+        } else {
+            self.calculate_center_node(node);
+        }
+    }
 
+    /// Rebuilds the tree around every body in one go, the result is the same tree repeated `insert_index`
+    /// calls followed by `update_mass_from` would give.
+    ///
+    /// Every body gets a Morton (Z-order) key describing its path from the root, with the same
+    /// midpoint comparisons `Node::subtree_index` makes. After sorting, the bodies of any node are a
+    /// contiguous run of keys, so the tree is built top down by splitting runs and the masses are
+    /// filled in bottom up on the way back. Inside a rayon pool the keys are computed and sorted in parallel.
+    pub fn build(&mut self, bodies: &[Body]){
+        self.clear();
+        let levels = self.key_levels();
+        let mut keys = std::mem::take(&mut self.keys);
+        keys.clear();
+        if rayon::current_thread_index().is_some() {
+            keys.par_extend(bodies.par_iter().enumerate().map(|(index, body)| (self.morton_key(body.pos, levels), index)));
+            keys.par_sort_unstable();
+        } else {
+            keys.extend(bodies.iter().enumerate().map(|(index, body)| (self.morton_key(body.pos, levels), index)));
+            keys.sort_unstable();
+        }
+        self.build_below(ROOT, &keys, 0, levels, bodies);
+        self.keys = keys;
+        self.mirror_root();
     }
 
+    /// How many levels the keys need, a couple more than the depth at which nodes reach `MIN_SIZE`
+    fn key_levels(&self) -> usize {
+        let mut levels = 0;
+        let mut boundaries = self.boundaries;
+        while boundaries.width() > MIN_SIZE && levels < MAX_KEY_LEVELS {
+            boundaries = boundaries.subranges().0;
+            levels += 1;
+        }
+        (levels + 2).min(MAX_KEY_LEVELS)
+    }
 
+    /// The quadrants `pos` falls into on the way down from the root, two bits per level with the root's first
+    fn morton_key(&self, pos: Vector2<f64>, levels: usize) -> u128 {
+        let mut key: u128 = 0;
+        let mut boundaries = self.boundaries;
+        for _ in 0..levels{
+            let midpoint = boundaries.midpoint();
+            let right = pos.x > midpoint.x;
+            let below = pos.y > midpoint.y;
+            //A, B, C and D are 0 to 3, so sorting by key orders children the same way as the arena
+            key = (key << 2) | ((below as u128) << 1) | (right as u128);
+            let subranges = boundaries.subranges();
+            boundaries = match (right, below) {
+                (false, false) => subranges.0,
+                (true, false) => subranges.1,
+                (false, true) => subranges.2,
+                (true, true) => subranges.3
+            };
+        }
+        key
+    }
 
-    pub fn calculate_center_leaf(&mut self){
-        if self.bodies.is_empty(){
-            self.center_of_mass = None;
+    fn build_below(&mut self, node: usize, keys: &[(u128, usize)], depth: usize, levels: usize, bodies: &[Body]){
+        if keys.len() <= self.limit || self.nodes[node].boundaries.width() <= MIN_SIZE {
+            self.nodes[node].bodies.extend(keys.iter().map(|(_, index)| *index));
+            self.calculate_center_leaf(node, bodies);
+            return;
+        }
+        if depth == levels {
+            //deeper than the keys reach, finish this branch one body at a time
+            let first = self.nodes.len();
+            for (_, index) in keys{
+                self.insert_below(node, bodies, *index);
+            }
+            for below in (first..self.nodes.len()).rev(){
+                self.update_node_mass(below, bodies);
+            }
+            self.update_node_mass(node, bodies);
             return;
         }
 
-        //if we get to this point then this leaf has some bodies
-        //if the center of mass doesnt exist then we create it
-        match self.center_of_mass{
-            Some(_) => {},
-            None => { self.center_of_mass = Some(Vector2::new(0.0,0.0));}
+        self.split_node(node);
+        let children = self.nodes[node].children.unwrap();
+        let shift = 2 * (levels - 1 - depth);
+        let mut start = 0;
+        for (quadrant, child) in children.iter().enumerate(){
+            let end = start + keys[start..].partition_point(|(key, _)| ((key >> shift) & 3) as usize <= quadrant);
+            self.build_below(*child, &keys[start..end], depth + 1, levels, bodies);
+            start = end;
         }
+        self.calculate_center_node(node);
+    }
 
-        self.total_mass = 0.0f64;
-        //then update the center of mass
-        for body in &self.bodies{
+    pub fn calculate_center_leaf(&mut self, node: usize, bodies: &[Body]){
+        let mut center_of_mass: Option<Vector2<f64>> = None;
+        let mut total_mass: f64 = 0.0;
+        for index in &self.nodes[node].bodies{
             /*
             Formally, if two bodies have positions (x1 , y1) and (x2, y2), and masses m1 and m2, then their total mass and center of mass (x, y) are given by:
             m = m1 + m2
             x = (x1m1 + x2m2) / m
             y = (y1m1 + y2m2) / m
              */
-
-            // self.center_of_mass.as_mut().unwrap().x= ((body.pos.x*body.mass) + (self.center_of_mass.unwrap().x * self.total_mass))/self.total_mass;
-            // self.center_of_mass.as_mut().unwrap().y= ((body.pos.y*body.mass) + (self.center_of_mass.unwrap().y * self.total_mass))/self.total_mass;
-            // self.total_mass+=body.mass;
-            // self.
-
-            //is this valid?
-            self.center_of_mass = Some(self.center_between_two_points(self.center_of_mass.unwrap(), self.total_mass, body.pos, body.mass));
-            self.total_mass+=body.mass;
-
+            let body = &bodies[*index];
+            //tracers and massless bodies stay in the leaf but don't count, a leaf of only those has no center
+            if !body.is_source() {
+                continue;
+            }
+            center_of_mass = Some(self.center_between_two_points(center_of_mass.unwrap_or(body.pos), total_mass, body.pos, body.mass));
+            total_mass += body.mass;
         }
-
-        //TODO: FIX!!!!!!
-        // self.center_of_mass.as_mut().unwrap().x/=self.total_mass;
-        // self.center_of_mass.as_mut().unwrap().y/=self.total_mass;
-
+        //second pass for the moments, they are taken about the finished center of mass
+        let mut quadrupole: [f64; 3] = [0.0; 3];
+        if let Some(center) = center_of_mass {
+            for index in self.nodes[node].bodies.iter().filter(|index| bodies[**index].is_source()){
+                add_moments(&mut quadrupole, [0.0; 3], bodies[*index].pos - center, bodies[*index].mass);
+            }
+        }
+        self.nodes[node].center_of_mass = center_of_mass;
+        self.nodes[node].total_mass = total_mass;
+        self.nodes[node].quadrupole = quadrupole;
     }
 
-    pub fn calculate_center_node(&mut self){
-        let mut non_empty_count: usize = 0;
-        match self.center_of_mass{
-            Some(_) => {},
-            None => { self.center_of_mass = Some(Vector2::new(0.0,0.0));}
+    pub fn calculate_center_node(&mut self, node: usize){
+        let mut center_of_mass: Option<Vector2<f64>> = None;
+        let mut total_mass: f64 = 0.0;
+        for subtree in self.child_nodes(&self.nodes[node]){
+            if let Some(center) = subtree.center_of_mass {
+                center_of_mass = Some(self.center_between_two_points(center_of_mass.unwrap_or(center), total_mass, center, subtree.total_mass));
+                total_mass += subtree.total_mass;
+            }
         }
-        for subtree in &self.subtrees{
-            match subtree.center_of_mass {
-                Some(center) => {
-                    non_empty_count+=1;
-                    // self.total_mass += subtree.total_mass;
-                    // self.center_of_mass.as_mut().unwrap().x = (center.x * subtree.total_mass);
-                    // self.center_of_mass.as_mut().unwrap().y = (center.y * subtree.total_mass);
-                    // self.center_of_mass.as_mut().unwrap().x = (center.x * subtree.total_mass) + (self.center_of_mass.unwrap().x * self.total_mass);
-
-                    //is this valid?
-                    self.center_of_mass = Some(self.center_between_two_points(self.center_of_mass.unwrap(),self.total_mass,center,subtree.total_mass));
-                    self.total_mass+=subtree.total_mass;
-
-                },
-                None => {
-                    continue;
+        //parallel axis theorem, each child's moments are moved from its own center to the new one
+        let mut quadrupole: [f64; 3] = [0.0; 3];
+        if let Some(center) = center_of_mass {
+            for subtree in self.child_nodes(&self.nodes[node]){
+                if let Some(child_center) = subtree.center_of_mass {
+                    add_moments(&mut quadrupole, subtree.quadrupole, child_center - center, subtree.total_mass);
                 }
             }
         }
-        // self.center_of_mass.as_mut().unwrap().div_assign(non_empty_count);
-        // if non_empty_count > 0{
-        //     self.center_of_mass.as_mut().unwrap().x /= self.total_mass;
-        //     self.center_of_mass.as_mut().unwrap().y /= self.total_mass;
-        // }
-
+        self.nodes[node].center_of_mass = center_of_mass;
+        self.nodes[node].total_mass = total_mass;
+        self.nodes[node].quadrupole = quadrupole;
     }
 
     pub fn center_between_two_points(&self, pos_a: Vector2<f64>, mass_a: f64, pos_b: Vector2<f64>, mass_b: f64) -> Vector2<f64>{
         let sum_mass: f64 = mass_a + mass_b;
-        let center: Vector2<f64> = ((pos_a * mass_a) + (pos_b * mass_b))/sum_mass;
-        // print!("{:?}",center);
-
-        return center;
+        ((pos_a * mass_a) + (pos_b * mass_b))/sum_mass
+    }
 
+    pub fn print_boundaries(&self, spacing: String){
+        self.view().print_boundaries(spacing);
     }
 
-    pub fn subtree_index(&self, pos: Vector2<f64>) -> Option<usize>{
-        if self.subtrees.len() == 0 {
-            return None;
-        }
-        let midpoint = self.boundaries.midpoint();
-        if pos.x <= midpoint.x {
-            if pos.y <= midpoint.y {
-                return Some(A);
-            } else {
-                return Some(C);
-            }
-        } else {
-            if pos.y <= midpoint.y {
-                return Some(B);
-            } else {
-                return Some(D);
-            }
+}
+
+impl<'a> Subtree<'a> {
+    /// The children of this node in `A`, `B`, `C`, `D` order, empty for a leaf
+    pub fn subtrees(&self) -> Vec<Subtree<'a>> {
+        match self.children {
+            Some(children) => children.iter().map(|index| Subtree{ tree: self.tree, index: *index }).collect(),
+            None => Vec::new()
         }
     }
 
     pub fn print_boundaries(&self, spacing: String){
         println!("{} {:?}",spacing,self.boundaries);
-        for st in &self.subtrees{
+        for st in self.subtrees(){
             st.print_boundaries(spacing.to_owned() + "  ");
         }
     }
+}
+
+impl Deref for Subtree<'_> {
+    type Target = Node;
+
+    fn deref(&self) -> &Node {
+        &self.tree.nodes[self.index]
+    }
+}
 
+/// Adds `moments` taken about a point `offset` away from the center, carrying `mass`
+fn add_moments(total: &mut [f64; 3], moments: [f64; 3], offset: Vector2<f64>, mass: f64){
+    total[0] += moments[0] + mass * offset.x * offset.x;
+    total[1] += moments[1] + mass * offset.x * offset.y;
+    total[2] += moments[2] + mass * offset.y * offset.y;
 }
 
 #[cfg(test)]
 mod tests{
     use cgmath::Vector2;
     use crate::body::Body;
-    use crate::quadtree::{A, D, Rectangle};
+    use crate::quadtree::{A, D, Node, Rectangle};
     use crate::quadtree::Quadtree;
     #[test]
     fn test_within(){
@@ -317,6 +585,17 @@ mod tests{
 
     }
 
+    #[test]
+    fn max_extent(){
+        let rec: Rectangle = Rectangle::new(Vector2::new(0.0f64,0.0f64),Vector2::new(8.0f64,6.0f64));
+        assert_eq!(rec.max_extent_from(Vector2::new(0.0,0.0)), 10.0);
+        assert_eq!(rec.max_extent_from(Vector2::new(4.0,3.0)), 5.0);
+        assert_eq!(rec.max_extent_from(Vector2::new(8.0,6.0)), 10.0);
+        assert_eq!(rec.distance_to(Vector2::new(4.0,3.0)), 0.0);
+        assert_eq!(rec.distance_to(Vector2::new(11.0,10.0)), 5.0);
+        assert_eq!(rec.distance_to(Vector2::new(-2.0,3.0)), 2.0);
+    }
+
     #[test]
     fn rectangle_subranges(){
         let rec: Rectangle = Rectangle::new(Vector2::new(200.0f64,200.0f64),Vector2::new(400.0f64,400.0f64));
@@ -350,7 +629,7 @@ mod tests{
         let rec: Rectangle = Rectangle::new(Vector2::new(200.0f64,200.0f64),Vector2::new(400.0f64,400.0f64));
         let mut qt: Quadtree = Quadtree::new(rec,5);
         qt.split();
-        assert_eq!(qt.subtrees.len(),4);
+        assert_eq!(qt.subtrees().len(),4);
 
     }
 
@@ -362,7 +641,7 @@ mod tests{
         let body2: Body = Body::with_pos(Vector2::new(210.0,230.0));
         qt.insert(body);
         qt.insert(body2);
-        assert_eq!(qt.subtrees.len(),4);
+        assert_eq!(qt.subtrees().len(),4);
     }
     #[test]
     fn center_node_mass(){
@@ -371,14 +650,14 @@ mod tests{
         qt.insert(Body::with_pos(Vector2::new(1.0,1.0)));
         qt.insert(Body::with_pos(Vector2::new(10.0,10.0)));
         qt.insert(Body::with_pos(Vector2::new(100.0,20.0)));
-        assert_eq!(qt.subtrees.len(),4);
-        assert_eq!(qt.subtrees[A].boundaries.br,Vector2::new(200.0,200.0));
+        assert_eq!(qt.subtrees().len(),4);
+        assert_eq!(qt.subtrees()[A].boundaries.br,Vector2::new(200.0,200.0));
         qt.insert(Body::with_pos(Vector2::new(120.0,120.0)));
-        assert_eq!(qt.subtrees[A].subtrees.len(),4);
-        assert_eq!(qt.subtrees[A].subtrees[A].subtrees.len(),4);
-        qt.subtrees[A].update_mass();
-        assert_eq!(qt.subtrees[A].center_of_mass.unwrap().x,57.75);
-        assert_eq!(qt.subtrees[A].center_of_mass.unwrap().y,37.75);
+        assert_eq!(qt.subtrees()[A].subtrees().len(),4);
+        assert_eq!(qt.subtrees()[A].subtrees()[A].subtrees().len(),4);
+        qt.update_mass();
+        assert_eq!(qt.subtrees()[A].center_of_mass.unwrap().x,57.75);
+        assert_eq!(qt.subtrees()[A].center_of_mass.unwrap().y,37.75);
     }
     #[test]
     fn center_mass(){
@@ -406,8 +685,6 @@ mod tests{
     fn center_mass_two(){
         let rec: Rectangle = Rectangle::new(Vector2::new(0.0f64,0.0f64),Vector2::new(400.0f64,400.0f64));
         let mut qt: Quadtree = Quadtree::new(rec,1);
-        qt.bodies = Vec::new();
-        qt.subtrees = Vec::new();
         let body5: Body = Body::with_pos(Vector2::new(1.0,1.0));
         let body6: Body = Body::with_pos(Vector2::new(2.0,2.0));
         let body7: Body = Body::with_pos(Vector2::new(3.0,3.0));
@@ -447,25 +724,200 @@ mod tests{
         let body4: Body = Body::with_pos(Vector2::new(150.0,150.0));
         qt.insert(body3);
         qt.insert(body4);
-        println!("{:?}",qt.subtrees);
-        assert_eq!(qt.subtrees.len(), 4);
-        assert_eq!(qt.subtrees[A].subtrees[A].bodies.len(),1);
+        println!("{:?}",qt.subtrees());
+        assert_eq!(qt.subtrees().len(), 4);
+        assert_eq!(qt.subtrees()[A].subtrees()[A].bodies.len(),1);
         qt.clear();
-        assert_eq!(qt.subtrees.len(),0);
+        assert_eq!(qt.subtrees().len(),0);
 
     }
 
+    #[test]
+    fn min_size_leaf_keeps_every_body_once(){
+        let rec: Rectangle = Rectangle::new(Vector2::new(0.0f64,0.0f64),Vector2::new(4.0f64,4.0f64));
+        let mut qt: Quadtree = Quadtree::new(rec,1);
+        let bodies: Vec<Body> = vec![
+            Body::with_pos(Vector2::new(0.1,0.1)),
+            Body::with_pos(Vector2::new(0.2,0.2)),
+            Body::with_pos(Vector2::new(0.3,0.3))
+        ];
+        for i in 0..bodies.len(){
+            qt.insert_index(&bodies, i);
+        }
+        let leaf = qt.subtrees()[A].subtrees()[A];
+        assert_eq!(leaf.boundaries.width(), 1.0);
+        let mut indices: Vec<usize> = leaf.bodies.clone();
+        indices.sort();
+        assert_eq!(indices, vec![0, 1, 2]);
+        qt.update_mass_from(&bodies);
+        assert_eq!(qt.root().total_mass, 3.0);
+        qt.update_mass_from(&bodies);
+        assert_eq!(qt.root().total_mass, 3.0);
+    }
+
+    #[test]
+    fn rebuild_reuses_boundaries(){
+        let rec: Rectangle = Rectangle::new(Vector2::new(0.0f64,0.0f64),Vector2::new(100.0f64,100.0f64));
+        let mut qt: Quadtree = Quadtree::new(rec,1);
+        let bodies: Vec<Body> = (0..50).map(|_| Body::random(0.0, 100.0)).collect();
+        for i in 0..bodies.len(){
+            qt.insert_index(&bodies, i);
+        }
+        qt.update_mass_from(&bodies);
+        let node_count = qt.nodes.len();
+        let total: usize = qt.nodes.iter().map(|node| node.bodies.len()).sum();
+        assert_eq!(total, 50);
+        assert_eq!(qt.root().total_mass, 500.0);
+
+        qt.boundaries = Rectangle::new(Vector2::new(-50.0f64,-50.0f64),Vector2::new(150.0f64,150.0f64));
+        qt.clear();
+        assert_eq!(qt.root().boundaries, qt.boundaries);
+        assert_eq!(qt.root().center_of_mass, None);
+        for i in 0..bodies.len(){
+            qt.insert_index(&bodies, i);
+        }
+        qt.update_mass_from(&bodies);
+        assert_ne!(qt.nodes.len(), node_count);
+        assert_eq!(qt.root().total_mass, 500.0);
+    }
+
+    fn assert_same_tree(built: &Quadtree, built_node: &Node, inserted: &Quadtree, inserted_node: &Node){
+        assert_eq!(built_node.boundaries, inserted_node.boundaries);
+        assert_eq!(built_node.is_leaf(), inserted_node.is_leaf());
+        let mut built_bodies = built_node.bodies.clone();
+        let mut inserted_bodies = inserted_node.bodies.clone();
+        built_bodies.sort();
+        inserted_bodies.sort();
+        assert_eq!(built_bodies, inserted_bodies);
+        assert!((built_node.total_mass - inserted_node.total_mass).abs() < 1e-9);
+        match (built_node.center_of_mass, inserted_node.center_of_mass) {
+            (Some(a), Some(b)) => assert!((a.x - b.x).abs() < 1e-9 && (a.y - b.y).abs() < 1e-9),
+            (a, b) => assert_eq!(a, b)
+        }
+        for (a, b) in built.child_nodes(built_node).zip(inserted.child_nodes(inserted_node)){
+            assert_same_tree(built, a, inserted, b);
+        }
+    }
+
+    fn compare_builds(rec: Rectangle, limit: usize, bodies: &[Body]){
+        let mut inserted: Quadtree = Quadtree::new(rec,limit);
+        for i in 0..bodies.len(){
+            inserted.insert_index(bodies, i);
+        }
+        inserted.update_mass_from(bodies);
+        let mut built: Quadtree = Quadtree::new(rec,limit);
+        built.build(bodies);
+        assert_eq!(built.nodes.len(), inserted.nodes.len());
+        assert_same_tree(&built, built.root(), &inserted, inserted.root());
+    }
+
+    #[test]
+    fn build_matches_insert(){
+        let rec: Rectangle = Rectangle::new(Vector2::new(0.0f64,0.0f64),Vector2::new(1024.0f64,1024.0f64));
+        let bodies: Vec<Body> = (0..500).map(|_| Body::random(0.0, 1024.0)).collect();
+        for limit in [1, 4]{
+            compare_builds(rec, limit, &bodies);
+        }
+
+        //bodies on the midpoints, piled up below MIN_SIZE and outside the boundaries
+        let mut bodies: Vec<Body> = Vec::new();
+        for x in 0..=16{
+            for y in 0..=16{
+                bodies.push(Body::with_pos(Vector2::new(x as f64 * 64.0, y as f64 * 64.0)));
+            }
+        }
+        for i in 0..10{
+            bodies.push(Body::with_pos(Vector2::new(300.0 + i as f64 * 0.01, 700.0)));
+        }
+        bodies.push(Body::with_pos(Vector2::new(-20.0, 2000.0)));
+        compare_builds(rec, 1, &bodies);
+    }
+
+    #[test]
+    fn query_radius_matches_scan(){
+        let bodies: Vec<Body> = (0..500).map(|_| Body::random(0.0, 100.0)).collect();
+        let mut qt: Quadtree = Quadtree::new(Rectangle::new(Vector2::new(0.0,0.0),Vector2::new(100.0,100.0)),4);
+        qt.build(&bodies);
+        let mut found: Vec<usize> = Vec::new();
+        for (pos, radius) in [(Vector2::new(50.0, 50.0), 10.0), (Vector2::new(0.0, 100.0), 30.0), (Vector2::new(-5.0, 20.0), 4.0)]{
+            found.clear();
+            qt.query_radius(&bodies, pos, radius, &mut found);
+            found.sort();
+            let scan: Vec<usize> = (0..bodies.len()).filter(|index| (bodies[*index].pos.x - pos.x).hypot(bodies[*index].pos.y - pos.y) <= radius).collect();
+            assert_eq!(found, scan);
+        }
+    }
+
+    #[test]
+    fn build_reuses_tree(){
+        let rec: Rectangle = Rectangle::new(Vector2::new(0.0f64,0.0f64),Vector2::new(100.0f64,100.0f64));
+        let mut qt: Quadtree = Quadtree::new(rec,1);
+        let bodies: Vec<Body> = (0..50).map(|_| Body::random(0.0, 100.0)).collect();
+        qt.build(&bodies);
+        let node_count = qt.nodes.len();
+        qt.build(&bodies);
+        assert_eq!(qt.nodes.len(), node_count);
+        assert_eq!(qt.root().total_mass, 500.0);
+        qt.build(&bodies[..1]);
+        assert_eq!(qt.nodes.len(), 1);
+        assert_eq!(qt.root().bodies, vec![0]);
+    }
+
+    #[test]
+    fn build_deeper_than_keys(){
+        //too many levels for a key, the deepest part is finished by insert
+        let rec: Rectangle = Rectangle::new(Vector2::new(0.0f64,0.0f64),Vector2::new(1e30f64,1e30f64));
+        let bodies: Vec<Body> = vec![
+            Body::with_pos(Vector2::new(10.0,10.0)),
+            Body::with_pos(Vector2::new(13.0,13.0)),
+            Body::with_pos(Vector2::new(5e29,1e29))
+        ];
+        compare_builds(rec, 1, &bodies);
+    }
+
+    #[test]
+    fn quadrupole_matches_direct_sum(){
+        let rec: Rectangle = Rectangle::new(Vector2::new(0.0f64,0.0f64),Vector2::new(256.0f64,256.0f64));
+        let mut qt: Quadtree = Quadtree::new(rec,2);
+        let mut bodies: Vec<Body> = (0..200).map(|_| Body::random(0.0, 256.0)).collect();
+        for (i, body) in bodies.iter_mut().enumerate(){
+            body.mass = 1.0 + (i % 7) as f64;
+        }
+        qt.build(&bodies);
+        for node in &qt.nodes{
+            let mut direct: [f64; 3] = [0.0; 3];
+            let center = match node.center_of_mass {
+                Some(center) => center,
+                None => continue
+            };
+            let mut stack: Vec<&Node> = vec![node];
+            while let Some(below) = stack.pop() {
+                stack.extend(qt.child_nodes(below));
+                for body in below.bodies.iter().map(|index| &bodies[*index]){
+                    let offset = body.pos - center;
+                    direct[0] += body.mass * offset.x * offset.x;
+                    direct[1] += body.mass * offset.x * offset.y;
+                    direct[2] += body.mass * offset.y * offset.y;
+                }
+            }
+            for (tree, sum) in node.quadrupole.iter().zip(direct.iter()){
+                assert!((tree - sum).abs() <= 1e-9 * direct[0].max(direct[2]).max(1.0));
+            }
+        }
+        assert!(qt.root().quadrupole[0] > 0.0);
+    }
+
     #[test]
     fn test_non_positive_boundaries(){
         let rec: Rectangle = Rectangle::new(Vector2::new(-100.0f64,-100.0f64),Vector2::new(100.0f64,100.0f64));
         let mut qt: Quadtree = Quadtree::new(rec,1);
         qt.split();
-        assert_eq!(qt.subtrees.len(), 4);
+        assert_eq!(qt.subtrees().len(), 4);
 
         //finish
 
-        // assert_eq!(qt.subtrees[A].boundaries.tl.x)
+        // assert_eq!(qt.subtrees()[A].boundaries.tl.x)
     }
 
 
-}
\ No newline at end of file
+}
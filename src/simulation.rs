@@ -1,17 +1,41 @@
-use cgmath::{Vector2};
+use cgmath::{InnerSpace, Vector2, Zero};
 
 use crate::body::Body;
+use crate::external::{add_external_forces, ExternalField};
+use crate::force_law::{ForceLaw, Gravity};
 use crate::gravity;
+use crate::gravity::PhysicsParams;
+use crate::hermite::{Hermite, HermiteError};
+use crate::integrator::{ExplicitEuler, Integrator};
 
 #[derive(Debug)]
 pub struct Simulation{
     pub bodies: Vec<Body>,
+    pub integrator: Box<dyn Integrator>,
+    pub params: PhysicsParams,
+    /// The interaction summed between every pair, `Gravity` by default
+    pub law: Box<dyn ForceLaw>,
+    pub time: f64,
+    /// Background fields every body feels on top of the pairwise forces
+    pub external: Vec<Box<dyn ExternalField>>,
 }
 
 impl Simulation {
     pub fn new() -> Self{
         Self {
             bodies: Vec::new(),
+            integrator: Box::new(ExplicitEuler),
+            params: PhysicsParams::default(),
+            law: Box::new(Gravity),
+            time: 0.0,
+            external: Vec::new(),
+        }
+    }
+
+    pub fn with_integrator(integrator: Box<dyn Integrator>) -> Self{
+        Self {
+            integrator,
+            ..Self::new()
         }
     }
 
@@ -41,19 +65,106 @@ impl Simulation {
     // }
 
 
-    pub fn update(&mut self) {
-        for i in 0..self.bodies.len() {
-            for j in i+1..self.bodies.len() {
-                gravity::calculate_force(&mut self.bodies[i..j + 1]);
-            }
+    fn hermite_supported(&self) -> Result<(), HermiteError> {
+        if !self.law.is_gravity() {
+            return Err(HermiteError::NotGravity);
         }
+        if !self.external.is_empty() {
+            return Err(HermiteError::ExternalFields);
+        }
+        Ok(())
+    }
 
-        for body in self.bodies.iter_mut(){
-            gravity::apply_force(body);
+    /// Advances the bodies by `params.dt`, the extra force evaluations of an integrator see the external fields
+    /// as they are at the time of the stage.
+    pub fn update(&mut self) {
+        let params = self.params;
+        let (law, external, start) = (self.law.as_ref(), &self.external, self.time);
+        pairwise_forces_with(law, &mut self.bodies, &params);
+        add_external_forces(external, &mut self.bodies, start, params.g);
+        self.integrator.step(&mut self.bodies, &params, &mut |bodies: &mut [Body], offset: f64| {
+            pairwise_forces_with(law, bodies, &params);
+            add_external_forces(external, bodies, start + offset, params.g);
+        });
+        self.time += params.dt;
+    }
+
+    /// Advances the bodies by `params.dt` with `hermite` instead of `integrator`. Hermite only knows the jerk of
+    /// gravity, so nothing moves if `law` is anything else or there are `external` fields, and a step that runs
+    /// out of substeps leaves the bodies and `time` where it stopped.
+    pub fn update_hermite(&mut self, hermite: &Hermite) -> Result<(), HermiteError> {
+        self.hermite_supported()?;
+        let params = self.params;
+        match hermite.step(&mut self.bodies, &params) {
+            Ok(_) => {
+                self.time += params.dt;
+                Ok(())
+            }
+            Err(error) => {
+                if let HermiteError::TooManySubsteps { elapsed } = error {
+                    self.time += elapsed;
+                }
+                Err(error)
+            }
         }
     }
 
 
 
 
-}
\ No newline at end of file
+}
+
+/// Direct O(n^2) summation of gravity, overwrites the force on every body
+pub fn pairwise_forces(bodies: &mut [Body], params: &PhysicsParams) {
+    pairwise_forces_with(&Gravity, bodies, params);
+}
+
+/// `pairwise_forces` under any force law
+pub fn pairwise_forces_with(law: &dyn ForceLaw, bodies: &mut [Body], params: &PhysicsParams) {
+    for body in bodies.iter_mut(){
+        body.force.x = 0.0f64;
+        body.force.y = 0.0f64;
+    }
+    for j in 1..bodies.len() {
+        let (before, after) = bodies.split_at_mut(j);
+        for body in before.iter_mut() {
+            gravity::calculate_force_law(law, body, &mut after[0], params);
+        }
+    }
+}
+
+/// `pairwise_forces` that also returns the rate of change of every force, from the relative velocities.
+/// Always gravity, this is what `hermite::Hermite` integrates with.
+pub fn pairwise_jerks(bodies: &mut [Body], params: &PhysicsParams) -> Vec<Vector2<f64>> {
+    let mut jerks: Vec<Vector2<f64>> = vec![Vector2::zero(); bodies.len()];
+    for body in bodies.iter_mut(){
+        body.force = Vector2::zero();
+    }
+    for j in 1..bodies.len() {
+        let (before, after) = bodies.split_at_mut(j);
+        let body_b = &mut after[0];
+        for (i, body_a) in before.iter_mut().enumerate() {
+            let (strength_a, strength_b) = (Gravity.strength(body_a), Gravity.strength(body_b));
+            if strength_a == 0.0 && strength_b == 0.0 {
+                continue;
+            }
+            let (d, v) = (body_a.pos - body_b.pos, body_a.velocity - body_b.velocity);
+            let r2 = d.magnitude2();
+            let factor = params.softening.force_factor(r2);
+            //the time derivative of -G factor(r^2) d
+            let field = d * -(params.g * factor);
+            let rate = ((v * factor) + (d * (2.0 * params.softening.force_factor_derivative(r2) * d.dot(v)))) * -params.g;
+            if strength_b != 0.0 {
+                let coupling = Gravity.coupling(body_a) * strength_b;
+                body_a.force += field * coupling;
+                jerks[i] += rate * coupling;
+            }
+            if strength_a != 0.0 {
+                let coupling = strength_a * Gravity.coupling(body_b);
+                body_b.force -= field * coupling;
+                jerks[j] -= rate * coupling;
+            }
+        }
+    }
+    jerks
+}
@@ -0,0 +1,221 @@
+/// How the 1/r^2 force is modified at small separations.
+///
+/// Every force routine in `gravity` asks the kernel for `force_factor(r^2)`, the value that replaces
+/// 1/r^3 in `F = G m_a m_b d / r^3`, so the pairwise and center of mass paths always agree.
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub enum Softening {
+    /// Plain Newtonian gravity. Below `min_distance` the pair is treated as two overlapping uniform
+    /// spheres, so the force falls off linearly to zero instead of diverging.
+    None { min_distance: f64 },
+    /// Plummer softening, 1/r^3 becomes 1/(r^2 + epsilon^2)^(3/2)
+    Plummer { epsilon: f64 },
+    /// Cubic spline kernel (Monaghan & Lattanzio 1985) as used by GADGET, exactly Newtonian beyond `h`.
+    /// GADGET quotes an equivalent Plummer length of `h / 2.8`.
+    CubicSpline { h: f64 },
+}
+
+impl Softening {
+    /// A spline with the same central potential as Plummer softening of length `epsilon`
+    pub fn spline_from_plummer(epsilon: f64) -> Self {
+        Softening::CubicSpline { h: 2.8 * epsilon }
+    }
+
+    /// The Plummer-equivalent length of the softening, below it forces are no longer Newtonian
+    pub fn length(&self) -> f64 {
+        match *self {
+            Softening::None { min_distance } => min_distance,
+            Softening::Plummer { epsilon } => epsilon,
+            Softening::CubicSpline { h } => h / 2.8
+        }
+    }
+
+    /// The factor replacing 1/r^3 for a pair separated by sqrt(`r2`)
+    pub fn force_factor(&self, r2: f64) -> f64 {
+        match *self {
+            Softening::None { min_distance } => {
+                let r = r2.sqrt().max(min_distance);
+                1.0 / (r * r * r)
+            }
+            Softening::Plummer { epsilon } => {
+                let d = r2 + epsilon * epsilon;
+                1.0 / (d * d.sqrt())
+            }
+            Softening::CubicSpline { h } => {
+                let r = r2.sqrt();
+                if r >= h {
+                    return 1.0 / (r2 * r);
+                }
+                let u = r / h;
+                let h3 = h * h * h;
+                if u < 0.5 {
+                    (32.0 / 3.0 + u * u * (32.0 * u - 38.4)) / h3
+                } else {
+                    (64.0 / 3.0 - 48.0 * u + 38.4 * u * u - (32.0 / 3.0) * u * u * u - 1.0 / (15.0 * u * u * u)) / h3
+                }
+            }
+        }
+    }
+
+    /// The derivative of `force_factor` with respect to `r2`, what the jerk of a pair needs
+    pub fn force_factor_derivative(&self, r2: f64) -> f64 {
+        match *self {
+            Softening::None { min_distance } => {
+                let r = r2.sqrt();
+                if r < min_distance {
+                    return 0.0;
+                }
+                -1.5 / (r2 * r2 * r)
+            }
+            Softening::Plummer { epsilon } => {
+                let d = r2 + epsilon * epsilon;
+                -1.5 / (d * d * d.sqrt())
+            }
+            Softening::CubicSpline { h } => {
+                let r = r2.sqrt();
+                if r >= h {
+                    return -1.5 / (r2 * r2 * r);
+                }
+                let u = r / h;
+                let h5 = h * h * h * h * h;
+                //d/du of the factor above over du/d(r^2) = 2 u h^2
+                if u < 0.5 {
+                    (48.0 * u - 38.4) / h5
+                } else {
+                    (-48.0 + 76.8 * u - 32.0 * u * u + 0.2 / (u * u * u * u)) / (2.0 * u * h5)
+                }
+            }
+        }
+    }
+
+    /// The softened 1/r, so the potential energy of a pair is `-G m_a m_b potential(r^2)`
+    pub fn potential(&self, r2: f64) -> f64 {
+        match *self {
+            Softening::None { min_distance } => {
+                let r = r2.sqrt();
+                if r >= min_distance {
+                    return 1.0 / r;
+                }
+                (3.0 * min_distance * min_distance - r2) / (2.0 * min_distance * min_distance * min_distance)
+            }
+            Softening::Plummer { epsilon } => {
+                1.0 / (r2 + epsilon * epsilon).sqrt()
+            }
+            Softening::CubicSpline { h } => {
+                let r = r2.sqrt();
+                if r >= h {
+                    return 1.0 / r;
+                }
+                let u = r / h;
+                let w = if u < 0.5 {
+                    -2.8 + u * u * (16.0 / 3.0 + u * u * (6.4 * u - 9.6))
+                } else {
+                    -3.2 + 1.0 / (15.0 * u) + u * u * (32.0 / 3.0 + u * (-16.0 + u * (9.6 - (32.0 / 15.0) * u)))
+                };
+                -w / h
+            }
+        }
+    }
+}
+
+impl Default for Softening {
+    /// The additive epsilon of 1.0 the force routines have always used
+    fn default() -> Self {
+        Softening::Plummer { epsilon: 1.0 }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use cgmath::Vector2;
+    use crate::body::Body;
+    use crate::gravity::{calculate_force_single, PhysicsParams};
+    use crate::softening::Softening;
+
+    const KERNELS: [Softening; 3] = [
+        Softening::None { min_distance: 0.5 },
+        Softening::Plummer { epsilon: 0.5 },
+        Softening::CubicSpline { h: 1.4 },
+    ];
+
+    fn close(a: f64, b: f64, tolerance: f64) -> bool {
+        (a - b).abs() <= tolerance * b.abs().max(1.0)
+    }
+
+    #[test]
+    fn plummer_profile(){
+        let softening = Softening::Plummer { epsilon: 0.5 };
+        for r in [0.0, 0.1, 0.5, 1.0, 10.0]{
+            let expected = 1.0 / (r * r + 0.25f64).powf(1.5);
+            assert!(close(softening.force_factor(r * r), expected, 1e-12));
+            assert!(close(softening.potential(r * r), 1.0 / (r * r + 0.25f64).sqrt(), 1e-12));
+        }
+    }
+
+    #[test]
+    fn newtonian_outside_support(){
+        for softening in [Softening::None { min_distance: 0.5 }, Softening::CubicSpline { h: 1.4 }]{
+            for r in [1.4, 2.0, 50.0]{
+                assert!(close(softening.force_factor(r * r), 1.0 / (r * r * r), 1e-12));
+                assert!(close(softening.potential(r * r), 1.0 / r, 1e-12));
+            }
+        }
+    }
+
+    #[test]
+    fn spline_center(){
+        let h = 1.4;
+        let softening = Softening::CubicSpline { h };
+        //the force goes to zero linearly with slope 32/3 h^-3, the potential is 2.8/h at the center
+        assert!(close(softening.force_factor(0.0), 32.0 / (3.0 * h * h * h), 1e-12));
+        assert!(close(softening.potential(0.0), 2.8 / h, 1e-12));
+        assert!(close(softening.potential(0.0), Softening::Plummer { epsilon: h / 2.8 }.potential(0.0), 1e-12));
+    }
+
+    #[test]
+    fn profiles_are_continuous(){
+        for softening in KERNELS{
+            for r in [0.5, 0.7, 1.4]{
+                let below = (r - 1e-9) * (r - 1e-9);
+                let above = (r + 1e-9) * (r + 1e-9);
+                assert!(close(softening.force_factor(below), softening.force_factor(above), 1e-6), "{:?} at {}", softening, r);
+                assert!(close(softening.potential(below), softening.potential(above), 1e-6), "{:?} at {}", softening, r);
+            }
+        }
+    }
+
+    #[test]
+    fn force_is_gradient_of_potential(){
+        //|F| = -d(phi)/dr, with |F| = r * force_factor
+        let step = 1e-6;
+        for softening in KERNELS{
+            for i in 1..40{
+                let r = i as f64 * 0.05;
+                let derivative = (softening.potential((r + step) * (r + step)) - softening.potential((r - step) * (r - step))) / (2.0 * step);
+                assert!(close(-derivative, r * softening.force_factor(r * r), 1e-5), "{:?} at {}", softening, r);
+            }
+        }
+    }
+
+    #[test]
+    fn factor_derivative_matches_differences(){
+        let step = 1e-7;
+        for softening in KERNELS{
+            for i in 1..40{
+                //away from the kinks at the edges of the kernels
+                let r2 = (i as f64 * 0.05 + 0.01).powi(2);
+                let derivative = (softening.force_factor(r2 + step) - softening.force_factor(r2 - step)) / (2.0 * step);
+                assert!(close(softening.force_factor_derivative(r2), derivative, 1e-5), "{:?} at {}", softening, r2);
+            }
+        }
+    }
+
+    #[test]
+    fn pairwise_force_uses_kernel(){
+        let params = PhysicsParams::new(1.0, 0.001, Softening::CubicSpline { h: 1.0 });
+        let mut body_a = Body::with_mass_and_pos(2.0, Vector2::new(0.0, 0.0));
+        let mut body_b = Body::with_mass_and_pos(3.0, Vector2::new(4.0, 0.0));
+        calculate_force_single(&mut body_a, &mut body_b, &params);
+        assert!(close(body_a.force.x, 6.0 / 16.0, 1e-12));
+        assert!(close(body_b.force.x, -6.0 / 16.0, 1e-12));
+    }
+}
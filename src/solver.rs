@@ -0,0 +1,20 @@
+use crate::body::Body;
+use crate::gravity::PhysicsParams;
+use crate::quadtree::Quadtree;
+
+/// A gravity solver working on a `Quadtree`, implemented by `BarnesHutRunner`, `FmmSolver` and `PmSolver`
+/// so setups and tools can swap one for another.
+pub trait Solver {
+    fn params(&self) -> &PhysicsParams;
+
+    fn params_mut(&mut self) -> &mut PhysicsParams;
+
+    /// Simulated time so far
+    fn time(&self) -> f64;
+
+    /// Rebuilds `quadtree` around `bodies` and overwrites the force on every body
+    fn evaluate_forces(&self, quadtree: &mut Quadtree, bodies: &mut [Body]);
+
+    /// Advances `bodies` by one step of `params().dt`, does nothing while paused
+    fn iterate(&mut self, quadtree: &mut Quadtree, bodies: &mut Vec<Body>);
+}
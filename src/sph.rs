@@ -0,0 +1,409 @@
+use std::f64::consts::PI;
+use cgmath::{InnerSpace, Vector2, Zero};
+use rayon::prelude::*;
+use crate::body::{Body, Gas};
+use crate::boundary::{minimum_image, Boundary};
+use crate::integrator::for_each_body_with;
+use crate::quadtree::Quadtree;
+
+/// Normalisation of the 2D cubic spline
+const SPLINE_NORM: f64 = 10.0 / (7.0 * PI);
+
+/// Pressure of the gas from its density and specific internal energy
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub enum EquationOfState {
+    /// p = (gamma - 1) rho u
+    Ideal { gamma: f64 },
+    /// p = c^2 rho at a fixed sound speed, the internal energy is left alone
+    Isothermal { sound_speed: f64 }
+}
+
+/// Smoothed-particle hydrodynamics between the `BodyKind::Gas` bodies, set with `BarnesHutRunner::sph`.
+///
+/// Densities are summed over the neighbours `Quadtree::query_radius` finds, with a 2D cubic spline reaching
+/// twice the `Gas::smoothing_length`. Every length is set so the kernel holds `neighbours` gas bodies.
+/// The pressure force and Monaghan's artificial viscosity use the mean kernel gradient of each pair, so
+/// momentum is conserved, and the internal energy takes up the matching pdV work and viscous heating.
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub struct Sph {
+    pub eos: EquationOfState,
+    /// Gas bodies inside the kernel of each one, itself included
+    pub neighbours: usize,
+    /// Linear artificial viscosity
+    pub alpha: f64,
+    /// Quadratic artificial viscosity, keeps particles from streaming through strong shocks
+    pub beta: f64
+}
+
+impl EquationOfState {
+    pub fn pressure(&self, density: f64, internal_energy: f64) -> f64 {
+        match *self {
+            EquationOfState::Ideal { gamma } => (gamma - 1.0) * density * internal_energy,
+            EquationOfState::Isothermal { sound_speed } => sound_speed * sound_speed * density
+        }
+    }
+
+    pub fn sound_speed(&self, internal_energy: f64) -> f64 {
+        match *self {
+            EquationOfState::Ideal { gamma } => (gamma * (gamma - 1.0) * internal_energy).max(0.0).sqrt(),
+            EquationOfState::Isothermal { sound_speed } => sound_speed
+        }
+    }
+}
+
+/// The 2D cubic spline at distance `r` for smoothing length `h`, zero beyond 2 h
+pub fn kernel(r: f64, h: f64) -> f64 {
+    let q = r / h;
+    let shape = if q < 1.0 {
+        1.0 - (1.5 * q * q) + (0.75 * q * q * q)
+    } else if q < 2.0 {
+        0.25 * (2.0 - q).powi(3)
+    } else {
+        0.0
+    };
+    SPLINE_NORM * shape / (h * h)
+}
+
+/// The gradient of `kernel` at separation `d` (r12)
+pub fn kernel_gradient(d: Vector2<f64>, h: f64) -> Vector2<f64> {
+    let r = d.magnitude();
+    if r == 0.0 {
+        return Vector2::zero();
+    }
+    let q = r / h;
+    let slope = if q < 1.0 {
+        (-3.0 * q) + (2.25 * q * q)
+    } else if q < 2.0 {
+        -0.75 * (2.0 - q) * (2.0 - q)
+    } else {
+        0.0
+    };
+    d * (SPLINE_NORM * slope / (h * h * h * r))
+}
+
+/// `f` of every body, spread over the thread pool when called from inside one like `BarnesHutRunner::tree_forces`
+fn map_bodies<T: Send, F: Fn(&Body) -> T + Send + Sync>(bodies: &[Body], f: F) -> Vec<T> {
+    if rayon::current_thread_index().is_some() {
+        bodies.par_iter().map(f).collect()
+    } else {
+        bodies.iter().map(f).collect()
+    }
+}
+
+/// The gas bodies within `radius` of `pos` by index, with their separation from it (r21).
+/// A periodic domain is searched across its periodic edges too.
+fn gas_neighbours(quadtree: &Quadtree, bodies: &[Body], boundary: &Boundary, pos: Vector2<f64>, radius: f64) -> Vec<(usize, Vector2<f64>)> {
+    let mut found: Vec<usize> = Vec::new();
+    match (boundary.domain(), boundary.period()) {
+        (Some(domain), Some(period)) => {
+            //the copies across an edge only matter within `radius` of it
+            let shifts = |value: f64, low: f64, size: f64| {
+                if size.is_infinite() || (value - low).min(low + size - value) > radius { vec![0.0] } else { vec![-size, 0.0, size] }
+            };
+            for x in shifts(pos.x, domain.tl.x, period.x){
+                for y in shifts(pos.y, domain.tl.y, period.y){
+                    quadtree.query_radius(bodies, pos + Vector2::new(x, y), radius, &mut found);
+                }
+            }
+            found.sort_unstable();
+            found.dedup();
+            found.into_iter().filter(|index| bodies[*index].is_gas())
+                .map(|index| (index, minimum_image(pos - bodies[index].pos, period.x, period.y)))
+                .collect()
+        }
+        _ => {
+            quadtree.query_radius(bodies, pos, radius, &mut found);
+            found.into_iter().filter(|index| bodies[*index].is_gas())
+                .map(|index| (index, pos - bodies[index].pos))
+                .collect()
+        }
+    }
+}
+
+impl Sph {
+    pub fn new(eos: EquationOfState, neighbours: usize) -> Self {
+        Self {
+            eos,
+            neighbours,
+            alpha: 1.0,
+            beta: 2.0
+        }
+    }
+
+    /// Sets the smoothing length of every gas body so its kernel holds `neighbours` gas bodies, searching out
+    /// from the last length, then sums its density over the kernel and sets its pressure from `eos`.
+    /// `quadtree` has to be built from `bodies`.
+    pub fn densities(&self, quadtree: &Quadtree, bodies: &mut [Body], boundary: &Boundary){
+        let count = bodies.iter().filter(|body| body.is_gas()).count();
+        if count == 0 {
+            return;
+        }
+        let target = self.neighbours.clamp(1, count);
+        let extent = quadtree.boundaries.width().max(quadtree.boundaries.height());
+        //the radius holding `target` bodies if they were spread evenly over the root
+        let guess = extent * (target as f64 / count as f64).sqrt();
+        let states: Vec<(f64, f64)> = map_bodies(bodies, |body| {
+            if !body.is_gas() {
+                return (0.0, 0.0);
+            }
+            let mut radius = if body.gas.smoothing_length > 0.0 { 2.0 * body.gas.smoothing_length } else { guess };
+            loop {
+                let found = gas_neighbours(quadtree, bodies, boundary, body.pos, radius);
+                if found.is_empty() && radius > 4.0 * extent {
+                    //a body outside the root is missed by the search, it only has itself to go by
+                    return (guess / 2.0, body.mass * kernel(0.0, guess / 2.0));
+                }
+                if found.len() >= target || radius > 4.0 * extent {
+                    let mut neighbours: Vec<(f64, f64)> = found.iter().map(|(index, d)| (d.magnitude(), bodies[*index].mass)).collect();
+                    neighbours.sort_by(|a, b| a.0.total_cmp(&b.0));
+                    let reach = neighbours[(target - 1).min(neighbours.len() - 1)].0;
+                    //bodies on top of each other have no length to go by
+                    let h = if reach > 0.0 { reach / 2.0 } else { guess / 2.0 };
+                    return (h, neighbours.iter().map(|(r, mass)| mass * kernel(*r, h)).sum());
+                }
+                radius *= 1.5;
+            }
+        });
+        for_each_body_with(bodies, &states, |body, (h, density)| {
+            if body.is_gas() {
+                body.gas.smoothing_length = *h;
+                body.gas.density = *density;
+                body.gas.pressure = self.eos.pressure(*density, body.gas.internal_energy);
+            }
+        });
+    }
+
+    /// Updates the smoothing lengths, densities and pressures, then adds the pressure and viscous force
+    /// to every gas body and sets its `Gas::energy_rate`. `quadtree` has to be built from `bodies`.
+    pub fn add_forces(&self, quadtree: &Quadtree, bodies: &mut [Body], boundary: &Boundary){
+        self.densities(quadtree, bodies, boundary);
+        //a pair interacts while either kernel reaches the other body
+        let reach = 2.0 * bodies.iter().filter(|body| body.is_gas()).map(|body| body.gas.smoothing_length).fold(0.0, f64::max);
+        let rates: Vec<(Vector2<f64>, f64)> = map_bodies(bodies, |body| {
+            if body.is_gas() { self.pair_sums(quadtree, bodies, boundary, body, reach) } else { (Vector2::zero(), 0.0) }
+        });
+        for_each_body_with(bodies, &rates, |body, (acceleration, rate)| {
+            if body.is_gas() {
+                body.force += acceleration * body.mass;
+                body.gas.energy_rate = *rate;
+            }
+        });
+    }
+
+    /// Acceleration and rate of change of internal energy of gas `body` from the gas within `reach`
+    fn pair_sums(&self, quadtree: &Quadtree, bodies: &[Body], boundary: &Boundary, body: &Body, reach: f64) -> (Vector2<f64>, f64) {
+        let own = body.gas;
+        let own_pressure = own.pressure / (own.density * own.density);
+        let mut acceleration: Vector2<f64> = Vector2::zero();
+        let mut rate: f64 = 0.0;
+        for (index, d) in gas_neighbours(quadtree, bodies, boundary, body.pos, reach){
+            let other = &bodies[index];
+            let gas = other.gas;
+            let r = d.magnitude();
+            if r == 0.0 || r >= 2.0 * own.smoothing_length.max(gas.smoothing_length) {
+                continue;
+            }
+            let gradient = (kernel_gradient(d, own.smoothing_length) + kernel_gradient(d, gas.smoothing_length)) * 0.5;
+            let velocity = body.velocity - other.velocity;
+            let viscosity = self.viscosity(d, velocity, &own, &gas);
+            acceleration -= gradient * (other.mass * (own_pressure + (gas.pressure / (gas.density * gas.density)) + viscosity));
+            rate += other.mass * (own_pressure + (0.5 * viscosity)) * velocity.dot(gradient);
+        }
+        match self.eos {
+            EquationOfState::Ideal { .. } => (acceleration, rate),
+            EquationOfState::Isothermal { .. } => (acceleration, 0.0)
+        }
+    }
+
+    /// Monaghan's viscous pressure term for a pair at separation `d` closing in with relative `velocity`
+    fn viscosity(&self, d: Vector2<f64>, velocity: Vector2<f64>, a: &Gas, b: &Gas) -> f64 {
+        let closing = velocity.dot(d);
+        if closing >= 0.0 {
+            return 0.0;
+        }
+        let h = 0.5 * (a.smoothing_length + b.smoothing_length);
+        let mu = h * closing / (d.magnitude2() + (0.01 * h * h));
+        let sound = 0.5 * (self.eos.sound_speed(a.internal_energy) + self.eos.sound_speed(b.internal_energy));
+        ((-self.alpha * sound * mu) + (self.beta * mu * mu)) / (0.5 * (a.density + b.density))
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::f64::consts::PI;
+    use cgmath::{InnerSpace, Vector2};
+    use crate::bh_runner::BarnesHutRunner;
+    use crate::body::Body;
+    use crate::boundary::{minimum_image, Boundary};
+    use crate::diagnostics::DiagnosticsLog;
+    use crate::gravity::PhysicsParams;
+    use crate::integrator::Leapfrog;
+    use crate::quadtree::{Quadtree, Rectangle};
+    use crate::softening::Softening;
+    use crate::sph::{kernel, kernel_gradient, EquationOfState, Sph};
+
+    const GAMMA: f64 = 1.4;
+
+    fn quadtree(domain: Rectangle) -> Quadtree {
+        Quadtree::new(domain,1)
+    }
+
+    fn ideal(neighbours: usize) -> Sph {
+        Sph::new(EquationOfState::Ideal { gamma: GAMMA }, neighbours)
+    }
+
+    #[test]
+    fn kernel_is_normalised(){
+        let h = 0.7;
+        //midpoint rule over rings out to 2 h
+        let rings = 2000;
+        let dr = 2.0 * h / rings as f64;
+        let total: f64 = (0..rings).map(|i| {
+            let r = (i as f64 + 0.5) * dr;
+            2.0 * PI * r * kernel(r, h) * dr
+        }).sum();
+        assert!((total - 1.0).abs() < 1e-6, "{}", total);
+        assert_eq!(kernel(2.0 * h, h), 0.0);
+
+        let step = 1e-6;
+        for d in [Vector2::new(0.3, -0.2), Vector2::new(0.9, 0.5), Vector2::new(-1.0, 0.6)]{
+            let numeric = Vector2::new(
+                kernel((d + Vector2::new(step, 0.0)).magnitude(), h) - kernel((d - Vector2::new(step, 0.0)).magnitude(), h),
+                kernel((d + Vector2::new(0.0, step)).magnitude(), h) - kernel((d - Vector2::new(0.0, step)).magnitude(), h)
+            ) / (2.0 * step);
+            assert!((kernel_gradient(d, h) - numeric).magnitude() < 1e-6, "{:?} {:?}", kernel_gradient(d, h), numeric);
+        }
+    }
+
+    #[test]
+    fn uniform_lattice_is_in_balance(){
+        //unit density on a periodic lattice
+        let domain = Rectangle::new(Vector2::new(0.0,0.0),Vector2::new(20.0,20.0));
+        let mut bodies: Vec<Body> = (0..400).map(|i| Body::gas(1.0, Vector2::new((i % 20) as f64 + 0.5, (i / 20) as f64 + 0.5), 1.0)).collect();
+        bodies.push(Body::with_mass_and_pos(5.0, Vector2::new(10.0, 10.2)));
+        let mut qt = quadtree(domain);
+        qt.build(&bodies);
+        let sph = ideal(30);
+        sph.add_forces(&qt, &mut bodies, &Boundary::Periodic(domain));
+        for body in bodies.iter().filter(|body| body.is_gas()){
+            assert!((body.gas.density - 1.0).abs() < 2e-2, "{:?}", body.gas);
+            assert!((body.gas.pressure - (GAMMA - 1.0) * body.gas.density).abs() < 1e-12);
+            assert!(body.force.magnitude() < 1e-9, "{:?}", body.force);
+            //ties on the lattice can only add neighbours
+            let inside = bodies.iter().filter(|other| other.is_gas() && minimum_image(other.pos - body.pos, 20.0, 20.0).magnitude() <= 2.0 * body.gas.smoothing_length).count();
+            assert!((30..40).contains(&inside), "{}", inside);
+        }
+        //the collisionless body is left alone
+        assert_eq!(bodies[400].force, Vector2::new(0.0, 0.0));
+        assert_eq!(bodies[400].gas.density, 0.0);
+    }
+
+    #[test]
+    fn body_outside_the_root_keeps_a_density(){
+        let domain = Rectangle::new(Vector2::new(0.0,0.0),Vector2::new(10.0,10.0));
+        let mut bodies: Vec<Body> = (0..100).map(|i| Body::gas(1.0, Vector2::new((i % 10) as f64 + 0.5, (i / 10) as f64 + 0.5), 1.0)).collect();
+        let mut qt = quadtree(domain);
+        qt.build(&bodies);
+        //moved out after the tree was built, as a drift does before a reflective or absorbing boundary acts
+        bodies[0].pos = Vector2::new(-100.0, -100.0);
+        let sph = ideal(10);
+        sph.add_forces(&qt, &mut bodies, &Boundary::Reflective(domain));
+        let body = &bodies[0].gas;
+        assert!(body.smoothing_length > 0.0 && body.density > 0.0 && body.pressure > 0.0, "{:?}", body);
+        assert!(bodies.iter().all(|body| body.force.x.is_finite() && body.force.y.is_finite()));
+    }
+
+    /// A periodic strip holding two mirrored Sod tubes, dense gas in the middle half of a domain twice as wide.
+    /// Every body sits on the same lattice of `spacing` and the density jump comes from the mass.
+    fn sod_tubes(spacing: f64, rows: usize) -> (Rectangle, Vec<Body>) {
+        let columns = (2.0 / spacing).round() as usize;
+        let domain = Rectangle::new(Vector2::new(0.0,0.0),Vector2::new(2.0, rows as f64 * spacing));
+        let mut bodies: Vec<Body> = Vec::new();
+        for i in 0..columns{
+            for j in 0..rows{
+                let pos = Vector2::new((i as f64 + 0.5) * spacing, (j as f64 + 0.5) * spacing);
+                //left state rho 1, p 1 and right state rho 0.125, p 0.1
+                let (density, pressure) = if pos.x > 0.5 && pos.x < 1.5 { (1.0, 1.0) } else { (0.125, 0.1) };
+                bodies.push(Body::gas(density * spacing * spacing, pos, pressure / ((GAMMA - 1.0) * density)));
+            }
+        }
+        (domain, bodies)
+    }
+
+    /// Mean of `value` over the bodies with x in `range`
+    fn mean(bodies: &[Body], range: (f64, f64), value: fn(&Body) -> f64) -> f64 {
+        let inside: Vec<f64> = bodies.iter().filter(|body| body.pos.x > range.0 && body.pos.x < range.1).map(value).collect();
+        inside.iter().sum::<f64>() / inside.len() as f64
+    }
+
+    #[test]
+    fn sod_shock_tube(){
+        let (domain, mut bodies) = sod_tubes(0.025, 8);
+        let mut runner = BarnesHutRunner::with_integrator(0.5, Box::new(Leapfrog));
+        //weak self-gravity, so the walk and the Ewald correction run alongside the pressure without moving the shock
+        runner.params = PhysicsParams::new(1e-3, 0.0025, Softening::default());
+        runner.set_boundary(Boundary::Periodic(domain));
+        runner.sph = Some(ideal(30));
+        let mut qt = quadtree(domain);
+        let mut log = DiagnosticsLog::new();
+        log.exact_limit = 0;
+        log.record_runner(&runner, &mut qt, &bodies);
+        for _ in 0..80{
+            runner.iterate(&mut qt, &mut bodies);
+        }
+        log.record_runner(&runner, &mut qt, &bodies);
+        assert!((runner.time - 0.2).abs() < 1e-9);
+
+        //exact solution at t = 0.2 right of the interface at x = 1.5: contact at 1.685, shock at 1.850,
+        //with rho 0.266 and u 0.927 between them
+        let density = mean(&bodies, (1.72, 1.82), |body| body.gas.density);
+        let velocity = mean(&bodies, (1.72, 1.82), |body| body.velocity.x);
+        assert!((density - 0.266).abs() < 0.15 * 0.266, "{}", density);
+        assert!((velocity - 0.927).abs() < 0.1 * 0.927, "{}", velocity);
+        //the mirrored tube runs the other way
+        assert!((mean(&bodies, (0.18, 0.28), |body| body.velocity.x) + velocity).abs() < 0.05, "{}", velocity);
+        //ahead of the shock the gas has not moved yet
+        assert!((mean(&bodies, (1.92, 2.0), |body| body.gas.density) - 0.125).abs() < 0.05 * 0.125);
+        assert!(mean(&bodies, (1.92, 2.0), |body| body.velocity.x).abs() < 1e-2);
+
+        //kinetic and thermal energy trade off, momentum stays zero
+        assert!(log.records[0].potential_energy < 0.0);
+        assert!(log.max_relative_energy_drift() < 3e-3, "{}", log.max_relative_energy_drift());
+        assert!(log.records[1].thermal_energy < log.records[0].thermal_energy);
+        assert!(log.records[1].momentum.magnitude() < 1e-9);
+    }
+
+    #[test]
+    fn collapsing_cloud(){
+        //a cold disk of gas, densest in the middle, falls in on itself and heats up
+        let golden = PI * (3.0 - 5.0f64.sqrt());
+        let count = 200;
+        let mut bodies: Vec<Body> = (0..count).map(|k| {
+            let r = (k as f64 + 0.5) / count as f64;
+            let angle = golden * k as f64;
+            Body::gas(1.0 / count as f64, Vector2::new(r * angle.cos(), r * angle.sin()), 0.05)
+        }).collect();
+        let mut runner = BarnesHutRunner::with_integrator(0.5, Box::new(Leapfrog));
+        runner.params = PhysicsParams::new(1.0, 0.002, Softening::Plummer { epsilon: 0.05 });
+        runner.sph = Some(ideal(30));
+        let mut qt = quadtree(Rectangle::new(Vector2::new(-1.0,-1.0),Vector2::new(1.0,1.0)));
+        let mut log = DiagnosticsLog::new();
+        log.record_runner(&runner, &mut qt, &bodies);
+        runner.force_iterate(&mut qt, &mut bodies);
+        let start = bodies.iter().map(|body| body.gas.density).fold(0.0, f64::max);
+        for step in 1..120{
+            runner.iterate(&mut qt, &mut bodies);
+            if step % 10 == 0 {
+                log.record_runner(&runner, &mut qt, &bodies);
+            }
+        }
+        let peak = bodies.iter().map(|body| body.gas.density).fold(0.0, f64::max);
+        assert!(peak > 5.0 * start, "{} {}", peak, start);
+        //the infall goes into heat and the total is kept
+        let (first, last) = (log.records[0], log.records[log.records.len() - 1]);
+        assert!(last.thermal_energy > 3.0 * first.thermal_energy, "{:?}", last);
+        assert!(last.kinetic_energy > 0.1);
+        assert!(log.max_relative_energy_drift() < 2e-3, "{}", log.max_relative_energy_drift());
+        assert!(last.momentum.magnitude() < 1e-12);
+    }
+}
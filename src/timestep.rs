@@ -0,0 +1,244 @@
+use cgmath::{InnerSpace, Vector2, Zero};
+use crate::body::Body;
+use crate::gravity::PhysicsParams;
+use crate::integrator::{for_each_body, for_each_body_with};
+
+/// Individual timesteps in power-of-two blocks below `params.dt`, set with `BarnesHutRunner::timesteps`.
+///
+/// At the start of every step each body asks for sqrt(2 eta epsilon / |a|), epsilon the softening length, or
+/// for eta |v| / |a| when there is no softening length to go by, and is put in the bin of the longest `params.dt / 2^level` that is no longer than that. The step is then
+/// walked in ticks of the deepest bin in use: bodies at the start of their own step get half a kick, everyone
+/// drifts, and only the bodies at the end of their step get new forces and the closing half kick.
+/// Every body is synchronised again at the end of the step.
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub struct BlockTimesteps {
+    /// Accuracy parameter of the criterion, smaller is more accurate
+    pub eta: f64,
+    /// The deepest bin, the shortest step is `params.dt / 2^max_level`
+    pub max_level: u32
+}
+
+/// Works out new forces for the bodies at the given indices, see `BlockTimesteps::step`
+pub type SubsetForces<'a> = dyn FnMut(&mut [Body], &[usize], f64) + 'a;
+
+/// What one `BlockTimesteps::step` did
+#[derive(Debug,Clone,PartialEq)]
+pub struct BlockStats {
+    /// Bodies in each bin, by level
+    pub bins: Vec<usize>,
+    /// Forces worked out over the step, a fixed step at the deepest bin would need bodies times ticks
+    pub force_evaluations: usize
+}
+
+impl BlockTimesteps {
+    pub fn new(eta: f64, max_level: u32) -> Self {
+        Self {
+            eta,
+            max_level
+        }
+    }
+
+    /// The step a body asks for from its acceleration, unbounded when nothing pulls on it. Without a softening
+    /// length the time to change its velocity by itself is used, a body at rest then gets the deepest bin.
+    pub fn criterion(&self, body: &Body, params: &PhysicsParams) -> f64 {
+        let magnitude = body.acceleration.magnitude();
+        if magnitude == 0.0 {
+            return f64::INFINITY;
+        }
+        let length = params.softening.length();
+        if length == 0.0 {
+            return self.eta * body.velocity.magnitude() / magnitude;
+        }
+        (2.0 * self.eta * length / magnitude).sqrt()
+    }
+
+    /// The bin of the longest `dt / 2^level` no longer than `step`, at most `max_level`
+    pub fn level(&self, step: f64, dt: f64) -> u32 {
+        let mut level = 0;
+        while level < self.max_level && dt / (1u64 << level) as f64 > step {
+            level += 1;
+        }
+        level
+    }
+
+    /// Advances `bodies` by `params.dt`. On entry every body's `force` must hold the force at its position.
+    /// `forces` is handed the bodies, the indices of those that need a new force and the time since the start
+    /// of the step, and has to overwrite the force of those bodies. Forces are cleared when the step returns.
+    pub fn step(&self, bodies: &mut [Body], params: &PhysicsParams, forces: &mut SubsetForces) -> BlockStats {
+        for_each_body(bodies, |body| body.acceleration = body.force / body.test_mass());
+        let levels: Vec<u32> = bodies.iter().map(|body| self.level(self.criterion(body, params), params.dt)).collect();
+        let deepest = levels.iter().copied().max().unwrap_or(0);
+        let ticks: u64 = 1 << deepest;
+        let tick = params.dt / ticks as f64;
+        //the step of every body in ticks
+        let strides: Vec<u64> = levels.iter().map(|level| 1 << (deepest - level)).collect();
+
+        let mut stats = BlockStats {
+            bins: vec![0; deepest as usize + 1],
+            force_evaluations: 0
+        };
+        for level in &levels{
+            stats.bins[*level as usize] += 1;
+        }
+
+        let mut active: Vec<usize> = Vec::new();
+        for t in 0..ticks{
+            for_each_body_with(bodies, &strides, |body, stride| {
+                if t % stride == 0 {
+                    body.velocity += body.acceleration * (*stride as f64 * tick / 2.0);
+                }
+            });
+            for_each_body(bodies, |body| body.pos += body.velocity * tick);
+
+            active.clear();
+            active.extend(strides.iter().enumerate().filter(|(_, stride)| (t + 1) % **stride == 0).map(|(index, _)| index));
+            forces(bodies, &active, (t + 1) as f64 * tick);
+            stats.force_evaluations += active.len();
+            for index in &active{
+                let body = &mut bodies[*index];
+                body.acceleration = body.force / body.test_mass();
+                body.velocity += body.acceleration * (strides[*index] as f64 * tick / 2.0);
+            }
+        }
+        for_each_body(bodies, |body| body.force = Vector2::zero());
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use cgmath::{InnerSpace, Vector2};
+    use crate::bh_runner::BarnesHutRunner;
+    use crate::body::Body;
+    use crate::diagnostics::DiagnosticsLog;
+    use crate::gravity::PhysicsParams;
+    use crate::integrator::{Integrator, Leapfrog};
+    use crate::quadtree::{Quadtree, Rectangle};
+    use crate::softening::Softening;
+    use crate::timestep::BlockTimesteps;
+
+    //unit masses on springs, the second one 64 times as stiff
+    fn springs(bodies: &mut [Body]){
+        for (index, body) in bodies.iter_mut().enumerate(){
+            body.force = -body.pos * if index == 0 { 1.0 } else { 64.0 };
+        }
+    }
+
+    fn start() -> Vec<Body> {
+        vec![Body::with_mass_and_pos(1.0, Vector2::new(1.0, 0.0)), Body::with_mass_and_pos(1.0, Vector2::new(0.0, 1.0))]
+    }
+
+    fn params(dt: f64) -> PhysicsParams {
+        PhysicsParams::new(1.0, dt, Softening::Plummer { epsilon: 0.01 })
+    }
+
+    #[test]
+    fn levels_follow_the_criterion(){
+        let timesteps = BlockTimesteps::new(0.5, 4);
+        assert_eq!(timesteps.level(1.0, 1.0), 0);
+        assert_eq!(timesteps.level(0.3, 1.0), 2);
+        assert_eq!(timesteps.level(0.25, 1.0), 2);
+        assert_eq!(timesteps.level(1e-9, 1.0), 4);
+        //sqrt(2 eta epsilon / |a|)
+        let mut body = Body::with_pos(Vector2::new(0.0, 0.0));
+        assert_eq!(timesteps.criterion(&body, &params(1.0)), f64::INFINITY);
+        body.acceleration = Vector2::new(0.0, 0.04);
+        assert!((timesteps.criterion(&body, &params(1.0)) - 0.5).abs() < 1e-12);
+    }
+
+    #[test]
+    fn zero_softening_goes_by_the_velocity(){
+        let unsoftened = PhysicsParams::new(1.0, 0.05, Softening::None { min_distance: 0.0 });
+        let timesteps = BlockTimesteps::new(0.2, 6);
+        //eta |v| / |a|
+        let mut body = Body::with_pos(Vector2::new(0.0, 0.0));
+        body.acceleration = Vector2::new(0.0, 4.0);
+        body.velocity = Vector2::new(2.0, 0.0);
+        assert!((timesteps.criterion(&body, &unsoftened) - 0.1).abs() < 1e-12);
+
+        //the same circular orbits as below, |v| / |a| is 1 and 1 / 8
+        let mut block = start();
+        block[0].velocity = Vector2::new(0.0, 1.0);
+        block[1].velocity = Vector2::new(-8.0, 0.0);
+        springs(&mut block);
+        let stats = timesteps.step(&mut block, &unsoftened, &mut |bodies: &mut [Body], _, _| springs(bodies));
+        assert_eq!(stats.bins, vec![1, 1]);
+    }
+
+    #[test]
+    fn one_bin_is_leapfrog(){
+        let timesteps = BlockTimesteps::new(1e6, 4);
+        let (mut block, mut leapfrog) = (start(), start());
+        for _ in 0..50{
+            springs(&mut block);
+            let stats = timesteps.step(&mut block, &params(0.01), &mut |bodies: &mut [Body], active: &[usize], _| {
+                assert_eq!(active, &[0, 1]);
+                springs(bodies);
+            });
+            assert_eq!(stats.bins, vec![2]);
+            springs(&mut leapfrog);
+            Leapfrog.step(&mut leapfrog, &params(0.01), &mut |bodies: &mut [Body], _| springs(bodies));
+        }
+        for (a, b) in block.iter().zip(leapfrog.iter()){
+            assert!((a.pos - b.pos).magnitude() < 1e-14 && (a.velocity - b.velocity).magnitude() < 1e-14);
+        }
+    }
+
+    #[test]
+    fn every_bin_is_its_own_leapfrog(){
+        //circular orbits keep |a| fixed, the stiff spring asks for a step 8 times shorter and gets one
+        let timesteps = BlockTimesteps::new(0.2, 6);
+        let mut block = start();
+        block[0].velocity = Vector2::new(0.0, 1.0);
+        block[1].velocity = Vector2::new(-8.0, 0.0);
+        let (mut slow, mut fast) = (vec![block[0]], vec![block[1]]);
+        let mut evaluations = 0;
+        for _ in 0..20{
+            springs(&mut block);
+            let stats = timesteps.step(&mut block, &params(0.05), &mut |bodies: &mut [Body], _, _| springs(bodies));
+            assert_eq!(stats.bins, vec![1, 0, 0, 1]);
+            evaluations += stats.force_evaluations;
+            slow[0].force = -slow[0].pos;
+            Leapfrog.step(&mut slow, &params(0.05), &mut |bodies: &mut [Body], _| bodies[0].force = -bodies[0].pos);
+            for _ in 0..8{
+                fast[0].force = -fast[0].pos * 64.0;
+                Leapfrog.step(&mut fast, &params(0.05 / 8.0), &mut |bodies: &mut [Body], _| bodies[0].force = -bodies[0].pos * 64.0);
+            }
+        }
+        assert!((block[0].pos - slow[0].pos).magnitude() < 1e-14 && (block[0].velocity - slow[0].velocity).magnitude() < 1e-14);
+        assert!((block[1].pos - fast[0].pos).magnitude() < 1e-14 && (block[1].velocity - fast[0].velocity).magnitude() < 1e-14);
+        assert_eq!(evaluations, 20 * (1 + 8));
+    }
+
+    #[test]
+    fn block_timesteps_beat_the_fixed_step(){
+        let mut runner = BarnesHutRunner::with_integrator(0.5, Box::new(Leapfrog));
+        runner.params = PhysicsParams::new(1.0, 0.05, Softening::Plummer { epsilon: 0.01 });
+        let mut bodies: Vec<Body> = Vec::new();
+        runner.generate_plummer(&mut bodies, Vector2::new(0.0, 0.0), 200, 1.0, 1.0);
+        let mut drifts: Vec<f64> = Vec::new();
+        let mut evaluations = 0;
+        for timesteps in [None, Some(BlockTimesteps::new(0.02, 5))]{
+            runner.timesteps = timesteps;
+            let mut run: Vec<Body> = bodies.clone();
+            let mut qt: Quadtree = Quadtree::new(Rectangle::new(Vector2::new(0.0,0.0),Vector2::new(1.0,1.0)),1);
+            let mut log = DiagnosticsLog::new();
+            log.record_runner(&runner, &mut qt, &run);
+            for _ in 0..40{
+                runner.iterate(&mut qt, &mut run);
+                if let Some(stats) = runner.block_stats() {
+                    evaluations += stats.force_evaluations;
+                }
+                log.record_runner(&runner, &mut qt, &run);
+            }
+            drifts.push(log.max_relative_energy_drift());
+        }
+        //the dense core gets the short steps it needs
+        assert!(drifts[1] < 0.1 * drifts[0], "{:?}", drifts);
+        let stats = runner.block_stats().unwrap();
+        assert_eq!(stats.bins.iter().sum::<usize>(), 200);
+        assert!(stats.bins.iter().filter(|count| **count > 0).count() >= 3, "{:?}", stats);
+        //for far less work than running everything at the shortest step
+        assert!(evaluations < 200 * 40 * 32 / 2, "{}", evaluations);
+    }
+}
@@ -0,0 +1,330 @@
+use std::f64::consts::PI;
+use cgmath::{Vector2, Zero};
+use crate::bh_runner::BarnesHutRunner;
+use crate::body::Body;
+use crate::boundary::Boundary;
+use crate::force_law::ForceLaw;
+use crate::gravity::PhysicsParams;
+use crate::integrator::{for_each_body, for_each_body_with};
+use crate::quadtree::{Quadtree, Rectangle};
+
+/// Velocity induced by 2D point vortices, the `circulation` of a body at the origin moves a point at d with
+/// Γ / 2 pi (-d.y, d.x) / (r^2 + core^2), counter-clockwise for positive Γ.
+///
+/// `core` smooths the singularity into a vortex blob, the softening of `PhysicsParams` is not used.
+/// Nodes are seen as a single vortex of their total circulation at the centroid of |Γ|, so the tree is
+/// accurate for patches of one sign. Tracers are carried along without inducing anything.
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub struct BiotSavart {
+    pub core: f64,
+    /// When set every vortex stands for an infinite row of copies this far apart along x, and the kernel is
+    /// the closed form sum over the row (Krasny 1986). Use the width of a `Boundary::PeriodicX` domain, see
+    /// `VortexSolver::periodic`. Copies along y are not summed: a box periodic in y can't hold the net
+    /// circulation of a sheet.
+    pub period: Option<f64>
+}
+
+/// Moves point vortices with the velocity they induce on each other, found by the tree walk of `runner`
+/// under `BiotSavart`. Vortices don't accelerate, `velocity` is overwritten every step and the positions
+/// follow it with the second order midpoint rule.
+pub struct VortexSolver {
+    /// Does the walk, its `params.dt` is the step and its `boundary` the domain
+    pub runner: BarnesHutRunner,
+    pub paused: bool
+}
+
+impl ForceLaw for BiotSavart {
+    fn strength(&self, body: &Body) -> f64 {
+        if body.is_tracer() { 0.0 } else { body.circulation }
+    }
+
+    fn coupling(&self, _body: &Body) -> f64 {
+        1.0
+    }
+
+    fn kernel(&self, d: Vector2<f64>, _params: &PhysicsParams) -> Vector2<f64> {
+        if let Some(period) = self.period {
+            return self.row_kernel(d, period);
+        }
+        let r2 = (d.x * d.x) + (d.y * d.y) + (self.core * self.core);
+        //a vortex does not move itself
+        if r2 == 0.0 {
+            return Vector2::zero();
+        }
+        Vector2::new(-d.y, d.x) / (2.0 * PI * r2)
+    }
+}
+
+impl BiotSavart {
+    pub fn new(core: f64) -> Self {
+        Self {
+            core,
+            period: None
+        }
+    }
+
+    /// Sums the copies of every vortex `period` apart along x
+    pub fn row(core: f64, period: f64) -> Self {
+        Self {
+            core,
+            period: Some(period)
+        }
+    }
+
+    /// The velocity of a row of unit vortices `period` apart, (-sinh ky, sin kx) / 2 L (cosh ky - cos kx + δ^2)
+    /// with k = 2 pi / L. δ^2 = (k core)^2 / 2 makes it the blob kernel close to the vortex.
+    fn row_kernel(&self, d: Vector2<f64>, period: f64) -> Vector2<f64> {
+        let k = 2.0 * PI / period;
+        //far from the row it is a uniform stream of half the circulation per length, and cosh would overflow
+        if (k * d.y).abs() > 20.0 {
+            return Vector2::new(-d.y.signum(), 0.0) / (2.0 * period);
+        }
+        let delta2 = (k * self.core).powi(2) / 2.0;
+        let denominator = (k * d.y).cosh() - (k * d.x).cos() + delta2;
+        if denominator == 0.0 {
+            return Vector2::zero();
+        }
+        Vector2::new(-(k * d.y).sinh(), (k * d.x).sin()) / (2.0 * period * denominator)
+    }
+}
+
+impl VortexSolver {
+    pub fn new(theta: f64, core: f64) -> Self {
+        let mut runner = BarnesHutRunner::from_theta(theta);
+        runner.law = Box::new(BiotSavart::new(core));
+        Self {
+            runner,
+            paused: false
+        }
+    }
+
+    /// A solver for flows periodic in x over `domain`, the vortices feel every copy of each other along x
+    pub fn periodic(theta: f64, core: f64, domain: Rectangle) -> Self {
+        let mut solver = Self::new(theta, core);
+        solver.runner.law = Box::new(BiotSavart::row(core, domain.width()));
+        solver.runner.set_boundary(Boundary::PeriodicX(domain));
+        solver
+    }
+
+    /// Rebuilds the tree around `bodies` and sets the velocity of every body to the one the vortices induce there
+    pub fn induced_velocities(&self, quadtree: &mut Quadtree, bodies: &mut [Body]){
+        self.runner.evaluate_forces(quadtree, bodies);
+        for_each_body(bodies, |body: &mut Body| {
+            body.velocity = body.force;
+            body.force = Vector2::zero();
+        });
+    }
+
+    pub fn update(&mut self, quadtree: &mut Quadtree, bodies: &mut Vec<Body>){
+        let dt = self.runner.params.dt;
+        self.runner.install(|| {
+            let start: Vec<Vector2<f64>> = bodies.iter().map(|body| body.pos).collect();
+            self.induced_velocities(quadtree, bodies);
+            for_each_body(bodies, |body: &mut Body| body.pos += body.velocity * (dt / 2.0));
+            self.induced_velocities(quadtree, bodies);
+            for_each_body_with(bodies, &start, |body: &mut Body, start: &Vector2<f64>| body.pos = start + (body.velocity * dt));
+        });
+        self.runner.boundary().apply(bodies);
+        self.runner.time += dt;
+    }
+
+    pub fn iterate(&mut self, quadtree: &mut Quadtree, bodies: &mut Vec<Body>){
+        if self.paused {
+            return;
+        }
+        self.update(quadtree, bodies);
+    }
+}
+
+/// A vortex sheet across the middle of `domain` for the Kelvin-Helmholtz instability. `count` equal vortices
+/// carry a velocity jump of `shear` across the sheet, which is displaced by a sine of `amplitude` with
+/// `waves` wavelengths over the width. Meant for `VortexSolver::periodic` on `domain`, which makes the sheet
+/// infinite along x.
+pub fn kelvin_helmholtz(domain: &Rectangle, count: usize, shear: f64, amplitude: f64, waves: usize) -> Vec<Body> {
+    let (width, middle) = (domain.width(), domain.midpoint().y);
+    let spacing = width / count as f64;
+    (0..count).map(|i| {
+        let x = (i as f64 + 0.5) * spacing;
+        let y = middle + (amplitude * (2.0 * PI * waves as f64 * x / width).sin());
+        Body::with_pos(Vector2::new(domain.tl.x + x, y)).with_circulation(shear * spacing)
+    }).collect()
+}
+
+/// Two co-rotating patches of `count` vortices in a disk of `radius`, `separation` apart around `center`,
+/// each of total `circulation`. Patches closer than about 3.3 radii merge.
+pub fn vortex_merger(center: Vector2<f64>, separation: f64, radius: f64, count: usize, circulation: f64) -> Vec<Body> {
+    //golden angle spiral, evenly spread over the disk
+    let golden = PI * (3.0 - 5.0f64.sqrt());
+    let mut bodies: Vec<Body> = Vec::with_capacity(2 * count);
+    for side in [-0.5, 0.5]{
+        let patch = center + Vector2::new(side * separation, 0.0);
+        for k in 0..count{
+            let r = radius * ((k as f64 + 0.5) / count as f64).sqrt();
+            let angle = golden * k as f64;
+            bodies.push(Body::with_pos(patch + Vector2::new(r * angle.cos(), r * angle.sin())).with_circulation(circulation / count as f64));
+        }
+    }
+    bodies
+}
+
+#[cfg(test)]
+mod tests{
+    use std::f64::consts::PI;
+    use cgmath::{InnerSpace, Vector2};
+    use crate::accuracy::{relative_errors, ErrorStats};
+    use crate::body::Body;
+    use crate::quadtree::{Quadtree, Rectangle};
+    use crate::simulation::pairwise_forces_with;
+    use crate::force_law::ForceLaw;
+    use crate::gravity::PhysicsParams;
+    use crate::vortex::{kelvin_helmholtz, vortex_merger, BiotSavart, VortexSolver};
+
+    fn quadtree() -> Quadtree {
+        Quadtree::new(Rectangle::new(Vector2::new(0.0,0.0),Vector2::new(1.0,1.0)),1)
+    }
+
+    #[test]
+    fn vortex_pair_corotates(){
+        //two vortices of 2 pi a distance 2 apart turn at 1 / 2 radian per unit time
+        let mut bodies: Vec<Body> = vec![Body::with_pos(Vector2::new(1.0, 0.0)).with_circulation(2.0 * PI), Body::with_pos(Vector2::new(-1.0, 0.0)).with_circulation(2.0 * PI)];
+        let mut solver = VortexSolver::new(0.5, 0.0);
+        solver.runner.params.dt = PI / 200.0;
+        let mut qt = quadtree();
+        for _ in 0..200{
+            solver.iterate(&mut qt, &mut bodies);
+        }
+        //a quarter turn counter-clockwise
+        assert!((bodies[0].pos - Vector2::new(0.0, 1.0)).magnitude() < 1e-3, "{:?}", bodies[0].pos);
+        assert!((bodies[1].pos - Vector2::new(0.0, -1.0)).magnitude() < 1e-3, "{:?}", bodies[1].pos);
+        assert!((solver.runner.time - PI).abs() < 1e-12);
+    }
+
+    #[test]
+    fn opposite_pair_translates_with_a_passive_tracer(){
+        //a dipole of +-2 pi a distance 2 apart moves at 1 / 2 along the perpendicular
+        let mut bodies: Vec<Body> = vec![
+            Body::with_pos(Vector2::new(1.0, 0.0)).with_circulation(2.0 * PI),
+            Body::with_pos(Vector2::new(-1.0, 0.0)).with_circulation(-2.0 * PI),
+            Body::tracer(Vector2::new(0.0, 0.0))
+        ];
+        let mut solver = VortexSolver::new(0.5, 0.0);
+        solver.runner.params.dt = 0.01;
+        let mut qt = quadtree();
+        //between the two the flow is twice as fast
+        let mut start: Vec<Body> = bodies.clone();
+        solver.induced_velocities(&mut qt, &mut start);
+        assert!((start[2].velocity - Vector2::new(0.0, -2.0)).magnitude() < 1e-12);
+        for _ in 0..100{
+            solver.iterate(&mut qt, &mut bodies);
+        }
+        assert!((bodies[0].pos - Vector2::new(1.0, -0.5)).magnitude() < 1e-9, "{:?}", bodies[0].pos);
+        assert!((bodies[1].pos - Vector2::new(-1.0, -0.5)).magnitude() < 1e-9, "{:?}", bodies[1].pos);
+        //the tracer runs ahead of the pair without pushing back on it
+        assert!(bodies[2].pos.x.abs() < 1e-12 && bodies[2].pos.y < -1.0, "{:?}", bodies[2].pos);
+    }
+
+    #[test]
+    fn tree_matches_direct_sum(){
+        let mut bodies: Vec<Body> = vortex_merger(Vector2::new(500.0, 500.0), 400.0, 150.0, 300, 1000.0);
+        let law = BiotSavart::new(5.0);
+        let solver = VortexSolver::new(0.5, law.core);
+        let mut naive: Vec<Body> = bodies.clone();
+        pairwise_forces_with(&law, &mut naive, &solver.runner.params);
+        let exact: Vec<Vector2<f64>> = naive.iter().map(|body| body.force).collect();
+        solver.induced_velocities(&mut quadtree(), &mut bodies);
+        let tree: Vec<Vector2<f64>> = bodies.iter().map(|body| body.velocity).collect();
+        let stats = ErrorStats::from_errors(&relative_errors(&tree, &exact));
+        assert!(stats.median < 1e-2, "{:?}", stats);
+        assert!(bodies.iter().all(|body| body.force == Vector2::new(0.0, 0.0)));
+    }
+
+    #[test]
+    fn row_kernel_is_the_sum_over_copies(){
+        let period = 10.0;
+        let (row, single) = (BiotSavart::row(0.0, period), BiotSavart::new(0.0));
+        let params = PhysicsParams::default();
+        for d in [Vector2::new(0.4, -0.2), Vector2::new(4.0, 3.0), Vector2::new(-7.0, 0.5)]{
+            //pair up the copies on either side so the sum converges
+            let summed: Vector2<f64> = single.kernel(d, &params) + (1..20000).map(|n| {
+                let shift = Vector2::new(n as f64 * period, 0.0);
+                single.kernel(d + shift, &params) + single.kernel(d - shift, &params)
+            }).sum::<Vector2<f64>>();
+            let closed = row.kernel(d, &params);
+            //the tail of the sum falls off as 1 / n, about |d| / pi period^2 n
+            assert!((closed - summed).magnitude() < 1e-5, "{:?} {:?}", closed, summed);
+        }
+        assert_eq!(row.kernel(Vector2::new(0.0, 0.0), &params), Vector2::new(0.0, 0.0));
+        assert_eq!(row.kernel(Vector2::new(1.0, 100.0), &params), Vector2::new(-1.0 / 20.0, 0.0));
+    }
+
+    #[test]
+    fn periodic_sheet_is_infinite(){
+        //above a flat sheet of strength γ the flow is -γ / 2, the edges of the domain are not felt
+        let domain = Rectangle::new(Vector2::new(0.0,0.0),Vector2::new(10.0,10.0));
+        let mut sheet = kelvin_helmholtz(&domain, 64, 2.0, 0.0, 1);
+        sheet.push(Body::tracer(Vector2::new(0.3, 6.5)));
+        sheet.push(Body::tracer(Vector2::new(9.9, 3.0)));
+        //a node is seen as a single vortex, which for a piece of a line is good to about a percent
+        for (theta, tolerance) in [(0.0, 1e-9), (0.5, 1e-2)]{
+            let mut bodies = sheet.clone();
+            let solver = VortexSolver::periodic(theta, 0.0, domain);
+            solver.induced_velocities(&mut Quadtree::new(domain,1), &mut bodies);
+            assert!((bodies[64].velocity - Vector2::new(-1.0, 0.0)).magnitude() < tolerance, "{:?}", bodies[64].velocity);
+            assert!((bodies[65].velocity - Vector2::new(1.0, 0.0)).magnitude() < tolerance, "{:?}", bodies[65].velocity);
+            //and the sheet itself does not move
+            assert!(bodies[..64].iter().all(|body| body.velocity.magnitude() < tolerance));
+        }
+    }
+
+    #[test]
+    fn periodic_row_is_open_in_y(){
+        //a vortex near the bottom seen from near the top and from above the domain, with no copies along y
+        let domain = Rectangle::new(Vector2::new(0.0,0.0),Vector2::new(10.0,10.0));
+        let row = BiotSavart::row(0.0, 10.0);
+        let params = PhysicsParams::default();
+        let mut bodies: Vec<Body> = vec![
+            Body::with_pos(Vector2::new(5.0, 0.5)).with_circulation(1.0),
+            Body::tracer(Vector2::new(5.0, 9.5)),
+            Body::tracer(Vector2::new(2.0, 12.0))
+        ];
+        let mut solver = VortexSolver::periodic(0.0, 0.0, domain);
+        solver.induced_velocities(&mut Quadtree::new(domain,1), &mut bodies);
+        for tracer in &bodies[1..]{
+            let expected = row.kernel(tracer.pos - bodies[0].pos, &params);
+            assert!((tracer.velocity - expected).magnitude() < 1e-12, "{:?} {:?}", tracer.velocity, expected);
+        }
+        solver.runner.params.dt = 0.1;
+        solver.update(&mut Quadtree::new(domain,1), &mut bodies);
+        assert!(bodies[2].pos.y > 10.0);
+    }
+
+    #[test]
+    fn scenarios_carry_their_circulation(){
+        let domain = Rectangle::new(Vector2::new(0.0,0.0),Vector2::new(200.0,100.0));
+        let sheet = kelvin_helmholtz(&domain, 100, 3.0, 5.0, 2);
+        assert_eq!(sheet.len(), 100);
+        assert!((sheet.iter().map(|body| body.circulation).sum::<f64>() - 600.0).abs() < 1e-9);
+        assert!(sheet.iter().all(|body| domain.within(body.pos) && (body.pos.y - 50.0).abs() <= 5.0));
+
+        let patches = vortex_merger(Vector2::new(0.0, 0.0), 10.0, 2.0, 50, 4.0);
+        assert_eq!(patches.len(), 100);
+        assert!((patches.iter().map(|body| body.circulation).sum::<f64>() - 8.0).abs() < 1e-12);
+        assert!(patches.iter().all(|body| ((body.pos.x.abs() - 5.0).powi(2) + body.pos.y.powi(2)).sqrt() <= 2.0));
+    }
+
+    #[test]
+    fn merger_keeps_the_centroid(){
+        //the circulation weighted centroid is an invariant of the vortex dynamics
+        let mut bodies: Vec<Body> = vortex_merger(Vector2::new(50.0, 20.0), 10.0, 4.0, 40, 100.0);
+        let centroid = |bodies: &[Body]| bodies.iter().map(|body| body.pos * body.circulation).sum::<Vector2<f64>>() / bodies.iter().map(|body| body.circulation).sum::<f64>();
+        let before = centroid(&bodies);
+        let mut solver = VortexSolver::new(0.5, 0.5);
+        solver.runner.params.dt = 0.05;
+        let mut qt = quadtree();
+        for _ in 0..20{
+            solver.iterate(&mut qt, &mut bodies);
+        }
+        assert!((centroid(&bodies) - before).magnitude() < 1e-2, "{:?} {:?}", centroid(&bodies), before);
+        assert!(bodies.iter().any(|body| (body.pos - before).magnitude() > 1.0));
+    }
+}